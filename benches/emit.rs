@@ -0,0 +1,147 @@
+//! Measures the cost of emitting events through the formatting layer: wall-clock time
+//! and heap allocations per event, counted with a wrapping global allocator.
+//!
+//! Run with `cargo bench --bench emit 2>/dev/null`, with `--features fast-numbers` to
+//! compare the numeric paths. The last benchmarks write their records to the standard
+//! error.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use tracing_json_formatter::{JsonFormattingLayer, JsonStorageLayer, StdioMakeWriter};
+use tracing_subscriber::prelude::*;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const ITERATIONS: usize = 100_000;
+
+fn bench(name: &str, f: impl Fn()) {
+    // Warm up thread-locals and lazily initialized callsites.
+    for _ in 0..1_000 {
+        f();
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let bytes = ALLOCATED_BYTES.load(Ordering::Relaxed) - bytes;
+    println!(
+        "{:<24} {:>8.0} ns/event {:>6.1} allocs/event {:>8.0} bytes/event",
+        name,
+        elapsed.as_nanos() as f64 / ITERATIONS as f64,
+        allocations as f64 / ITERATIONS as f64,
+        bytes as f64 / ITERATIONS as f64,
+    );
+}
+
+fn main() {
+    let subscriber = tracing_subscriber::registry()
+        .with(JsonStorageLayer::new())
+        .with(JsonFormattingLayer::new("bench".into(), std::io::sink));
+
+    tracing::subscriber::with_default(subscriber, || {
+        bench("event", || {
+            tracing::info!(user_id = 42, path = "/index.html", "Request handled");
+        });
+
+        let span = tracing::info_span!("request", method = "GET", status = 200);
+        let _guard = span.enter();
+        bench("event in span", || {
+            tracing::info!(user_id = 42, path = "/index.html", "Request handled");
+        });
+    });
+
+    let subscriber = tracing_subscriber::registry()
+        .with(JsonStorageLayer::new())
+        .with(
+            JsonFormattingLayer::new("bench".into(), std::io::sink)
+                .with_module_path(true)
+                .with_nested_src(true),
+        );
+    tracing::subscriber::with_default(subscriber, || {
+        bench("event with src", || {
+            tracing::info!(user_id = 42, path = "/index.html", "Request handled");
+        });
+    });
+
+    let subscriber = tracing_subscriber::registry()
+        .with(JsonStorageLayer::new())
+        .with(
+            JsonFormattingLayer::new("bench".into(), std::io::sink).with_core_fields(
+                |metadata, core| {
+                    let subsystem = metadata.target().split("::").nth(1).unwrap_or("core");
+                    core.insert("subsystem", subsystem);
+                },
+            ),
+        );
+    tracing::subscriber::with_default(subscriber, || {
+        bench("event with core fields", || {
+            tracing::info!(user_id = 42, path = "/index.html", "Request handled");
+        });
+    });
+
+    let subscriber = tracing_subscriber::registry()
+        .with(JsonStorageLayer::new())
+        .with(JsonFormattingLayer::new("bench".into(), std::io::sink));
+    tracing::subscriber::with_default(subscriber, || {
+        bench("numeric event", || {
+            tracing::info!(
+                status = 200,
+                bytes = 18_234_u64,
+                latency_us = 1_532,
+                retries = 0,
+                offset = -12_i64,
+                shard = 7,
+                "Request handled"
+            );
+        });
+    });
+
+    let subscriber = tracing_subscriber::registry()
+        .with(JsonStorageLayer::new())
+        .with(JsonFormattingLayer::new("bench".into(), std::io::stderr));
+    tracing::subscriber::with_default(subscriber, || {
+        bench("event to io::stderr", || {
+            tracing::info!(user_id = 42, path = "/index.html", "Request handled");
+        });
+    });
+
+    let subscriber = tracing_subscriber::registry()
+        .with(JsonStorageLayer::new())
+        .with(JsonFormattingLayer::new(
+            "bench".into(),
+            StdioMakeWriter::stderr(),
+        ));
+    tracing::subscriber::with_default(subscriber, || {
+        bench("event to StdioMakeWriter", || {
+            tracing::info!(user_id = 42, path = "/index.html", "Request handled");
+        });
+    });
+}
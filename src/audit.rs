@@ -0,0 +1,265 @@
+use std::fmt::{self, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tracing::field::{Field, Visit};
+use tracing::Event;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+/// The `prev_hash` of the first audit record written by the process.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// The dedicated destination of audit records, see
+/// [`JsonFormattingLayer::with_audit_log`](crate::JsonFormattingLayer::with_audit_log).
+pub(crate) struct AuditLog {
+    field: String,
+    pub(crate) make_writer: Arc<BoxMakeWriter>,
+    /// The hash of the last audit record, held while a record is written so that the
+    /// order of the lines follows the chain.
+    chain: Mutex<String>,
+}
+
+impl AuditLog {
+    pub(crate) fn new(field: String, make_writer: BoxMakeWriter) -> Self {
+        Self {
+            field,
+            make_writer: Arc::new(make_writer),
+            chain: Mutex::new(GENESIS.to_owned()),
+        }
+    }
+
+    /// Whether `event` is an audit record: its own sentinel field, not one inherited from
+    /// its spans, is set to `true`.
+    pub(crate) fn is_audit_event(&self, event: &Event<'_>) -> bool {
+        struct Sentinel<'f> {
+            field: &'f str,
+            set: bool,
+        }
+
+        impl Visit for Sentinel<'_> {
+            fn record_bool(&mut self, field: &Field, value: bool) {
+                if field.name() == self.field {
+                    self.set = value;
+                }
+            }
+
+            fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
+        }
+
+        if event.metadata().fields().field(&self.field).is_none() {
+            return false;
+        }
+        let mut sentinel = Sentinel {
+            field: &self.field,
+            set: false,
+        };
+        event.record(&mut sentinel);
+        sentinel.set
+    }
+
+    /// Lock the chain, to append a record to it.
+    pub(crate) fn chain(&self) -> MutexGuard<'_, String> {
+        self.chain.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The SHA-256 digest of `data` (FIPS 180-4), as lowercase hexadecimal.
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    // The message, a 1 bit, zeros up to 8 bytes short of a block boundary, then the bit
+    // length of the message.
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_be_bytes());
+
+    for block in padded.chunks_exact(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (word, add) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+
+    let mut hex = String::with_capacity(64);
+    for word in state {
+        let _ = write!(hex, "{word:08x}");
+    }
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_field, CapturingMakeWriter};
+    use crate::{JsonFormattingLayer, JsonStorageLayer};
+    use serde_json::Value;
+    use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tracing_subscriber::fmt::MakeWriter;
+    use tracing_subscriber::prelude::*;
+
+    fn capture(audit_log: impl for<'a> MakeWriter<'a> + Send + Sync + 'static, f: impl FnOnce()) {
+        let layer = JsonFormattingLayer::new("test".into(), std::io::sink)
+            .with_audit_log("audit", audit_log);
+        let subscriber = tracing_subscriber::registry()
+            .with(JsonStorageLayer::new())
+            .with(layer);
+        tracing::subscriber::with_default(subscriber, f);
+    }
+
+    /// The `hash` of an audit line, and the hash it should have.
+    fn hashes(line: &str) -> (String, String) {
+        let record: Value = serde_json::from_str(line).unwrap();
+        let (hashed, _) = line.rsplit_once(r#","hash":"#).unwrap();
+        (
+            record["hash"].as_str().unwrap().to_owned(),
+            sha256_hex(format!("{hashed}}}").as_bytes()),
+        )
+    }
+
+    #[test]
+    fn sha256_matches_the_published_test_vectors() {
+        // FIPS 180-2, appendix B, and the digest of the empty message.
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            sha256_hex(&[b'a'; 1_000_000]),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    #[test]
+    fn audit_records_form_a_hash_chain() {
+        let audit_log = CapturingMakeWriter::default();
+        capture(audit_log.clone(), || {
+            tracing::info!(audit = true, user_id = 42, "Role granted");
+            tracing::info!(audit = false, "Not audited");
+            tracing::info!(audit = true, user_id = 42, "Role revoked");
+        });
+
+        let contents = audit_log.contents();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let mut prev_hash = GENESIS.to_owned();
+        for line in lines {
+            let record: Value = serde_json::from_str(line).unwrap();
+            assert_field(&record, "prev_hash", prev_hash.as_str());
+            let (hash, expected) = hashes(line);
+            assert_eq!(hash, expected);
+            prev_hash = hash;
+        }
+    }
+
+    #[test]
+    fn span_fields_dont_make_audit_records() {
+        let audit_log = CapturingMakeWriter::default();
+        capture(audit_log.clone(), || {
+            tracing::info_span!("request", audit = true).in_scope(|| {
+                tracing::info!("Routing");
+            });
+        });
+
+        assert_eq!(audit_log.contents(), "");
+    }
+
+    /// Fails its first write.
+    #[derive(Default)]
+    struct FailingOnce(AtomicBool, CapturingMakeWriter);
+
+    impl<'a> MakeWriter<'a> for FailingOnce {
+        type Writer = Box<dyn io::Write + 'a>;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            if self.0.swap(true, Ordering::SeqCst) {
+                Box::new(self.1.make_writer())
+            } else {
+                Box::new(FailingWriter)
+            }
+        }
+    }
+
+    struct FailingWriter;
+
+    impl io::Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_writes_dont_advance_the_chain() {
+        let audit_log = FailingOnce::default();
+        let contents = audit_log.1.clone();
+        capture(audit_log, || {
+            tracing::info!(audit = true, "Lost");
+            tracing::info!(audit = true, "Written");
+        });
+
+        let records = contents.records();
+        assert_eq!(records.len(), 1);
+        assert_field(&records[0], "message", "Written");
+        assert_field(&records[0], "prev_hash", GENESIS);
+    }
+}
@@ -0,0 +1,300 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::Metrics;
+
+struct Batch {
+    writer: Box<dyn Write + Send>,
+    buffer: Vec<u8>,
+    capacity: usize,
+    flush_interval: Duration,
+    last_flush: Instant,
+    sync_level: Option<Level>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Batch {
+    /// Write the buffered records, and flush the writer. On failure, the records that
+    /// weren't written stay in the buffer for the next flush.
+    fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        let mut written = 0;
+        let result = loop {
+            if written == self.buffer.len() {
+                break Ok(());
+            }
+            match self.writer.write(&self.buffer[written..]) {
+                Ok(0) => break Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        self.buffer.drain(..written);
+        result?;
+        self.writer.flush()
+    }
+
+    /// Discard the buffered records, counting them as dropped.
+    fn discard(&mut self) {
+        if let Some(metrics) = &self.metrics {
+            let records = self.buffer.iter().filter(|&&b| b == b'\n').count();
+            Metrics::add(&metrics.backpressure_dropped, records);
+        }
+        self.buffer.clear();
+    }
+}
+
+fn lock(batch: &Mutex<Batch>) -> MutexGuard<'_, Batch> {
+    batch.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A [`MakeWriter`] accumulating records in memory and writing them in batches, saving a
+/// syscall per record when logging to a file or a pipe.
+///
+/// The buffer is written once it would exceed its capacity (64 KiB by default), and at
+/// least every flush interval (one second by default) by a background thread, so that
+/// records don't linger while the application is idle. The buffer is also written when
+/// the writer is dropped, and by the layer's [`FlushGuard`](crate::FlushGuard).
+///
+/// When the underlying writer fails, the records it didn't take stay in the buffer and
+/// are written with the next batch. New records are refused with the error while the
+/// buffer is full, and the records still buffered when the writer is dropped are
+/// counted in [`Metrics::backpressure_dropped`].
+///
+/// ```rust
+/// use std::time::Duration;
+/// use tracing_json_formatter::{BatchedMakeWriter, JsonFormattingLayer};
+///
+/// let make_writer = BatchedMakeWriter::new(std::io::stdout())
+///     .with_capacity(256 * 1024)
+///     .with_flush_interval(Duration::from_millis(200));
+/// let layer = JsonFormattingLayer::new("my-app".into(), make_writer);
+/// ```
+pub struct BatchedMakeWriter {
+    batch: Arc<Mutex<Batch>>,
+}
+
+impl BatchedMakeWriter {
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        let capacity = 64 * 1024;
+        let batch = Arc::new(Mutex::new(Batch {
+            writer: Box::new(writer),
+            buffer: Vec::with_capacity(capacity),
+            capacity,
+            flush_interval: Duration::from_secs(1),
+            last_flush: Instant::now(),
+            sync_level: None,
+            metrics: None,
+        }));
+        let ticker = Arc::downgrade(&batch);
+        // Without the thread, records are still written once the buffer fills up.
+        let _ = thread::Builder::new()
+            .name("batched-writer".to_owned())
+            .spawn(move || tick(&ticker));
+        Self { batch }
+    }
+
+    /// Write the buffer once it would exceed `capacity` bytes; records larger than that
+    /// are written on their own.
+    pub fn with_capacity(self, capacity: usize) -> Self {
+        {
+            let mut batch = lock(&self.batch);
+            batch.capacity = capacity;
+            let additional = capacity.saturating_sub(batch.buffer.len());
+            batch.buffer.reserve(additional);
+        }
+        self
+    }
+
+    /// Write the buffer at least every `flush_interval`, `Duration::ZERO` writing every
+    /// record immediately.
+    pub fn with_flush_interval(self, flush_interval: Duration) -> Self {
+        lock(&self.batch).flush_interval = flush_interval;
+        self
+    }
+
+    /// Write and flush records at `level` or more severe right away, with the records
+    /// buffered before them, so that they survive an imminent crash: with
+    /// `Level::ERROR`, errors are never left in the buffer.
+    ///
+    /// Records are only known by their level when the layer asks for a writer with
+    /// `MakeWriter::make_writer_for`, as [`JsonFormattingLayer`](crate::JsonFormattingLayer)
+    /// does.
+    pub fn with_sync_level(self, level: Level) -> Self {
+        lock(&self.batch).sync_level = Some(level);
+        self
+    }
+
+    /// Count the records that couldn't be written by the time the writer was dropped in
+    /// `metrics`, typically the counters also given to
+    /// [`JsonFormattingLayer::with_metrics`](crate::JsonFormattingLayer::with_metrics).
+    pub fn with_metrics(self, metrics: Arc<Metrics>) -> Self {
+        lock(&self.batch).metrics = Some(metrics);
+        self
+    }
+}
+
+/// Write the buffer of the batch every flush interval, until the writer is dropped.
+fn tick(batch: &Weak<Mutex<Batch>>) {
+    let mut interval = Duration::from_secs(1);
+    loop {
+        // A zero interval writes from the logging thread, just watch for the drop then.
+        thread::sleep(if interval.is_zero() {
+            Duration::from_millis(100)
+        } else {
+            interval
+        });
+        let Some(batch) = batch.upgrade() else {
+            return;
+        };
+        let mut batch = lock(&batch);
+        interval = batch.flush_interval;
+        if !batch.buffer.is_empty() && batch.last_flush.elapsed() >= interval {
+            let _ = batch.flush();
+        }
+    }
+}
+
+impl Drop for BatchedMakeWriter {
+    fn drop(&mut self) {
+        let mut batch = lock(&self.batch);
+        if batch.flush().is_err() {
+            batch.discard();
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for BatchedMakeWriter {
+    type Writer = BatchedWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        BatchedWriter {
+            batch: lock(&self.batch),
+            sync: false,
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let batch = lock(&self.batch);
+        let sync = batch.sync_level.is_some_and(|level| *meta.level() <= level);
+        BatchedWriter { batch, sync }
+    }
+}
+
+/// The writer returned by [`BatchedMakeWriter`], holding the buffer lock.
+pub struct BatchedWriter<'a> {
+    batch: MutexGuard<'a, Batch>,
+    /// Whether the record is written right away, see [`BatchedMakeWriter::with_sync_level`].
+    sync: bool,
+}
+
+impl Write for BatchedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let batch = &mut *self.batch;
+        if batch.buffer.len() + buf.len() > batch.capacity {
+            if let Err(e) = batch.flush() {
+                // Keep what's already buffered rather than the record.
+                if !batch.buffer.is_empty() && batch.buffer.len() + buf.len() > batch.capacity {
+                    return Err(e);
+                }
+            }
+        }
+        if buf.len() > batch.capacity {
+            batch.writer.write_all(buf)?;
+        } else {
+            batch.buffer.extend_from_slice(buf);
+        }
+        if self.sync || batch.last_flush.elapsed() >= batch.flush_interval {
+            // The record is buffered either way, written with the next batch on failure.
+            let _ = batch.flush();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.batch.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A writer failing while `failing` is set, taking everything otherwise.
+    #[derive(Clone, Default)]
+    struct Output {
+        failing: Arc<Mutex<bool>>,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if *self.failing.lock().unwrap() {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn batched(output: &Output) -> BatchedMakeWriter {
+        BatchedMakeWriter::new(output.clone())
+            .with_capacity(16)
+            .with_flush_interval(Duration::from_secs(3600))
+    }
+
+    #[test]
+    fn records_are_kept_when_the_write_fails() {
+        let output = Output::default();
+        let make_writer = batched(&output);
+        make_writer.make_writer().write_all(b"{\"a\":1}\n").unwrap();
+        *output.failing.lock().unwrap() = true;
+        assert!(make_writer.make_writer().flush().is_err());
+
+        *output.failing.lock().unwrap() = false;
+        make_writer.make_writer().write_all(b"{\"b\":2}\n").unwrap();
+        make_writer.make_writer().flush().unwrap();
+        assert_eq!(&*output.written.lock().unwrap(), b"{\"a\":1}\n{\"b\":2}\n");
+    }
+
+    #[test]
+    fn records_are_refused_while_the_buffer_is_stuck() {
+        let output = Output::default();
+        let make_writer = batched(&output);
+        *output.failing.lock().unwrap() = true;
+        make_writer.make_writer().write_all(b"{\"a\":1}\n").unwrap();
+        make_writer.make_writer().write_all(b"{\"b\":2}\n").unwrap();
+        assert!(make_writer.make_writer().write_all(b"{\"c\":3}\n").is_err());
+
+        *output.failing.lock().unwrap() = false;
+        make_writer.make_writer().write_all(b"{\"c\":3}\n").unwrap();
+        make_writer.make_writer().flush().unwrap();
+        assert_eq!(
+            &*output.written.lock().unwrap(),
+            b"{\"a\":1}\n{\"b\":2}\n{\"c\":3}\n"
+        );
+    }
+
+    #[test]
+    fn records_left_on_drop_are_counted_as_dropped() {
+        let output = Output::default();
+        let metrics = Arc::new(Metrics::default());
+        let make_writer = batched(&output).with_metrics(metrics.clone());
+        *output.failing.lock().unwrap() = true;
+        make_writer.make_writer().write_all(b"{\"a\":1}\n").unwrap();
+        make_writer.make_writer().write_all(b"{\"b\":2}\n").unwrap();
+        drop(make_writer);
+
+        assert_eq!(metrics.backpressure_dropped(), 2);
+        assert!(output.written.lock().unwrap().is_empty());
+    }
+}
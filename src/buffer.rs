@@ -0,0 +1,31 @@
+use std::cell::RefCell;
+
+/// Buffers growing past this capacity are not kept around, so that a single huge record
+/// doesn't pin its memory for the lifetime of the thread.
+const MAX_RETAINED_CAPACITY: usize = 64 * 1024;
+
+thread_local! {
+    static BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Run `f` with an empty buffer, reusing a per-thread allocation across records.
+///
+/// Falls back to a fresh buffer when the thread-local one is already in use, e.g. when
+/// a writer emits records of its own while a record is being written.
+pub(crate) fn with_buffer<R>(f: impl FnOnce(&mut Vec<u8>) -> R) -> R {
+    let mut f = Some(f);
+    let pooled = BUFFER.try_with(|cell| {
+        let mut buffer = cell.try_borrow_mut().ok()?;
+        buffer.clear();
+        let result = f.take().map(|f| f(&mut buffer));
+        if buffer.capacity() > MAX_RETAINED_CAPACITY {
+            *buffer = Vec::new();
+        }
+        result
+    });
+    match (pooled, f) {
+        (Ok(Some(result)), _) => result,
+        (_, Some(f)) => f(&mut Vec::new()),
+        (_, None) => unreachable!("the closure is only consumed along with the pooled buffer"),
+    }
+}
@@ -0,0 +1,102 @@
+use crate::encoder::Encoder;
+use crate::record::{FieldValue, Record};
+use serde_json::Value;
+use std::io;
+
+/// An encoder writing each record as a CBOR map (RFC 8949), with the same keys and values
+/// as [`JsonEncoder`](crate::JsonEncoder), for links where the overhead of JSON matters.
+///
+/// CBOR items delimit themselves: with [`Framing::None`](crate::Framing::None), the output
+/// is a CBOR sequence (RFC 8742). Integers are written in their shortest form, floats as
+/// 64-bit floats.
+///
+/// ```rust
+/// use tracing_json_formatter::{CborEncoder, Framing, JsonFormattingLayer};
+///
+/// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+///     .with_encoder(CborEncoder)
+///     .with_framing(Framing::None);
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CborEncoder;
+
+const UNSIGNED: u8 = 0x00;
+const NEGATIVE: u8 = 0x20;
+const TEXT: u8 = 0x60;
+const ARRAY: u8 = 0x80;
+const MAP: u8 = 0xa0;
+
+impl Encoder for CborEncoder {
+    fn encode(&self, record: &Record<'_>, buffer: &mut Vec<u8>) -> io::Result<()> {
+        let entries = record.entries();
+        encode_head(buffer, MAP, entries.len() as u64);
+        for (key, value) in entries {
+            encode_str(buffer, key);
+            match value {
+                FieldValue::Str(s) => encode_str(buffer, s),
+                FieldValue::Json(value) => encode_value(buffer, value),
+                FieldValue::Raw(_) => encode_value(buffer, &value.to_value()),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn encode_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0xf6),
+        Value::Bool(false) => out.push(0xf4),
+        Value::Bool(true) => out.push(0xf5),
+        Value::Number(number) => {
+            if let Some(n) = number.as_u64() {
+                encode_head(out, UNSIGNED, n);
+            } else if let Some(n) = number.as_i64() {
+                // -1 - n, i.e. the bitwise negation, for negative integers.
+                encode_head(out, NEGATIVE, !n as u64);
+            } else if let Some(n) = number.as_f64() {
+                out.push(0xfb);
+                out.extend_from_slice(&n.to_be_bytes());
+            } else {
+                encode_str(out, &number.to_string());
+            }
+        }
+        Value::String(s) => encode_str(out, s),
+        Value::Array(values) => {
+            encode_head(out, ARRAY, values.len() as u64);
+            for value in values {
+                encode_value(out, value);
+            }
+        }
+        Value::Object(fields) => {
+            encode_head(out, MAP, fields.len() as u64);
+            for (key, value) in fields {
+                encode_str(out, key);
+                encode_value(out, value);
+            }
+        }
+    }
+}
+
+fn encode_str(out: &mut Vec<u8>, s: &str) {
+    encode_head(out, TEXT, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// The initial byte of an item of the given major type, followed by its argument (a value,
+/// a length or a number of elements) in the shortest form.
+fn encode_head(out: &mut Vec<u8>, major: u8, n: u64) {
+    if n < 24 {
+        out.push(major | n as u8);
+    } else if n <= u64::from(u8::MAX) {
+        out.extend_from_slice(&[major | 24, n as u8]);
+    } else if n <= u64::from(u16::MAX) {
+        out.push(major | 25);
+        out.extend_from_slice(&(n as u16).to_be_bytes());
+    } else if n <= u64::from(u32::MAX) {
+        out.push(major | 26);
+        out.extend_from_slice(&(n as u32).to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+}
@@ -0,0 +1,56 @@
+use crate::storage_layer::{with_span_extensions, ChildSpan, JsonStorage, SerializedSpanFields};
+use serde_json::Value;
+use tracing::{Metadata, Span};
+
+/// The target of the spans binding fields, [`child_span`] and
+/// [`LogContext::span`](crate::LogContext::span).
+pub(crate) const BINDING_SPAN_TARGET: &str = module_path!();
+
+/// A span binding `fields` to everything recorded inside it, like Bunyan's
+/// `log.child({...})`: events and spans created under it carry the fields, next to those
+/// of the current span, which becomes its parent.
+///
+/// The span has `log_span = false`, so it never shows up as START and END records of its
+/// own, and the `ERROR` level, so that level filters don't disable it. Its name is left out
+/// of messages and span breadcrumbs, and it's never the root span of a transaction or of
+/// tail sampling: that's the first span under it. Fields are only bound when the span is recorded by a
+/// `Registry` with a [`JsonStorageLayer`](crate::JsonStorageLayer).
+///
+/// ```rust
+/// use tracing::Instrument;
+/// use tracing_json_formatter::child_span;
+///
+/// let tenant = child_span([("tenant_id", "acme".into()), ("plan", "pro".into())]);
+/// tenant.in_scope(|| {
+///     // {..., "tenant_id": "acme", "plan": "pro"}
+///     tracing::info!("Invoice sent");
+/// });
+///
+/// # async fn sync_invoices() {}
+/// let task = sync_invoices().instrument(tenant.clone());
+/// ```
+pub fn child_span(fields: impl IntoIterator<Item = (&'static str, Value)>) -> Span {
+    let span = tracing::error_span!(target: BINDING_SPAN_TARGET, "child", log_span = false);
+    bind_fields(&span, fields);
+    span
+}
+
+/// Store `fields` on `span`, marking it as a [`ChildSpan`].
+pub(crate) fn bind_fields(span: &Span, fields: impl IntoIterator<Item = (&'static str, Value)>) {
+    with_span_extensions(span, |extensions| {
+        let Some(storage) = extensions.get_mut::<JsonStorage>() else {
+            return false;
+        };
+        for (key, value) in fields {
+            storage.set(key, value);
+        }
+        extensions.remove::<SerializedSpanFields>();
+        extensions.insert(ChildSpan);
+        true
+    });
+}
+
+/// Whether `metadata` is that of a span binding fields, known before they're bound.
+pub(crate) fn is_binding_span(metadata: &Metadata<'_>) -> bool {
+    metadata.target() == BINDING_SPAN_TARGET && metadata.fields().field("log_span").is_some()
+}
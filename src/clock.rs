@@ -0,0 +1,49 @@
+use crate::timestamp::{self, Timestamp};
+
+/// The source of the `time` of records.
+///
+/// The layer reads the system time by default, replace it with
+/// [`JsonFormattingLayer::with_clock`](crate::JsonFormattingLayer::with_clock) to get
+/// deterministic output in tests.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Timestamp;
+}
+
+/// The system time, in the local time zone.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        timestamp::now()
+    }
+}
+
+/// A clock that always returns the same time.
+///
+/// ```rust
+/// use std::time::{Duration, SystemTime};
+/// use tracing_json_formatter::{FixedClock, JsonFormattingLayer, Timestamp};
+///
+/// // 2023-04-12T15:30:12Z, or a `chrono::DateTime` with `.into()`.
+/// let time = Timestamp::from(SystemTime::UNIX_EPOCH + Duration::from_secs(1_681_313_412));
+/// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+///     .with_clock(FixedClock(time));
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct FixedClock(pub Timestamp);
+
+impl Clock for FixedClock {
+    fn now(&self) -> Timestamp {
+        self.0
+    }
+}
+
+impl<F> Clock for F
+where
+    F: Fn() -> Timestamp + Send + Sync,
+{
+    fn now(&self) -> Timestamp {
+        self()
+    }
+}
@@ -0,0 +1,103 @@
+use crate::child::{bind_fields, BINDING_SPAN_TARGET};
+use crate::log_context::{self, Fields, Scoped};
+use crate::storage_layer::read_span_storage;
+use serde_json::Value;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tracing::Span;
+
+/// A snapshot of the fields of the current span and of the
+/// [`log_context`](crate::log_context), to carry them over to work spawned onto another task
+/// or thread, e.g. a `request_id`.
+///
+/// Unlike instrumenting the spawned future with `Span::current()`, the snapshot doesn't
+/// keep the span open: its END record isn't delayed until the spawned work completes.
+///
+/// ```rust
+/// use tracing_json_formatter::LogContext;
+///
+/// # fn spawn(future: impl std::future::Future<Output = ()> + Send + 'static) {}
+/// let context = LogContext::current();
+/// spawn(context.attach(async {
+///     // {..., "request_id": "..."}
+///     tracing::info!("Sending the confirmation email");
+/// }));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct LogContext {
+    fields: Vec<(&'static str, Value)>,
+    /// The fields of the `log_context`, which stay local to the work they're attached to.
+    local: Fields,
+}
+
+impl LogContext {
+    /// The fields of the current span, its inherited fields included, and those of the
+    /// current `log_context`.
+    ///
+    /// The snapshot is empty outside of spans, or when they aren't recorded by a `Registry`
+    /// with a [`JsonStorageLayer`](crate::JsonStorageLayer).
+    pub fn current() -> Self {
+        let mut fields = Vec::new();
+        read_span_storage(&Span::current(), |storage| {
+            fields.extend(storage.iter().map(|(key, value)| (key, value.clone())));
+        });
+        Self {
+            fields,
+            local: log_context::fields(),
+        }
+    }
+
+    /// The value of the field `key`, if it was captured.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.local
+            .iter()
+            .chain(&self.fields)
+            .find_map(|(k, value)| (*k == key).then_some(value))
+    }
+
+    /// A root span carrying the captured fields, left out of records like a
+    /// [`child_span`](crate::child_span).
+    pub fn span(&self) -> Span {
+        let span = tracing::error_span!(
+            target: BINDING_SPAN_TARGET,
+            parent: None,
+            "log_context",
+            log_span = false
+        );
+        bind_fields(&span, self.fields.iter().cloned());
+        span
+    }
+
+    /// Run `f` with the captured fields, e.g. on another thread.
+    pub fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.span()
+            .in_scope(|| log_context::sync_scope_with(self.local.clone(), f))
+    }
+
+    /// Wrap `future` so that the records emitted while it's polled carry the captured
+    /// fields.
+    pub fn attach<F: Future>(&self, future: F) -> WithLogContext<F> {
+        WithLogContext {
+            future: log_context::scope_with(self.local.clone(), future),
+            span: self.span(),
+        }
+    }
+}
+
+/// A future carrying the fields of a [`LogContext`], see [`LogContext::attach`].
+#[derive(Debug)]
+pub struct WithLogContext<F> {
+    future: Scoped<F>,
+    span: Span,
+}
+
+impl<F: Future> Future for WithLogContext<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = &mut *self;
+        let _guard = this.span.enter();
+        Pin::new(&mut this.future).poll(cx)
+    }
+}
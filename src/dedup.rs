@@ -0,0 +1,146 @@
+use crate::formatting_layer::{Transaction, Type};
+use crate::record::{Entry, Record};
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::ops::Range;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::Metadata;
+
+/// Collapses identical events fired in rapid succession, see
+/// [`JsonFormattingLayer::with_deduplication`](crate::JsonFormattingLayer::with_deduplication).
+#[derive(Debug)]
+pub(crate) struct Deduplicator {
+    window: Duration,
+    state: Mutex<Option<Run>>,
+}
+
+/// A run of identical events: the first one is emitted, the following ones are counted.
+#[derive(Debug)]
+struct Run {
+    key: u64,
+    /// The transaction of the events, runs don't span transactions.
+    transaction: Option<Transaction>,
+    start: Instant,
+    repeated: Option<Repeated>,
+}
+
+/// What to do with a record, see [`Deduplicator::check`].
+pub(crate) enum Verdict {
+    Suppress,
+    /// Emit the record, after the events suppressed since the previous one, if any.
+    Emit(Option<Repeated>),
+}
+
+/// The first of the suppressed events of a run, to be emitted with their count.
+#[derive(Debug)]
+pub(crate) struct Repeated {
+    pub(crate) metadata: &'static Metadata<'static>,
+    pub(crate) message: String,
+    pub(crate) fields: Vec<(String, Value)>,
+    pub(crate) transaction: Option<Transaction>,
+    pub(crate) count: u64,
+}
+
+impl Deduplicator {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            state: Mutex::new(None),
+        }
+    }
+
+    /// Whether `record` repeats the previous event within the window, in which case it's
+    /// counted instead of emitted.
+    ///
+    /// Events are compared on their callsite, level, message and the entries of `fields`,
+    /// the fields of the event: those added by the layer, such as `uptime_ms`, and the span
+    /// fields are left out. Events of different transactions are never repeats.
+    pub(crate) fn check(
+        &self,
+        record: &Record<'_>,
+        fields: Range<usize>,
+        transaction: Option<&Transaction>,
+    ) -> Verdict {
+        if !matches!(record.ty(), Type::Event) {
+            return Verdict::Emit(None);
+        }
+        let fields = &record.entries()[fields];
+        let key = key(record, fields);
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(run) = state.as_mut() {
+            let same_transaction = match (&run.transaction, transaction) {
+                (Some(run), Some(transaction)) => run.ptr_eq(transaction),
+                (run, transaction) => run.is_none() && transaction.is_none(),
+            };
+            if run.key == key && same_transaction && run.start.elapsed() < self.window {
+                let repeated = run.repeated.get_or_insert_with(|| Repeated {
+                    metadata: record.metadata(),
+                    message: record.message().to_owned(),
+                    fields: fields
+                        .iter()
+                        .map(|(key, value)| (key.to_string(), value.to_value()))
+                        .collect(),
+                    transaction: transaction.cloned(),
+                    count: 0,
+                });
+                repeated.count += 1;
+                return Verdict::Suppress;
+            }
+        }
+        let previous = state.replace(Run {
+            key,
+            transaction: transaction.cloned(),
+            start: Instant::now(),
+            repeated: None,
+        });
+        Verdict::Emit(previous.and_then(|run| run.repeated))
+    }
+
+    /// The repeats of the last event, which would otherwise wait for a different event.
+    pub(crate) fn take_pending(&self) -> Option<Repeated> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.as_mut().and_then(|run| run.repeated.take())
+    }
+
+    /// The repeats of the last event if it belongs to `transaction`, which is closing.
+    pub(crate) fn take_pending_in(&self, transaction: &Transaction) -> Option<Repeated> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state
+            .as_mut()
+            .filter(|run| {
+                run.transaction
+                    .as_ref()
+                    .is_some_and(|t| t.ptr_eq(transaction))
+            })
+            .and_then(|run| run.repeated.take())
+    }
+}
+
+/// A hash of the callsite, level, message and event fields of a record.
+fn key(record: &Record<'_>, fields: &[Entry<'_>]) -> u64 {
+    struct HashWriter<'h>(&'h mut DefaultHasher);
+
+    impl Write for HashWriter<'_> {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.write(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut hasher = DefaultHasher::new();
+    record.metadata().callsite().hash(&mut hasher);
+    record.level().hash(&mut hasher);
+    record.message().hash(&mut hasher);
+    for (key, value) in fields {
+        key.hash(&mut hasher);
+        let _ = serde_json::to_writer(HashWriter(&mut hasher), value);
+    }
+    hasher.finish()
+}
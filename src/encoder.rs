@@ -0,0 +1,435 @@
+use crate::formatting_layer::Type;
+use crate::record::{Entry, FieldValue, Record};
+use crate::schema::{self, FieldKind, Schema, SchemaField};
+use crate::timestamp;
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use tracing::Level;
+
+/// Turn a [`Record`] into bytes.
+///
+/// Encoders write a single record without any trailing delimiter, the layer takes care
+/// of separating records from one another.
+pub trait Encoder: Send + Sync {
+    fn encode(&self, record: &Record<'_>, buffer: &mut Vec<u8>) -> io::Result<()>;
+
+    /// The core fields of the records written for records whose core fields are `core`,
+    /// see [`JsonFormattingLayer::schema`](crate::JsonFormattingLayer::schema).
+    ///
+    /// Core fields are written unchanged by default.
+    fn schema(&self, core: Schema) -> Schema {
+        core
+    }
+}
+
+/// The default encoder: one JSON object per record.
+///
+/// Span fields are spliced in from their cached serialized form when available, instead
+/// of being serialized again for every record of the span.
+#[derive(Clone, Debug, Default)]
+pub struct JsonEncoder {
+    nested_keys: bool,
+}
+
+impl JsonEncoder {
+    /// The JSON encoder with its default configuration.
+    pub const fn new() -> Self {
+        Self { nested_keys: false }
+    }
+
+    /// Expand dotted keys into nested objects: `http.method` and `http.status_code` become
+    /// `{"http": {"method": ..., "status_code": ...}}`.
+    ///
+    /// A dotted key is kept as-is when its prefix is already used by a non-object value.
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::{JsonEncoder, JsonFormattingLayer};
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_encoder(JsonEncoder::new().with_nested_keys(true));
+    /// ```
+    pub fn with_nested_keys(mut self, nested_keys: bool) -> Self {
+        self.nested_keys = nested_keys;
+        self
+    }
+
+    fn encode_nested(&self, record: &Record<'_>, buffer: &mut Vec<u8>) -> io::Result<()> {
+        let mut nodes = Vec::new();
+        for (key, value) in record.entries() {
+            Node::insert(&mut nodes, key, value);
+        }
+        let mut errors = Vec::new();
+        Node::write_object(&nodes, buffer, &mut errors)?;
+        if !errors.is_empty() {
+            buffer.pop();
+            write_serialization_errors(&errors, buffer)?;
+            buffer.push(b'}');
+        }
+        Ok(())
+    }
+}
+
+/// A value of a record with nested keys, see [`JsonEncoder::with_nested_keys`].
+enum Node<'r, 'a> {
+    Leaf(&'r FieldValue<'a>),
+    Object(Vec<(&'r str, Node<'r, 'a>)>),
+}
+
+impl<'r, 'a> Node<'r, 'a> {
+    fn insert(nodes: &mut Vec<(&'r str, Node<'r, 'a>)>, key: &'r str, value: &'r FieldValue<'a>) {
+        let Some((head, rest)) = key
+            .split_once('.')
+            .filter(|(h, r)| !h.is_empty() && !r.is_empty())
+        else {
+            nodes.push((key, Node::Leaf(value)));
+            return;
+        };
+        match nodes.iter_mut().find(|(k, _)| *k == head) {
+            Some((_, Node::Object(children))) => Node::insert(children, rest, value),
+            Some(_) => nodes.push((key, Node::Leaf(value))),
+            None => {
+                let mut children = Vec::new();
+                Node::insert(&mut children, rest, value);
+                nodes.push((head, Node::Object(children)));
+            }
+        }
+    }
+
+    fn write_object(
+        nodes: &[(&'r str, Node<'r, 'a>)],
+        buffer: &mut Vec<u8>,
+        errors: &mut Vec<&'r str>,
+    ) -> io::Result<()> {
+        let mut separator: &[u8] = b"";
+        buffer.push(b'{');
+        for (key, node) in nodes {
+            match node {
+                Node::Leaf(value) => {
+                    if write_entry(buffer, separator, key, value, errors) {
+                        separator = b",";
+                    }
+                }
+                Node::Object(children) => {
+                    buffer.extend_from_slice(separator);
+                    serde_json::to_writer(&mut *buffer, key)?;
+                    buffer.push(b':');
+                    Node::write_object(children, buffer, errors)?;
+                    separator = b",";
+                }
+            }
+        }
+        buffer.push(b'}');
+        Ok(())
+    }
+}
+
+impl Encoder for JsonEncoder {
+    fn encode(&self, record: &Record<'_>, buffer: &mut Vec<u8>) -> io::Result<()> {
+        if self.nested_keys {
+            return self.encode_nested(record, buffer);
+        }
+        let splices = [
+            record
+                .serialized_callsite_fields()
+                .map(|serialized| (&serialized.range, &*serialized.bytes)),
+            record
+                .serialized_span_fields()
+                .map(|serialized| (&serialized.range, serialized.bytes)),
+        ];
+        let mut errors = Vec::new();
+        let mut separator: &[u8] = b"";
+        buffer.push(b'{');
+        for (index, (key, value)) in record.entries().iter().enumerate() {
+            if let Some((range, bytes)) = splices
+                .iter()
+                .flatten()
+                .find(|(range, _)| range.contains(&index))
+            {
+                if index == range.start && !bytes.is_empty() {
+                    buffer.extend_from_slice(separator);
+                    buffer.extend_from_slice(bytes);
+                    separator = b",";
+                }
+                continue;
+            }
+            if write_entry(buffer, separator, key, value, &mut errors) {
+                separator = b",";
+            }
+        }
+        if !errors.is_empty() {
+            write_serialization_errors(&errors, buffer)?;
+        }
+        buffer.push(b'}');
+        Ok(())
+    }
+}
+
+/// Write a `"key":value` pair preceded by `separator`.
+///
+/// A value that fails to serialize is left out rather than failing the whole record, its
+/// key is added to `errors` instead.
+fn write_entry<'k>(
+    buffer: &mut Vec<u8>,
+    separator: &[u8],
+    key: &'k str,
+    value: &FieldValue<'_>,
+    errors: &mut Vec<&'k str>,
+) -> bool {
+    let start = buffer.len();
+    buffer.extend_from_slice(separator);
+    let written = serde_json::to_writer(&mut *buffer, key).and_then(|()| {
+        buffer.push(b':');
+        write_value(buffer, value)
+    });
+    if written.is_err() {
+        buffer.truncate(start);
+        errors.push(key);
+    }
+    written.is_ok()
+}
+
+/// Serialize a value, formatting integers straight into the buffer with the
+/// `fast-numbers` feature instead of going through the generic serializer.
+fn write_value(buffer: &mut Vec<u8>, value: &FieldValue<'_>) -> serde_json::Result<()> {
+    #[cfg(feature = "fast-numbers")]
+    if let FieldValue::Json(value) = value {
+        if let serde_json::Value::Number(number) = value.as_ref() {
+            let mut digits = itoa::Buffer::new();
+            if let Some(n) = number.as_u64() {
+                buffer.extend_from_slice(digits.format(n).as_bytes());
+                return Ok(());
+            }
+            if let Some(n) = number.as_i64() {
+                buffer.extend_from_slice(digits.format(n).as_bytes());
+                return Ok(());
+            }
+        }
+    }
+    serde_json::to_writer(buffer, value)
+}
+
+/// Serialize `entries` as the comma-separated `"key":value` pairs of an object, `None` if
+/// a value fails to serialize.
+pub(crate) fn serialize_entries(entries: &[Entry<'_>]) -> Option<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let mut errors = Vec::new();
+    let mut separator: &[u8] = b"";
+    for (key, value) in entries {
+        write_entry(&mut buffer, separator, key, value, &mut errors);
+        separator = b",";
+    }
+    errors.is_empty().then_some(buffer)
+}
+
+/// Write the `_serialization_errors` field listing the keys of the values left out, just
+/// before the closing brace of an object.
+fn write_serialization_errors(errors: &[&str], buffer: &mut Vec<u8>) -> io::Result<()> {
+    if buffer.last() != Some(&b'{') {
+        buffer.push(b',');
+    }
+    buffer.extend_from_slice(b"\"_serialization_errors\":");
+    serde_json::to_writer(&mut *buffer, errors)?;
+    Ok(())
+}
+
+/// An encoder following the Elastic Common Schema, for ingestion into Elasticsearch.
+///
+/// Core fields are renamed to their ECS equivalent (`@timestamp`, `log.level`,
+/// `service.name`, ...), the fields of the event and its spans are kept as-is.
+#[derive(Clone, Debug, Default)]
+pub struct EcsEncoder;
+
+impl EcsEncoder {
+    fn key(core_key: &str) -> Option<&'static str> {
+        Some(match core_key {
+            "name" => "service.name",
+            "host" => "host.hostname",
+            "message" => "message",
+            "level" => "log.level",
+            "pid" => "process.pid",
+            "target" => "log.logger",
+            "line" => "log.origin.file.line",
+            "file" => "log.origin.file.name",
+            _ => return None,
+        })
+    }
+}
+
+impl Encoder for EcsEncoder {
+    fn schema(&self, core: Schema) -> Schema {
+        let mut fields = vec![
+            SchemaField::new("@timestamp", FieldKind::String),
+            SchemaField::new("ecs.version", FieldKind::String),
+        ];
+        fields.extend(
+            core.fields()
+                .iter()
+                .filter(|field| field.name != schema::fields::TIME)
+                .map(|field| SchemaField {
+                    name: Self::key(field.name).unwrap_or(field.name),
+                    ..*field
+                }),
+        );
+        Schema::new(fields)
+    }
+
+    fn encode(&self, record: &Record<'_>, buffer: &mut Vec<u8>) -> io::Result<()> {
+        let timestamp = timestamp::utc(&record.time()).rfc3339(3);
+        buffer.extend_from_slice(b"{\"@timestamp\":");
+        serde_json::to_writer(&mut *buffer, &timestamp)?;
+        buffer.extend_from_slice(b",\"ecs.version\":\"8.11\"");
+        let core = record.core_fields().iter().filter_map(|(key, value)| {
+            let key = Self::key(key).map_or(key.as_ref(), |key| key);
+            (key != "time").then_some((key, value))
+        });
+        let fields = record
+            .fields()
+            .iter()
+            .map(|(key, value)| (key.as_ref(), value));
+        let mut errors = Vec::new();
+        for (key, value) in core.chain(fields) {
+            write_entry(buffer, b",", key, value, &mut errors);
+        }
+        if !errors.is_empty() {
+            write_serialization_errors(&errors, buffer)?;
+        }
+        buffer.push(b'}');
+        Ok(())
+    }
+}
+
+/// A human-readable encoder, for local development.
+///
+/// Records look like `2023-04-12 15:30:12  INFO [HANDLE - EVENT] Done status=200`.
+#[derive(Clone, Debug, Default)]
+pub struct PrettyEncoder;
+
+impl Encoder for PrettyEncoder {
+    fn encode(&self, record: &Record<'_>, buffer: &mut Vec<u8>) -> io::Result<()> {
+        write!(
+            buffer,
+            "{} {:>5} {}",
+            timestamp::local(&record.time()).date_time(),
+            record.level(),
+            record.message()
+        )?;
+        for (key, value) in record.fields() {
+            match value.as_str() {
+                Some(s) if !s.contains(char::is_whitespace) => write!(buffer, " {}={}", key, s)?,
+                _ => write!(buffer, " {}={}", key, value)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A human-readable encoder with colored levels, for local development.
+///
+/// Records look like `15:30:12.345  INFO request > db: Query done rows=3`: the time, the
+/// level, the spans the record belongs to, the message (`START` or `END` for span records)
+/// and the fields as `key=value` pairs. Being just another encoder, it can replace the JSON output with a single
+/// setting (`LOG_FORMAT=console` with [`JsonFormattingLayer::from_env`](crate::JsonFormattingLayer::from_env))
+/// or at runtime, with [`ReloadHandle::set_encoder`](crate::ReloadHandle::set_encoder).
+///
+/// Colors are on when the standard output is a terminal and `NO_COLOR` isn't set.
+///
+/// ```rust
+/// use tracing_json_formatter::{ConsoleEncoder, JsonFormattingLayer, MessageFormat};
+///
+/// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+///     .with_encoder(ConsoleEncoder::new())
+///     .with_message_format(MessageFormat::Raw);
+/// ```
+#[derive(Clone, Debug)]
+pub struct ConsoleEncoder {
+    colors: bool,
+}
+
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+const BOLD: &str = "\x1b[1m";
+
+impl ConsoleEncoder {
+    pub fn new() -> Self {
+        Self {
+            colors: std::env::var_os("NO_COLOR").is_none() && io::stdout().is_terminal(),
+        }
+    }
+
+    /// Color the output with ANSI escape codes, or not.
+    pub fn with_colors(mut self, colors: bool) -> Self {
+        self.colors = colors;
+        self
+    }
+
+    fn style<'a>(&self, style: &'static str, text: impl fmt::Display + 'a) -> Styled<'a> {
+        Styled {
+            style: if self.colors { style } else { "" },
+            text: Box::new(text),
+        }
+    }
+}
+
+impl Default for ConsoleEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Styled<'a> {
+    style: &'static str,
+    text: Box<dyn fmt::Display + 'a>,
+}
+
+impl fmt::Display for Styled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.style.is_empty() {
+            return self.text.fmt(f);
+        }
+        write!(f, "{}{}{}", self.style, self.text, RESET)
+    }
+}
+
+fn level_color(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "\x1b[31m",
+        Level::WARN => "\x1b[33m",
+        Level::INFO => "\x1b[32m",
+        Level::DEBUG => "\x1b[34m",
+        Level::TRACE => "\x1b[35m",
+    }
+}
+
+impl Encoder for ConsoleEncoder {
+    fn encode(&self, record: &Record<'_>, buffer: &mut Vec<u8>) -> io::Result<()> {
+        write!(
+            buffer,
+            "{} {}",
+            self.style(DIM, timestamp::local(&record.time()).time_millis()),
+            self.style(
+                level_color(record.level()),
+                format_args!("{:>5}", record.level())
+            ),
+        )?;
+        if !record.spans().is_empty() {
+            write!(buffer, " {}:", self.style(BOLD, record.spans().join(" > ")))?;
+        }
+        match record.ty() {
+            // The spans already name the span of START and END records.
+            Type::EnterSpan | Type::ExitSpan => {
+                write!(buffer, " {}", self.style(DIM, record.ty()))?
+            }
+            Type::Event => write!(buffer, " {}", record.message())?,
+        }
+        for (key, value) in record.fields() {
+            write!(buffer, " {}", self.style(DIM, format_args!("{}=", key)))?;
+            match value.as_str() {
+                Some(s) if !s.is_empty() && !s.contains(char::is_whitespace) => {
+                    buffer.extend_from_slice(s.as_bytes())
+                }
+                _ => write!(buffer, "{}", value)?,
+            }
+        }
+        Ok(())
+    }
+}
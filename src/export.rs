@@ -0,0 +1,593 @@
+//! Background delivery of records to network collectors, shared by the exporters.
+
+use crate::metrics::Metrics;
+use crate::schema::fields;
+use crate::timestamp::{self, Timestamp};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::io;
+#[cfg(any(feature = "loki", feature = "otlp", feature = "splunk"))]
+use std::io::{BufRead, Read, Write};
+#[cfg(any(feature = "loki", feature = "otlp", feature = "splunk"))]
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::Level;
+
+/// What a background writer does with a record written while its queue is full, i.e.
+/// while the collector is slower than the application or unreachable.
+///
+/// Records dropped by the policy are counted in
+/// [`Metrics::backpressure_dropped`](crate::Metrics::backpressure_dropped) of the
+/// metrics given to [`BatchConfig::with_metrics`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    /// Block the logging thread until the queue has room.
+    Block,
+    /// Drop the record being written.
+    #[default]
+    DropNewest,
+    /// Drop the oldest queued record to make room.
+    DropOldest,
+    /// Drop records less severe than the level, block for the others: with
+    /// `DropBelow(Level::ERROR)`, errors are never lost.
+    DropBelow(Level),
+}
+
+/// How an exporter batches, queues and retries records, given to e.g.
+/// [`LokiMakeWriter::with_batch`](crate::LokiMakeWriter::with_batch).
+///
+/// ```rust
+/// use std::time::Duration;
+/// use tracing_json_formatter::{BackpressurePolicy, BatchConfig};
+///
+/// let batch = BatchConfig::default()
+///     .with_batch_size(100)
+///     .with_batch_delay(Duration::from_millis(200))
+///     .with_backpressure(BackpressurePolicy::DropOldest);
+/// ```
+#[derive(Clone, Debug)]
+pub struct BatchConfig {
+    /// Send a batch once it holds this many records...
+    pub(crate) max_records: usize,
+    /// ...or once its oldest record has waited this long.
+    pub(crate) max_delay: Duration,
+    /// Records waiting to be batched, beyond which the backpressure policy applies.
+    pub(crate) queue_capacity: usize,
+    pub(crate) backpressure: BackpressurePolicy,
+    /// Attempts after the first failed one, waiting twice as long each time.
+    pub(crate) max_retries: u32,
+    pub(crate) initial_backoff: Duration,
+    /// Records at this level or more severe are delivered before `push` returns.
+    pub(crate) sync_level: Option<Level>,
+    /// How long dropping the exporter waits for the queued records to be delivered.
+    pub(crate) drain_timeout: Duration,
+    pub(crate) metrics: Option<Arc<Metrics>>,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_records: 500,
+            max_delay: Duration::from_secs(1),
+            queue_capacity: 10_000,
+            backpressure: BackpressurePolicy::default(),
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(100),
+            sync_level: None,
+            drain_timeout: Duration::from_secs(5),
+            metrics: None,
+        }
+    }
+}
+
+impl BatchConfig {
+    /// Send at most `max_records` records at once, 500 by default.
+    pub fn with_batch_size(mut self, max_records: usize) -> Self {
+        self.max_records = max_records.max(1);
+        self
+    }
+
+    /// Send records at most `max_delay` after they were written, one second by default.
+    pub fn with_batch_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Retry a failed batch up to `max_retries` times before dropping it, 5 by default.
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Apply `policy` to records written while the queue, of 10 000 records, is full,
+    /// [`BackpressurePolicy::DropNewest`] by default.
+    pub fn with_backpressure(mut self, policy: BackpressurePolicy) -> Self {
+        self.backpressure = policy;
+        self
+    }
+
+    /// Deliver records at `level` or more severe before returning from the write, along
+    /// with the records queued before them, instead of leaving them in the queue: with
+    /// `Level::ERROR`, errors survive an imminent crash. The logging thread waits for the
+    /// delivery, retries included.
+    pub fn with_sync_level(mut self, level: Level) -> Self {
+        self.sync_level = Some(level);
+        self
+    }
+
+    /// Wait at most `drain_timeout` for the queued records to be delivered when the
+    /// exporter is dropped, five seconds by default: past it, the records still queued
+    /// or being retried are dropped, so that an unreachable collector can't hold up the
+    /// exit of the application.
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// Count the records dropped by the backpressure policy, the retries running out or
+    /// the drain timeout in `metrics`, typically the
+    /// counters also given to [`JsonFormattingLayer::with_metrics`](crate::JsonFormattingLayer::with_metrics).
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+}
+
+struct Queue<T> {
+    items: VecDeque<T>,
+    /// Callers of [`Worker::flush`] waiting for the items queued before their call.
+    flushes: Vec<SyncSender<()>>,
+    closed: bool,
+    /// Set once the drain timeout elapsed: the thread stops retrying.
+    abandoned: bool,
+}
+
+struct Shared<T> {
+    queue: Mutex<Queue<T>>,
+    /// Signalled when items, flushes or the closing of the queue are pending.
+    pending: Condvar,
+    /// Signalled when items leave the queue.
+    room: Condvar,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, Queue<T>> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A background thread receiving items through a bounded queue and handing them over in
+/// batches to a send function, retrying failed batches with exponential backoff.
+///
+/// Batches that still fail after the last retry are dropped: the exporter must not grow
+/// without bounds while a collector is down.
+pub(crate) struct Worker<T> {
+    shared: Arc<Shared<T>>,
+    config: BatchConfig,
+    thread: Mutex<Option<JoinHandle<()>>>,
+    /// Disconnected once the thread exits.
+    exited: Receiver<()>,
+}
+
+impl<T: Send + 'static> Worker<T> {
+    pub(crate) fn spawn<F>(name: &str, config: BatchConfig, mut send: F) -> io::Result<Self>
+    where
+        F: FnMut(&[T]) -> io::Result<()> + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                items: VecDeque::with_capacity(config.queue_capacity.min(1024)),
+                flushes: Vec::new(),
+                closed: false,
+                abandoned: false,
+            }),
+            pending: Condvar::new(),
+            room: Condvar::new(),
+        });
+        let (exiting, exited) = mpsc::channel();
+        let thread = {
+            let shared = shared.clone();
+            let config = config.clone();
+            thread::Builder::new()
+                .name(name.to_owned())
+                .spawn(move || {
+                    let _exiting = exiting;
+                    run(&shared, &config, &mut send);
+                })?
+        };
+        Ok(Self {
+            shared,
+            config,
+            thread: Mutex::new(Some(thread)),
+            exited,
+        })
+    }
+}
+
+impl<T> Worker<T> {
+    /// Queue an item written for a record of the given level, applying the backpressure
+    /// policy when the queue is full, and wait for its delivery when the level calls for it.
+    pub(crate) fn push(&self, item: T, level: Option<&Level>) -> io::Result<()> {
+        self.enqueue(item, level)?;
+        let sync = self
+            .config
+            .sync_level
+            .is_some_and(|sync_level| level.is_some_and(|level| *level <= sync_level));
+        if sync {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn enqueue(&self, item: T, level: Option<&Level>) -> io::Result<()> {
+        let mut queue = self.shared.lock();
+        while queue.items.len() >= self.config.queue_capacity {
+            if queue.closed {
+                break;
+            }
+            let block = match self.config.backpressure {
+                BackpressurePolicy::Block => true,
+                BackpressurePolicy::DropNewest => false,
+                BackpressurePolicy::DropOldest => {
+                    queue.items.pop_front();
+                    self.count_dropped();
+                    break;
+                }
+                BackpressurePolicy::DropBelow(threshold) => level.is_some_and(|l| *l <= threshold),
+            };
+            if !block {
+                self.count_dropped();
+                return Ok(());
+            }
+            queue = self
+                .shared
+                .room
+                .wait(queue)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        if queue.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        queue.items.push_back(item);
+        self.shared.pending.notify_one();
+        Ok(())
+    }
+
+    /// Send the queued items now and wait for them to be delivered (or given up on).
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        let (done, wait) = mpsc::sync_channel(1);
+        {
+            let mut queue = self.shared.lock();
+            if queue.closed {
+                return Ok(());
+            }
+            queue.flushes.push(done);
+            self.shared.pending.notify_one();
+        }
+        wait.recv()
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn count_dropped(&self) {
+        count_dropped(&self.config, 1);
+    }
+}
+
+fn count_dropped(config: &BatchConfig, items: usize) {
+    if let Some(metrics) = &config.metrics {
+        Metrics::add(&metrics.backpressure_dropped, items);
+    }
+}
+
+impl<T> Drop for Worker<T> {
+    /// Deliver the queued items before the thread exits, for at most the drain timeout.
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.pending.notify_one();
+        self.shared.room.notify_all();
+        let thread = self
+            .thread
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        match self.exited.recv_timeout(self.config.drain_timeout) {
+            Err(RecvTimeoutError::Timeout) => {
+                // Leave the thread behind, it drops the batch it's sending once it fails.
+                let mut queue = self.shared.lock();
+                queue.abandoned = true;
+                let items = queue.items.len();
+                queue.items.clear();
+                count_dropped(&self.config, items);
+            }
+            _ => {
+                if let Some(thread) = thread {
+                    let _ = thread.join();
+                }
+            }
+        }
+    }
+}
+
+/// The loop of the background thread: fill a batch from the queue, send it once full, due,
+/// flushed or when the queue is closed.
+fn run<T, F>(shared: &Shared<T>, config: &BatchConfig, send: &mut F)
+where
+    F: FnMut(&[T]) -> io::Result<()>,
+{
+    let mut batch = Vec::with_capacity(config.max_records);
+    let mut deadline: Option<Instant> = None;
+    loop {
+        let (flushes, closed) = {
+            let mut queue = shared.lock();
+            loop {
+                let available = config.max_records.saturating_sub(batch.len());
+                let taken = available.min(queue.items.len());
+                if taken > 0 {
+                    batch.extend(queue.items.drain(..taken));
+                    shared.room.notify_all();
+                    deadline.get_or_insert_with(|| Instant::now() + config.max_delay);
+                }
+                let due = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+                if batch.len() >= config.max_records
+                    || due
+                    || !queue.flushes.is_empty()
+                    || queue.closed
+                {
+                    break;
+                }
+                queue = match deadline {
+                    Some(deadline) => {
+                        let timeout = deadline.saturating_duration_since(Instant::now());
+                        let (queue, _) = shared
+                            .pending
+                            .wait_timeout(queue, timeout)
+                            .unwrap_or_else(|e| e.into_inner());
+                        queue
+                    }
+                    None => shared
+                        .pending
+                        .wait(queue)
+                        .unwrap_or_else(|e| e.into_inner()),
+                };
+            }
+            // Flushes are only acknowledged once everything queued before them is sent.
+            let drained = queue.items.is_empty();
+            let flushes = if drained {
+                std::mem::take(&mut queue.flushes)
+            } else {
+                Vec::new()
+            };
+            (flushes, queue.closed && drained)
+        };
+        send_batch(shared, config, send, &mut batch);
+        deadline = None;
+        for flush in flushes {
+            let _ = flush.send(());
+        }
+        if closed {
+            return;
+        }
+    }
+}
+
+fn send_batch<T, F>(shared: &Shared<T>, config: &BatchConfig, send: &mut F, batch: &mut Vec<T>)
+where
+    F: FnMut(&[T]) -> io::Result<()>,
+{
+    if batch.is_empty() {
+        return;
+    }
+    let mut backoff = config.initial_backoff;
+    let mut attempt = 0;
+    while send(batch).is_err() {
+        if attempt == config.max_retries || shared.lock().abandoned {
+            count_dropped(config, batch.len());
+            break;
+        }
+        thread::sleep(backoff);
+        backoff *= 2;
+        attempt += 1;
+    }
+    batch.clear();
+}
+
+/// A minimal HTTP/1.1 client for `http://` endpoints, enough to POST batches to collectors.
+///
+/// TLS isn't supported: reach `https://` collectors through a local agent or a
+/// TLS-terminating proxy.
+#[derive(Clone, Debug)]
+#[cfg(any(feature = "loki", feature = "otlp", feature = "splunk"))]
+pub(crate) struct HttpEndpoint {
+    host: String,
+    authority: String,
+    path: String,
+    timeout: Duration,
+}
+
+#[cfg(any(feature = "loki", feature = "otlp", feature = "splunk"))]
+impl HttpEndpoint {
+    pub(crate) fn new(url: &str) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message);
+        if url.starts_with("https://") {
+            return Err(invalid(
+                "https URLs aren't supported, use a TLS-terminating proxy",
+            ));
+        }
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| invalid("the URL must start with http://"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            return Err(invalid("the URL has no host"));
+        }
+        let host = if authority.contains(':') {
+            authority.to_owned()
+        } else {
+            format!("{}:80", authority)
+        };
+        Ok(Self {
+            host,
+            authority: authority.to_owned(),
+            path: path.to_owned(),
+            timeout: Duration::from_secs(10),
+        })
+    }
+
+    /// POST `body`, failing unless the response has a 2xx status.
+    pub(crate) fn post(
+        &self,
+        content_type: &str,
+        headers: &[(String, String)],
+        body: &[u8],
+    ) -> io::Result<()> {
+        let address = self
+            .host
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host not found"))?;
+        let mut stream = TcpStream::connect_timeout(&address, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.path,
+            self.authority,
+            content_type,
+            body.len()
+        );
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+        stream.write_all(body)?;
+
+        let status = read_status(&mut io::BufReader::new(stream))?;
+        if !(200..300).contains(&status) {
+            return Err(io::Error::other(format!("HTTP status {}", status)));
+        }
+        Ok(())
+    }
+}
+
+/// The time of `record`, read from its `time` field, or the current time when it has none
+/// the exporter can read, e.g. when the layer is configured without it.
+pub(crate) fn record_time(record: &Value) -> Timestamp {
+    record
+        .get(fields::TIME)
+        .and_then(timestamp::parse)
+        .unwrap_or_else(Timestamp::now)
+}
+
+/// Read the status code of an HTTP/1.x response, skipping interim `1xx` responses.
+#[cfg(any(feature = "loki", feature = "otlp", feature = "splunk"))]
+fn read_status(response: &mut impl BufRead) -> io::Result<u16> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response");
+    loop {
+        let mut line = String::new();
+        response.by_ref().take(8 * 1024).read_line(&mut line)?;
+        // "HTTP/1.1 200 OK", the reason phrase being optional.
+        let mut parts = line.trim_end_matches(['\r', '\n']).splitn(3, ' ');
+        let version = parts.next().ok_or_else(invalid)?;
+        let status = parts
+            .next()
+            .filter(|status| status.len() == 3)
+            .and_then(|status| status.parse::<u16>().ok())
+            .filter(|_| version.starts_with("HTTP/1."))
+            .ok_or_else(invalid)?;
+        if !(100..200).contains(&status) {
+            return Ok(status);
+        }
+        // Skip the headers of the interim response.
+        loop {
+            line.clear();
+            if response.by_ref().take(8 * 1024).read_line(&mut line)? == 0 {
+                return Err(invalid());
+            }
+            if line == "\r\n" || line == "\n" {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(metrics: &Arc<Metrics>) -> BatchConfig {
+        BatchConfig::default()
+            .with_batch_delay(Duration::ZERO)
+            .with_drain_timeout(Duration::from_millis(50))
+            .with_metrics(metrics.clone())
+    }
+
+    #[test]
+    fn dropping_the_worker_delivers_the_queue() {
+        let metrics = Arc::new(Metrics::default());
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let worker = {
+            let delivered = delivered.clone();
+            Worker::spawn("test-exporter", config(&metrics), move |items: &[u32]| {
+                delivered.lock().unwrap().extend_from_slice(items);
+                Ok(())
+            })
+            .unwrap()
+        };
+        for item in 0..3 {
+            worker.push(item, None).unwrap();
+        }
+        drop(worker);
+
+        assert_eq!(*delivered.lock().unwrap(), [0, 1, 2]);
+        assert_eq!(metrics.backpressure_dropped(), 0);
+    }
+
+    #[test]
+    fn dropping_the_worker_gives_up_after_the_drain_timeout() {
+        let metrics = Arc::new(Metrics::default());
+        let config = config(&metrics)
+            .with_max_retries(u32::MAX)
+            .with_batch_size(1);
+        let worker = Worker::spawn("test-exporter", config, |_: &[u32]| {
+            Err(io::ErrorKind::ConnectionRefused.into())
+        })
+        .unwrap();
+        for item in 0..3 {
+            worker.push(item, None).unwrap();
+        }
+        let start = Instant::now();
+        drop(worker);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // The batch being retried is dropped by the thread once its current attempt fails.
+        while metrics.backpressure_dropped() < 3 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(metrics.backpressure_dropped(), 3);
+    }
+
+    #[test]
+    #[cfg(any(feature = "loki", feature = "otlp", feature = "splunk"))]
+    fn status_lines_are_parsed_whole() {
+        let status = |response: &str| read_status(&mut response.as_bytes()).ok();
+
+        assert_eq!(status("HTTP/1.1 204 No Content\r\n\r\n"), Some(204));
+        assert_eq!(status("HTTP/1.0 503\r\n"), Some(503));
+        assert_eq!(
+            status("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n"),
+            Some(200)
+        );
+        assert_eq!(status("HTTP/1.1 2000 OK\r\n"), None);
+        assert_eq!(status("HTTP/2 200\r\n"), None);
+        assert_eq!(status("HTTP/1.1 100 Continue\r\n"), None);
+        assert_eq!(status(""), None);
+    }
+}
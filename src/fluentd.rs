@@ -0,0 +1,542 @@
+use crate::export::{record_time, BatchConfig, Worker};
+use serde_json::Value;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A [`MakeWriter`] shipping records to Fluentd or Fluent Bit with the forward protocol.
+///
+/// Records are encoded as MessagePack `[time, record]` entries and sent in batches, in
+/// forward mode, from a background thread keeping a TCP connection to the `in_forward`
+/// input open. Batches are retried on a new connection when sending fails, with the same
+/// queueing, retries and flushing as [`LokiMakeWriter`](crate::LokiMakeWriter).
+///
+/// With [`FluentdMakeWriter::with_ack`], each batch must be acknowledged by the receiver
+/// before the next one is sent, so batches lost with a connection are sent again.
+///
+/// ```rust
+/// use tracing_json_formatter::{FluentdMakeWriter, JsonFormattingLayer};
+///
+/// let make_writer = FluentdMakeWriter::new("127.0.0.1:24224", "my-app.logs").with_ack(true);
+/// let layer = JsonFormattingLayer::new("my-app".into(), make_writer);
+/// ```
+pub struct FluentdMakeWriter {
+    address: String,
+    tag: String,
+    ack: bool,
+    timeout: Duration,
+    batch: BatchConfig,
+    worker: OnceLock<Option<Worker<Vec<u8>>>>,
+}
+
+impl FluentdMakeWriter {
+    /// Send to the forward input listening at `address`, e.g. `127.0.0.1:24224`, tagging
+    /// records with `tag`.
+    pub fn new(address: impl Into<String>, tag: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+            tag: tag.into(),
+            ack: false,
+            timeout: Duration::from_secs(10),
+            batch: BatchConfig::default(),
+            worker: OnceLock::new(),
+        }
+    }
+
+    /// Ask the receiver to acknowledge every batch, off by default.
+    pub fn with_ack(mut self, ack: bool) -> Self {
+        self.ack = ack;
+        self
+    }
+
+    /// Give up connecting, sending or waiting for an acknowledgement after `timeout`,
+    /// ten seconds by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Batch, queue and retry records as configured by `batch`: by default, the writer
+    /// sends up to 500 records at once, at most a second after they were written.
+    pub fn with_batch(mut self, batch: BatchConfig) -> Self {
+        self.batch = batch;
+        self
+    }
+
+    fn worker(&self) -> Option<&Worker<Vec<u8>>> {
+        self.worker
+            .get_or_init(|| {
+                let mut connection = Connection {
+                    address: self.address.clone(),
+                    tag: self.tag.clone(),
+                    ack: self.ack,
+                    timeout: self.timeout,
+                    stream: None,
+                };
+                Worker::spawn("fluentd-exporter", self.batch.clone(), move |entries| {
+                    connection.send(entries)
+                })
+                .ok()
+            })
+            .as_ref()
+    }
+
+    /// The `[time, record]` entry of a record, records that aren't JSON are sent as
+    /// `{"message": ...}`.
+    fn entry(&self, record: &[u8]) -> Vec<u8> {
+        let record = String::from_utf8_lossy(record);
+        let record = record.trim_end();
+        let record = match serde_json::from_str(record) {
+            Ok(record @ Value::Object(_)) => record,
+            _ => serde_json::json!({ "message": record }),
+        };
+        let time = record_time(&record).to_system_time();
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut entry = Vec::with_capacity(128);
+        entry.push(0x92);
+        // EventTime, the ext type 0 holding seconds and nanoseconds.
+        entry.extend_from_slice(&[0xd7, 0x00]);
+        entry.extend_from_slice(&(time.as_secs() as u32).to_be_bytes());
+        entry.extend_from_slice(&time.subsec_nanos().to_be_bytes());
+        encode_value(&mut entry, &record);
+        entry
+    }
+}
+
+struct Connection {
+    address: String,
+    tag: String,
+    ack: bool,
+    timeout: Duration,
+    stream: Option<TcpStream>,
+}
+
+impl Connection {
+    /// Send `entries` in forward mode, reconnecting on the next call if anything fails.
+    fn send(&mut self, entries: &[Vec<u8>]) -> io::Result<()> {
+        let result = self.try_send(entries);
+        if result.is_err() {
+            self.stream = None;
+        }
+        result
+    }
+
+    fn try_send(&mut self, entries: &[Vec<u8>]) -> io::Result<()> {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => self.stream.insert(connect(&self.address, self.timeout)?),
+        };
+        let mut message = Vec::with_capacity(entries.iter().map(Vec::len).sum::<usize>() + 64);
+        message.push(0x93);
+        encode_str(&mut message, &self.tag);
+        encode_len(&mut message, entries.len(), 0x90, 0xdc);
+        for entry in entries {
+            message.extend_from_slice(entry);
+        }
+        let chunk = self.ack.then(chunk_id);
+        match &chunk {
+            Some(chunk) => {
+                message.push(0x81);
+                encode_str(&mut message, "chunk");
+                encode_str(&mut message, chunk);
+            }
+            None => message.push(0x80),
+        }
+        stream.write_all(&message)?;
+        if let Some(chunk) = chunk {
+            read_ack(stream, &chunk)?;
+        }
+        Ok(())
+    }
+}
+
+fn connect(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host not found"))?;
+    let stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// A chunk ID unique within the process, echoed back by the receiver in its ack.
+fn chunk_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    format!(
+        "{:x}-{:x}-{:x}",
+        nanos,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Wait for the `{"ack": chunk}` response of the receiver.
+fn read_ack(stream: &mut impl Read, chunk: &str) -> io::Result<()> {
+    match decode_value(stream, 0)?.get("ack") {
+        Some(Value::String(ack)) if ack == chunk => Ok(()),
+        _ => Err(invalid_data("unexpected acknowledgement")),
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Nesting and length limits of the responses of the receiver, small maps of strings.
+const MAX_DEPTH: usize = 8;
+const MAX_LEN: usize = 64 * 1024;
+
+/// Decode a MessagePack value from `input`, binaries and extensions becoming `null`.
+fn decode_value(input: &mut impl Read, depth: usize) -> io::Result<Value> {
+    if depth > MAX_DEPTH {
+        return Err(invalid_data("acknowledgement nested too deeply"));
+    }
+    let marker = read_array::<1>(input)?[0];
+    let value = match marker {
+        0x00..=0x7f => Value::from(marker),
+        0x80..=0x8f => decode_map(input, usize::from(marker & 0x0f), depth)?,
+        0x90..=0x9f => decode_array(input, usize::from(marker & 0x0f), depth)?,
+        0xa0..=0xbf => decode_str(input, usize::from(marker & 0x1f))?,
+        0xc0 => Value::Null,
+        0xc2 => Value::Bool(false),
+        0xc3 => Value::Bool(true),
+        0xc4..=0xc6 => {
+            let len = read_len(input, 1 << (marker - 0xc4))?;
+            skip(input, len)?;
+            Value::Null
+        }
+        0xc7..=0xc9 => {
+            let len = read_len(input, 1 << (marker - 0xc7))?;
+            skip(input, len + 1)?;
+            Value::Null
+        }
+        0xca => Value::from(f32::from_be_bytes(read_array(input)?)),
+        0xcb => Value::from(f64::from_be_bytes(read_array(input)?)),
+        0xcc => Value::from(u8::from_be_bytes(read_array(input)?)),
+        0xcd => Value::from(u16::from_be_bytes(read_array(input)?)),
+        0xce => Value::from(u32::from_be_bytes(read_array(input)?)),
+        0xcf => Value::from(u64::from_be_bytes(read_array(input)?)),
+        0xd0 => Value::from(i8::from_be_bytes(read_array(input)?)),
+        0xd1 => Value::from(i16::from_be_bytes(read_array(input)?)),
+        0xd2 => Value::from(i32::from_be_bytes(read_array(input)?)),
+        0xd3 => Value::from(i64::from_be_bytes(read_array(input)?)),
+        0xd4..=0xd8 => {
+            skip(input, 1 + (1 << (marker - 0xd4)))?;
+            Value::Null
+        }
+        0xd9..=0xdb => {
+            let len = read_len(input, 1 << (marker - 0xd9))?;
+            decode_str(input, len)?
+        }
+        0xdc | 0xdd => {
+            let len = read_len(input, 2 << (marker - 0xdc))?;
+            decode_array(input, len, depth)?
+        }
+        0xde | 0xdf => {
+            let len = read_len(input, 2 << (marker - 0xde))?;
+            decode_map(input, len, depth)?
+        }
+        0xe0..=0xff => Value::from(marker as i8),
+        0xc1 => return Err(invalid_data("invalid MessagePack marker")),
+    };
+    Ok(value)
+}
+
+fn decode_map(input: &mut impl Read, len: usize, depth: usize) -> io::Result<Value> {
+    let mut map = serde_json::Map::new();
+    for _ in 0..len {
+        let Value::String(key) = decode_value(input, depth + 1)? else {
+            return Err(invalid_data("acknowledgement with a non-string key"));
+        };
+        map.insert(key, decode_value(input, depth + 1)?);
+    }
+    Ok(Value::Object(map))
+}
+
+fn decode_array(input: &mut impl Read, len: usize, depth: usize) -> io::Result<Value> {
+    (0..len)
+        .map(|_| decode_value(input, depth + 1))
+        .collect::<io::Result<_>>()
+        .map(Value::Array)
+}
+
+fn decode_str(input: &mut impl Read, len: usize) -> io::Result<Value> {
+    let mut bytes = vec![0; len];
+    input.read_exact(&mut bytes)?;
+    String::from_utf8(bytes)
+        .map(Value::String)
+        .map_err(|_| invalid_data("acknowledgement with an invalid string"))
+}
+
+fn read_array<const N: usize>(input: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// A big-endian length of `size` bytes, up to [`MAX_LEN`].
+fn read_len(input: &mut impl Read, size: usize) -> io::Result<usize> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes[4 - size..])?;
+    let len = u32::from_be_bytes(bytes) as usize;
+    if len > MAX_LEN {
+        return Err(invalid_data("acknowledgement too large"));
+    }
+    Ok(len)
+}
+
+fn skip(input: &mut impl Read, len: usize) -> io::Result<()> {
+    let skipped = io::copy(&mut input.take(len as u64), &mut io::sink())?;
+    if skipped < len as u64 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+fn encode_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(false) => out.push(0xc2),
+        Value::Bool(true) => out.push(0xc3),
+        Value::Number(number) => {
+            if let Some(n) = number.as_u64() {
+                encode_uint(out, n);
+            } else if let Some(n) = number.as_i64() {
+                encode_int(out, n);
+            } else if let Some(n) = number.as_f64() {
+                out.push(0xcb);
+                out.extend_from_slice(&n.to_be_bytes());
+            } else {
+                encode_str(out, &number.to_string());
+            }
+        }
+        Value::String(s) => encode_str(out, s),
+        Value::Array(values) => {
+            encode_len(out, values.len(), 0x90, 0xdc);
+            for value in values {
+                encode_value(out, value);
+            }
+        }
+        Value::Object(fields) => {
+            encode_len(out, fields.len(), 0x80, 0xde);
+            for (key, value) in fields {
+                encode_str(out, key);
+                encode_value(out, value);
+            }
+        }
+    }
+}
+
+fn encode_uint(out: &mut Vec<u8>, n: u64) {
+    if n < 0x80 {
+        out.push(n as u8);
+    } else if n <= u64::from(u8::MAX) {
+        out.extend_from_slice(&[0xcc, n as u8]);
+    } else if n <= u64::from(u16::MAX) {
+        out.push(0xcd);
+        out.extend_from_slice(&(n as u16).to_be_bytes());
+    } else if n <= u64::from(u32::MAX) {
+        out.push(0xce);
+        out.extend_from_slice(&(n as u32).to_be_bytes());
+    } else {
+        out.push(0xcf);
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+/// Negative integers, non-negative ones go through [`encode_uint`].
+fn encode_int(out: &mut Vec<u8>, n: i64) {
+    if n >= -32 {
+        out.push(n as u8);
+    } else if n >= i64::from(i8::MIN) {
+        out.extend_from_slice(&[0xd0, n as u8]);
+    } else if n >= i64::from(i16::MIN) {
+        out.push(0xd1);
+        out.extend_from_slice(&(n as i16).to_be_bytes());
+    } else if n >= i64::from(i32::MIN) {
+        out.push(0xd2);
+        out.extend_from_slice(&(n as i32).to_be_bytes());
+    } else {
+        out.push(0xd3);
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+fn encode_str(out: &mut Vec<u8>, s: &str) {
+    let len = s.len();
+    if len < 32 {
+        out.push(0xa0 | len as u8);
+    } else if len <= usize::from(u8::MAX) {
+        out.extend_from_slice(&[0xd9, len as u8]);
+    } else if len <= usize::from(u16::MAX) {
+        out.push(0xda);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(0xdb);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// The header of an array (`fix` = 0x90, `long` = 0xdc) or a map (0x80, 0xde) of `len`
+/// elements, the 32-bit form following the 16-bit one.
+fn encode_len(out: &mut Vec<u8>, len: usize, fix: u8, long: u8) {
+    if len < 16 {
+        out.push(fix | len as u8);
+    } else if len <= usize::from(u16::MAX) {
+        out.push(long);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(long + 1);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+impl<'a> MakeWriter<'a> for FluentdMakeWriter {
+    type Writer = FluentdWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        FluentdWriter {
+            make_writer: self,
+            level: None,
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        FluentdWriter {
+            make_writer: self,
+            level: Some(*meta.level()),
+        }
+    }
+}
+
+/// The writer returned by [`FluentdMakeWriter`]: each `write` call is queued as one record.
+pub struct FluentdWriter<'a> {
+    make_writer: &'a FluentdMakeWriter,
+    level: Option<Level>,
+}
+
+impl Write for FluentdWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let worker = self
+            .make_writer
+            .worker()
+            .ok_or_else(|| io::Error::other("the Fluentd exporter thread couldn't be started"))?;
+        worker.push(self.make_writer.entry(buf), self.level.as_ref())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.make_writer.worker.get() {
+            Some(Some(worker)) => worker.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn encoded(value: &Value) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode_value(&mut out, value);
+        out
+    }
+
+    #[test]
+    fn acks_are_decoded_not_compared_byte_for_byte() {
+        let ack = |value: Value| read_ack(&mut encoded(&value).as_slice(), "c-1").is_ok();
+
+        assert!(ack(json!({ "ack": "c-1" })));
+        assert!(ack(json!({ "size": 3, "ack": "c-1" })));
+        assert!(!ack(json!({ "ack": "c-2" })));
+        assert!(!ack(json!({ "ack": 1 })));
+        assert!(!ack(json!(["ack", "c-1"])));
+
+        // A str8 key, where our encoder writes a fixstr.
+        let response = [&[0x81, 0xd9, 3][..], b"ack", &[0xa3], b"c-1"].concat();
+        assert!(read_ack(&mut response.as_slice(), "c-1").is_ok());
+        assert!(read_ack(&mut &response[..6], "c-1").is_err());
+    }
+
+    #[test]
+    fn decoding_reverses_encoding() {
+        let value = json!({
+            "null": null,
+            "bools": [true, false],
+            "ints": [0, 127, 128, 255, 256, 65_535, 65_536, u32::MAX, u64::MAX],
+            "negative": [-1, -32, -33, -128, -129, -32_768, -32_769, i32::MIN, i64::MIN],
+            "float": 1.5,
+            "strings": ["", "a".repeat(31), "b".repeat(32), "c".repeat(256)],
+            "nested": { "array": (0..16).collect::<Vec<_>>() },
+        });
+        assert_eq!(
+            decode_value(&mut encoded(&value).as_slice(), 0).unwrap(),
+            value
+        );
+    }
+
+    #[test]
+    fn integers_take_the_smallest_encoding() {
+        let int = |n: Value| encoded(&n);
+
+        assert_eq!(int(json!(0)), [0x00]);
+        assert_eq!(int(json!(127)), [0x7f]);
+        assert_eq!(int(json!(128)), [0xcc, 0x80]);
+        assert_eq!(int(json!(255)), [0xcc, 0xff]);
+        assert_eq!(int(json!(256)), [0xcd, 0x01, 0x00]);
+        assert_eq!(int(json!(65_535)), [0xcd, 0xff, 0xff]);
+        assert_eq!(int(json!(65_536)), [0xce, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(int(json!(u32::MAX)), [0xce, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(
+            int(json!(u64::from(u32::MAX) + 1)),
+            [0xcf, 0, 0, 0, 1, 0, 0, 0, 0]
+        );
+        assert_eq!(int(json!(-1)), [0xff]);
+        assert_eq!(int(json!(-32)), [0xe0]);
+        assert_eq!(int(json!(-33)), [0xd0, 0xdf]);
+        assert_eq!(int(json!(-128)), [0xd0, 0x80]);
+        assert_eq!(int(json!(-129)), [0xd1, 0xff, 0x7f]);
+        assert_eq!(int(json!(-32_768)), [0xd1, 0x80, 0x00]);
+        assert_eq!(int(json!(-32_769)), [0xd2, 0xff, 0xff, 0x7f, 0xff]);
+        assert_eq!(int(json!(i32::MIN)), [0xd2, 0x80, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            int(json!(i64::from(i32::MIN) - 1)),
+            [0xd3, 0xff, 0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff]
+        );
+    }
+
+    #[test]
+    fn strings_and_collections_take_the_smallest_header() {
+        let header = |value: Value, len: usize| encoded(&value)[..len].to_vec();
+
+        assert_eq!(header(json!("a".repeat(31)), 1), [0xbf]);
+        assert_eq!(header(json!("a".repeat(32)), 2), [0xd9, 32]);
+        assert_eq!(header(json!("a".repeat(255)), 2), [0xd9, 0xff]);
+        assert_eq!(header(json!("a".repeat(256)), 3), [0xda, 0x01, 0x00]);
+        assert_eq!(header(json!("a".repeat(65_535)), 3), [0xda, 0xff, 0xff]);
+        assert_eq!(header(json!("a".repeat(65_536)), 5), [0xdb, 0, 1, 0, 0]);
+
+        assert_eq!(header(json!(vec![0; 15]), 1), [0x9f]);
+        assert_eq!(header(json!(vec![0; 16]), 3), [0xdc, 0x00, 0x10]);
+        assert_eq!(header(json!(vec![0; 65_535]), 3), [0xdc, 0xff, 0xff]);
+        assert_eq!(header(json!(vec![0; 65_536]), 5), [0xdd, 0, 1, 0, 0]);
+
+        let map = |len: usize| Value::Object((0..len).map(|i| (i.to_string(), json!(0))).collect());
+        assert_eq!(header(map(15), 1), [0x8f]);
+        assert_eq!(header(map(16), 3), [0xde, 0x00, 0x10]);
+        assert_eq!(header(map(65_536), 5), [0xdf, 0, 1, 0, 0]);
+    }
+}
@@ -1,77 +1,2395 @@
-use crate::storage_layer::JsonStorage;
-use chrono::Local;
-use serde::ser::{SerializeMap, Serializer};
+use crate::audit::{sha256_hex, AuditLog};
+use crate::buffer::with_buffer;
+use crate::child::is_binding_span;
+use crate::clock::{Clock, FixedClock, SystemClock};
+use crate::dedup::{Deduplicator, Repeated, Verdict};
+use crate::encoder::{
+    serialize_entries, ConsoleEncoder, EcsEncoder, Encoder, JsonEncoder, PrettyEncoder,
+};
+use crate::guard::{flush_all, Emitter, FlushGuard, Flusher};
+use crate::lambda::LambdaContext;
+use crate::log_context;
+use crate::metrics::Metrics;
+use crate::process;
+use crate::record::{CoreFields, Entry, FieldValue, Record};
+#[cfg(feature = "record-id")]
+use crate::record_id::RecordIdFormat;
+use crate::reload::ReloadHandle;
+use crate::sampling::{Sampler, SUMMARY_METADATA};
+use crate::schema::{fields, FieldKind, Schema, SchemaField};
+use crate::span_summary::{SpanSummaries, SPAN_SUMMARY_METADATA};
+use crate::storage_layer::{
+    ChildSpan, JsonStorage, JsonStorageLayer, QuietSpan, RecordedFields, SerializedSpanFields,
+    StorageConfig, Timings,
+};
+use crate::test_support::Snapshot;
+use crate::timestamp::{self, Timestamp};
 use serde_json::Value;
+use std::any::type_name;
+use std::backtrace::Backtrace;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
 use std::io::Write;
-use tracing::{Event, Id, Subscriber};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use tracing::level_filters::LevelFilter;
+use tracing::{Dispatch, Event, Id, Level, Metadata, Subscriber};
+use tracing_core::callsite::Identifier;
 use tracing_core::span::Attributes;
+use tracing_core::subscriber::Interest;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::layer::Context;
-use tracing_subscriber::registry::SpanRef;
+use tracing_subscriber::registry::{Extensions, SpanRef};
 use tracing_subscriber::Layer;
 
+/// A layer formatting spans and events into records and writing them out.
+///
+/// It doesn't store span fields itself: it reads them from the span extensions, where a
+/// [`JsonStorageLayer`] installed below it puts them. A single storage layer can serve
+/// several formatting layers, fields are then collected (and serialized, see [`JsonEncoder`])
+/// once for all of them:
+///
+/// ```rust
+/// use tracing_json_formatter::{JsonFormattingLayer, JsonStorageLayer, PrettyEncoder};
+/// use tracing_subscriber::prelude::*;
+///
+/// tracing_subscriber::registry()
+///     .with(JsonStorageLayer::new())
+///     .with(JsonFormattingLayer::new("my-app".into(), std::io::stdout).with_encoder(PrettyEncoder))
+///     .with(JsonFormattingLayer::new("my-app".into(), std::io::stderr))
+///     .init();
+/// ```
 pub struct JsonFormattingLayer<W: for<'a> MakeWriter<'a> + 'static> {
-    make_writer: W,
-    pid: u32,
-    hostname: String,
+    make_writer: Arc<W>,
+    encoder: Box<dyn Encoder>,
+    encoder_name: &'static str,
+    clock: Box<dyn Clock>,
+    sinks: Vec<Sink>,
+    audit: Option<AuditLog>,
+    shut_down: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
+    reload: ReloadHandle,
+    sampler: Option<Sampler>,
+    deduplicator: Option<Deduplicator>,
+    span_summaries: Option<SpanSummaries>,
+    max_record_size: Option<usize>,
+    init_record: bool,
+    initialized: AtomicBool,
+    write_retries: u32,
+    write_backoff: Duration,
+    fallback: Option<Arc<BoxMakeWriter>>,
+    max_field_length: Option<usize>,
+    control_characters: ControlCharacters,
+    key_collision: KeyCollision,
+    reserved_keys: ReservedKeys,
+    target_levels: Vec<(String, LevelFilter)>,
+    level_remaps: Vec<LevelRemap>,
+    span_level: Option<LevelFilter>,
+    rejected_key_handler: Option<RejectedKeyHandler>,
+    nested_src: bool,
+    module_path: bool,
+    callsite_name: bool,
+    sorted_fields: bool,
+    span_field_prefix: Option<String>,
+    framing: Framing,
+    transformers: HashMap<String, Vec<ValueTransformer>>,
+    enrichers: Vec<Enricher>,
+    core_field_hooks: Vec<CoreFieldHook>,
+    static_fields: Vec<(String, Value)>,
+    span_timings: bool,
+    timing_unit: TimingUnit,
+    transactions: bool,
+    tail_sampling: Option<Level>,
+    snapshot: bool,
+    started_at: bool,
+    uptime: bool,
+    final_fields: bool,
+    time_format: TimeFormat,
+    level_format: LevelFormat,
+    message_format: MessageFormat,
+    missing_message: MissingMessage,
+    raw_message: bool,
+    message_details: Option<String>,
+    raw_json_fields: Vec<String>,
+    backtrace_level: Option<Level>,
+    lifecycle_labels: LifecycleLabels,
+    lifecycle_field: bool,
+    span_events: SpanEvents,
+    span_path: SpanPath,
+    storage_config: OnceLock<StorageConfig>,
+    callsites: RwLock<HashMap<Identifier, Arc<CallsiteFields>>>,
+    pid: Option<u32>,
+    hostname: Option<String>,
+    hostname_resolver: Option<HostnameResolver>,
     name: String,
 }
 
+synthetic_metadata!(INIT_METADATA, "logger initialized", Level::INFO);
+
+type Enricher = Box<dyn Fn(&Metadata<'_>, &mut Record<'_>) + Send + Sync>;
+type CoreFieldHook = Box<dyn Fn(&Metadata<'_>, &mut CoreFields<'_, '_>) + Send + Sync>;
+type ValueTransformer = Box<dyn Fn(&str, Value) -> Option<Value> + Send + Sync>;
+type HostnameResolve = Box<dyn Fn() -> Option<String> + Send + Sync>;
+type RejectedKeyHandler = Box<dyn Fn(&Metadata<'_>, &str) + Send + Sync>;
+
+/// The level given to the records of `target` at level `from`.
+struct LevelRemap {
+    target: String,
+    from: Level,
+    to: Level,
+}
+
+/// The source of the `host` field, see [`JsonFormattingLayer::with_hostname_resolver`].
+struct HostnameResolver {
+    resolve: HostnameResolve,
+    refresh: Option<Duration>,
+    /// The last hostname resolved, and when.
+    resolved: RwLock<(Instant, Option<String>)>,
+}
+
+impl HostnameResolver {
+    fn new(resolve: HostnameResolve, refresh: Option<Duration>) -> Self {
+        let resolved = RwLock::new((Instant::now(), resolve()));
+        Self {
+            resolve,
+            refresh,
+            resolved,
+        }
+    }
+
+    /// The hostname, resolved again if the refresh interval has elapsed.
+    fn hostname(&self) -> Option<String> {
+        let is_stale = |resolved: &(Instant, Option<String>)| {
+            self.refresh
+                .is_some_and(|refresh| resolved.0.elapsed() >= refresh)
+        };
+        {
+            let resolved = self.resolved.read().unwrap_or_else(|e| e.into_inner());
+            if !is_stale(&resolved) {
+                return resolved.1.clone();
+            }
+        }
+        let mut resolved = self.resolved.write().unwrap_or_else(|e| e.into_inner());
+        // Another thread may have refreshed it in the meantime.
+        if is_stale(&resolved) {
+            *resolved = (Instant::now(), (self.resolve)());
+        }
+        resolved.1.clone()
+    }
+}
+
+/// An additional destination, pairing an encoder with a writer.
+struct Sink {
+    encoder: Box<dyn Encoder>,
+    make_writer: Arc<BoxMakeWriter>,
+}
+
+/// The core entries of the records of a callsite, from `level` to the source location,
+/// which only depend on the callsite: built and serialized once, then borrowed by every
+/// record.
+struct CallsiteFields {
+    metadata: &'static Metadata<'static>,
+    entries: Vec<Entry<'static>>,
+    serialized: Option<Arc<[u8]>>,
+}
+
 #[derive(Debug)]
 pub struct Config {
     pub offset: i8,
 }
 
-impl<W: for<'a> MakeWriter<'a> + 'static> JsonFormattingLayer<W> {
-    pub fn new(name: String, make_writer: W) -> Self {
-        Self::with_default_fields(name, make_writer)
+impl<W: for<'a> MakeWriter<'a> + 'static> JsonFormattingLayer<W> {
+    pub fn new(name: String, make_writer: W) -> Self {
+        Self::with_default_fields(name, make_writer)
+    }
+
+    pub fn with_default_fields(name: String, make_writer: W) -> Self {
+        Self {
+            make_writer: Arc::new(make_writer),
+            encoder: Box::new(JsonEncoder::new()),
+            encoder_name: type_name::<JsonEncoder>(),
+            clock: Box::new(SystemClock),
+            sinks: Vec::new(),
+            audit: None,
+            shut_down: Arc::default(),
+            metrics: Arc::default(),
+            reload: ReloadHandle {
+                config: Arc::default(),
+            },
+            sampler: None,
+            deduplicator: None,
+            span_summaries: None,
+            max_record_size: None,
+            init_record: false,
+            initialized: AtomicBool::new(false),
+            write_retries: 0,
+            write_backoff: Duration::ZERO,
+            fallback: None,
+            max_field_length: None,
+            control_characters: ControlCharacters::default(),
+            key_collision: KeyCollision::default(),
+            reserved_keys: ReservedKeys::default(),
+            target_levels: Vec::new(),
+            level_remaps: Vec::new(),
+            span_level: None,
+            rejected_key_handler: None,
+            nested_src: false,
+            module_path: false,
+            callsite_name: false,
+            sorted_fields: false,
+            span_field_prefix: None,
+            framing: Framing::default(),
+            transformers: HashMap::new(),
+            enrichers: Vec::new(),
+            core_field_hooks: Vec::new(),
+            static_fields: Vec::new(),
+            span_timings: false,
+            timing_unit: TimingUnit::default(),
+            transactions: false,
+            tail_sampling: None,
+            snapshot: false,
+            started_at: false,
+            uptime: false,
+            final_fields: false,
+            time_format: TimeFormat::default(),
+            level_format: LevelFormat::default(),
+            message_format: MessageFormat::default(),
+            missing_message: MissingMessage::default(),
+            raw_message: false,
+            message_details: None,
+            raw_json_fields: Vec::new(),
+            backtrace_level: None,
+            lifecycle_labels: LifecycleLabels::default(),
+            lifecycle_field: false,
+            span_events: SpanEvents::default(),
+            span_path: SpanPath::default(),
+            storage_config: OnceLock::new(),
+            callsites: RwLock::default(),
+            name,
+            pid: process::pid(),
+            hostname: process::hostname(),
+            hostname_resolver: None,
+        }
+    }
+
+    /// Build a layer configured from environment variables, so that deployments can change
+    /// the output without recompiling:
+    ///
+    /// - `LOG_FORMAT`: `bunyan` (JSON, the default), `console` ([`ConsoleEncoder`], with raw
+    ///   messages since it shows the spans of records), `pretty` ([`PrettyEncoder`]) or `ecs`
+    ///   ([`EcsEncoder`]);
+    /// - `LOG_TIME`: `local` (the default), `utc`, `epoch`, `utc-nanos` or `epoch-nanos`, see
+    ///   [`TimeFormat`];
+    /// - `LOG_SPAN_EVENTS`: `full` (the default), `close` or `none`, see [`SpanEvents`].
+    ///
+    /// Unset variables and unknown values leave the default in place.
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::JsonFormattingLayer;
+    ///
+    /// let layer = JsonFormattingLayer::from_env("my-app".into(), std::io::stdout);
+    /// ```
+    pub fn from_env(name: String, make_writer: W) -> Self {
+        let var = |key: &str| std::env::var(key).ok().map(|v| v.to_ascii_lowercase());
+        let mut layer = Self::new(name, make_writer);
+        match var("LOG_FORMAT").as_deref() {
+            Some("console") => {
+                layer = layer
+                    .with_encoder(ConsoleEncoder::new())
+                    .with_message_format(MessageFormat::Raw)
+            }
+            Some("pretty") => layer = layer.with_encoder(PrettyEncoder),
+            Some("ecs") => layer = layer.with_encoder(EcsEncoder),
+            _ => {}
+        }
+        match var("LOG_TIME").as_deref() {
+            Some("utc") => layer = layer.with_time_format(TimeFormat::Utc),
+            Some("epoch") => layer = layer.with_time_format(TimeFormat::Epoch),
+            Some("utc-nanos") => layer = layer.with_time_format(TimeFormat::UtcNanos),
+            Some("epoch-nanos") => layer = layer.with_time_format(TimeFormat::EpochNanos),
+            _ => {}
+        }
+        match var("LOG_SPAN_EVENTS").as_deref() {
+            Some("close") => layer = layer.with_span_events(SpanEvents::Close),
+            Some("none") => layer = layer.with_span_events(SpanEvents::None),
+            _ => {}
+        }
+        layer
+    }
+
+    /// Replace the encoder used for the main writer, [`JsonEncoder`] by default.
+    pub fn with_encoder<E: Encoder + 'static>(mut self, encoder: E) -> Self {
+        self.encoder = Box::new(encoder);
+        self.encoder_name = type_name::<E>();
+        self
+    }
+
+    /// Send every record to an additional destination, encoded with its own encoder.
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::{JsonEncoder, JsonFormattingLayer, PrettyEncoder};
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_encoder(PrettyEncoder)
+    ///     .with_sink(JsonEncoder::new(), std::io::stderr);
+    /// ```
+    pub fn with_sink<E, M>(mut self, encoder: E, make_writer: M) -> Self
+    where
+        E: Encoder + 'static,
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        self.sinks.push(Sink {
+            encoder: Box::new(encoder),
+            make_writer: Arc::new(BoxMakeWriter::new(make_writer)),
+        });
+        self
+    }
+
+    /// Write audit records, events with `field` set to `true` (e.g. `audit = true`), to
+    /// `make_writer` instead of the other destinations, as a tamper-evident hash chain.
+    ///
+    /// Audit records are always encoded as JSON, with two more fields: `prev_hash`, the
+    /// `hash` of the previous audit record (64 zeros for the first one written by the
+    /// process), and `hash`, the hex-encoded SHA-256 of the line without its `hash` field,
+    /// i.e. up to `"prev_hash":"…"` followed by `}`. Editing, removing or reordering lines
+    /// breaks the chain from that point on. Only the chain is checked: store the hash of the
+    /// last record elsewhere to detect a truncated log, and keep in mind that someone able to
+    /// rewrite the whole log can compute a new chain.
+    ///
+    /// Only the fields of the event itself count: events inside a span with the field set
+    /// are not audit records. Audit records are neither sampled nor deduplicated, but the
+    /// level filters still apply. The size limit of [`JsonFormattingLayer::with_max_record_size`]
+    /// doesn't apply to audit records, it would break the chain.
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::JsonFormattingLayer;
+    ///
+    /// let audit_log = std::fs::File::options()
+    ///     .create(true)
+    ///     .append(true)
+    ///     .open(std::env::temp_dir().join("audit.log"))
+    ///     .unwrap();
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_audit_log("audit", std::sync::Mutex::new(audit_log));
+    ///
+    /// // {..., "message": "Role granted", "audit": true, "user_id": 42, "prev_hash": "…", "hash": "…"}
+    /// tracing::info!(audit = true, user_id = 42, "Role granted");
+    /// ```
+    pub fn with_audit_log<M>(mut self, field: impl Into<String>, make_writer: M) -> Self
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        self.audit = Some(AuditLog::new(field.into(), BoxMakeWriter::new(make_writer)));
+        self
+    }
+
+    /// Collapse identical events (same callsite, level, message and event fields) repeated
+    /// within `window` of the first one: the first is emitted, the repeats are counted and
+    /// reported as a single record with a `repeat_count` field, emitted with the next
+    /// different event, or when the layer or its [guard](Self::guard) is flushed, shut down
+    /// or dropped.
+    ///
+    /// This keeps tight error loops from flooding the output. Span fields and the fields
+    /// added by the layer are left out of the comparison and of the `repeat_count` record.
+    /// With [transactions](Self::with_transactions), the `repeat_count` record is bundled
+    /// with the repeated event, by the end of its root span at the latest. The repeats are
+    /// counted in [`Metrics::dropped`].
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    /// use tracing_json_formatter::JsonFormattingLayer;
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_deduplication(Duration::from_secs(1));
+    /// ```
+    pub fn with_deduplication(mut self, window: Duration) -> Self {
+        self.deduplicator = Some(Deduplicator::new(window));
+        self
+    }
+
+    /// Rate limit high-volume events, see [`Sampler`].
+    pub fn with_sampler(mut self, sampler: Sampler) -> Self {
+        self.sampler = Some(sampler);
+        self
+    }
+
+    /// Hold the events inside root spans that the level filters leave out, down to `level`,
+    /// and emit them only if the span fails: detailed diagnostics for failed requests,
+    /// without the volume of successful ones.
+    ///
+    /// A root span fails when an `ERROR` event is emitted inside it, or when it or one of
+    /// its child spans closes with an `error` field set to `true`. The held events are then
+    /// emitted, with their original time, before the END record of the root span; otherwise
+    /// they are discarded. At most the last 1 000 events are held per root span, and events
+    /// outside of any span are filtered as usual.
+    ///
+    /// ```rust
+    /// use tracing::level_filters::LevelFilter;
+    /// use tracing::Level;
+    /// use tracing_json_formatter::JsonFormattingLayer;
+    ///
+    /// let layer =
+    ///     JsonFormattingLayer::new("my-app".into(), std::io::stdout).with_tail_sampling(Level::DEBUG);
+    /// layer.reload_handle().set_max_level(LevelFilter::INFO);
+    ///
+    /// tracing::info_span!("request").in_scope(|| {
+    ///     // Emitted only because of the error below.
+    ///     tracing::debug!(rows = 0, "Queried the database");
+    ///     tracing::error!("User not found");
+    /// });
+    /// ```
+    pub fn with_tail_sampling(mut self, level: Level) -> Self {
+        self.tail_sampling = Some(level);
+        self
+    }
+
+    /// Emit a `Logger initialized` record once the layer is added to a subscriber, holding
+    /// its resolved configuration: the crate version, the encoder and writer, the number of
+    /// additional sinks, the maximum and per-target levels, the time format and the span
+    /// records emitted.
+    ///
+    /// It tells a misconfigured deployment apart from a quiet one.
+    pub fn with_init_record(mut self, init_record: bool) -> Self {
+        self.init_record = init_record;
+        self
+    }
+
+    /// Try writing a record up to `retries` more times when it fails, waiting `backoff`
+    /// before the first retry and twice as long before each of the following ones, at most
+    /// 10 ms each time.
+    ///
+    /// Only writes that failed before any of the record went out are retried: writing the
+    /// rest of a partly written record again would corrupt the output. The logging thread
+    /// is blocked while it waits.
+    pub fn with_write_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.write_retries = retries;
+        self.write_backoff = backoff;
+        self
+    }
+
+    /// Write the records that couldn't be written to their destination (once the retries
+    /// are exhausted) to `make_writer` instead, so that they still surface somewhere.
+    ///
+    /// Keep it to the most severe records with `MakeWriterExt`:
+    ///
+    /// ```rust
+    /// use tracing::Level;
+    /// use tracing_json_formatter::JsonFormattingLayer;
+    /// use tracing_subscriber::fmt::writer::MakeWriterExt;
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_fallback_writer(std::io::stderr.with_max_level(Level::ERROR));
+    /// ```
+    pub fn with_fallback_writer<M>(mut self, make_writer: M) -> Self
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        self.fallback = Some(Arc::new(BoxMakeWriter::new(make_writer)));
+        self
+    }
+
+    /// Keep encoded records under `max_size` bytes by truncating their longest string
+    /// values; truncated records carry a `truncated: true` field.
+    ///
+    /// Records that are still too large once strings have been shortened (e.g. because of
+    /// a high number of fields) are emitted anyway.
+    pub fn with_max_record_size(mut self, max_size: usize) -> Self {
+        self.max_record_size = Some(max_size);
+        self
+    }
+
+    /// Truncate the message and string field values longer than `max_length` characters,
+    /// marking the cut with an ellipsis.
+    pub fn with_max_field_length(mut self, max_length: usize) -> Self {
+        self.max_field_length = Some(max_length);
+        self
+    }
+
+    /// Group the `file` and `line` fields into a nested `src` object, following the Bunyan
+    /// convention: `"src": {"file": "src/main.rs", "line": 12}`.
+    ///
+    /// Span records also carry the span name as `func`, which is the name of the function
+    /// for spans created by `#[instrument]`.
+    pub fn with_nested_src(mut self, nested_src: bool) -> Self {
+        self.nested_src = nested_src;
+        self
+    }
+
+    /// Replace the `host` field, read from the system by default; `None` leaves it out.
+    ///
+    /// Targets without a hostname, such as `wasm32`, have no `host` field by default.
+    pub fn with_hostname(mut self, hostname: Option<&str>) -> Self {
+        self.hostname = hostname.map(str::to_owned);
+        self.hostname_resolver = None;
+        self
+    }
+
+    /// Read the `host` field from `resolver` rather than once from the system, calling it
+    /// again every `refresh` interval, or never with `None`: in containers that get renamed
+    /// or with DHCP hostnames, the name of the machine can change while the process runs.
+    ///
+    /// Records have no `host` field while the resolver returns `None`.
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    /// use tracing_json_formatter::JsonFormattingLayer;
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_hostname_resolver(
+    ///         || std::fs::read_to_string("/etc/hostname").ok().map(|h| h.trim().to_owned()),
+    ///         Some(Duration::from_secs(60)),
+    ///     );
+    /// ```
+    pub fn with_hostname_resolver<F>(mut self, resolver: F, refresh: Option<Duration>) -> Self
+    where
+        F: Fn() -> Option<String> + Send + Sync + 'static,
+    {
+        self.hostname_resolver = Some(HostnameResolver::new(Box::new(resolver), refresh));
+        self
+    }
+
+    /// Replace the `pid` field, the ID of the process by default; `None` leaves it out.
+    ///
+    /// Targets without processes, such as `wasm32`, have no `pid` field by default.
+    pub fn with_pid(mut self, pid: Option<u32>) -> Self {
+        self.pid = pid;
+        self
+    }
+
+    /// Add a `module_path` field, the module of the callsite, which differs from `target`
+    /// when the callsite overrides it (`info!(target: "audit", ...)`).
+    pub fn with_module_path(mut self, module_path: bool) -> Self {
+        self.module_path = module_path;
+        self
+    }
+
+    /// Add a `span` field to event records, the names of the spans they belong to from the
+    /// root, to query the events under a span without parsing messages, see [`SpanPath`].
+    pub fn with_span_path(mut self, span_path: SpanPath) -> Self {
+        self.span_path = span_path;
+        self
+    }
+
+    /// Add a `callsite` field to event records, the name of their callsite as given by
+    /// `tracing` (e.g. `event src/main.rs:42`), telling apart identical messages emitted
+    /// from different places.
+    pub fn with_callsite_name(mut self, callsite_name: bool) -> Self {
+        self.callsite_name = callsite_name;
+        self
+    }
+
+    /// Emit the fields of records sorted by key, after the core fields (which keep their
+    /// fixed order), so that records can be compared line by line.
+    ///
+    /// Span fields are then serialized with every record rather than spliced in from
+    /// their cached form.
+    pub fn with_sorted_fields(mut self, sorted_fields: bool) -> Self {
+        self.sorted_fields = sorted_fields;
+        self
+    }
+
+    /// Which value is kept when an event records a key that is also a field of its span,
+    /// see [`KeyCollision`].
+    pub fn with_key_collision(mut self, key_collision: KeyCollision) -> Self {
+        self.key_collision = key_collision;
+        self
+    }
+
+    /// Set the most verbose level written for the `target` module and its children, for this
+    /// layer only: an alternative to an `EnvFilter` for tuning the verbosity of a few modules.
+    ///
+    /// The most specific target wins, and targets without a level of their own follow the
+    /// maximum level of the [`ReloadHandle`].
+    ///
+    /// ```rust
+    /// use tracing::Level;
+    /// use tracing::level_filters::LevelFilter;
+    /// use tracing_json_formatter::JsonFormattingLayer;
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_target_level("sqlx", Level::WARN)
+    ///     .with_target_level("my_app::auth", Level::TRACE)
+    ///     .with_target_level("hyper", LevelFilter::OFF);
+    /// ```
+    pub fn with_target_level(
+        mut self,
+        target: impl Into<String>,
+        level: impl Into<LevelFilter>,
+    ) -> Self {
+        let target = target.into();
+        self.target_levels.retain(|(prefix, _)| *prefix != target);
+        self.target_levels.push((target, level.into()));
+        self.target_levels
+            .sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+        self
+    }
+
+    /// Filter span START and END records (and span summaries) by `level` alone, instead of
+    /// the maximum level and target levels, which then only apply to events: DEBUG events
+    /// can come with INFO spans only, or the other way around.
+    ///
+    /// ```rust
+    /// use tracing::Level;
+    /// use tracing_json_formatter::JsonFormattingLayer;
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_span_level(Level::INFO);
+    /// ```
+    pub fn with_span_level(mut self, level: impl Into<LevelFilter>) -> Self {
+        self.span_level = Some(level.into());
+        self
+    }
+
+    /// Treat the records of `target` and its submodules at level `from` as records at level
+    /// `to`, to fit noisy or overly quiet dependencies into alerting conventions.
+    ///
+    /// Remapping happens before filtering, [target levels](Self::with_target_level) and the
+    /// maximum level apply to the new level, and the most specific target wins. Writers
+    /// routing records by level (`MakeWriterExt::with_max_level`, ...) still see the level
+    /// of the callsite.
+    ///
+    /// ```rust
+    /// use tracing::Level;
+    /// use tracing_json_formatter::JsonFormattingLayer;
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_level_remap("hyper", Level::DEBUG, Level::TRACE)
+    ///     .with_level_remap("my_app::payments", Level::WARN, Level::ERROR);
+    /// ```
+    pub fn with_level_remap(mut self, target: impl Into<String>, from: Level, to: Level) -> Self {
+        let target = target.into();
+        self.level_remaps
+            .retain(|remap| remap.target != target || remap.from != from);
+        self.level_remaps.push(LevelRemap { target, from, to });
+        self.level_remaps
+            .sort_by_key(|remap| Reverse(remap.target.len()));
+        self
+    }
+
+    /// What happens to fields named like a core field (`level`, `time`, ...), which would
+    /// otherwise show up twice in records, see [`ReservedKeys`].
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::{JsonFormattingLayer, ReservedKeys};
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_reserved_keys(ReservedKeys::Rename("field."));
+    ///
+    /// // {..., "level": "INFO", ..., "field.level": "admin"}
+    /// # let _ = || {
+    /// tracing::info!(level = "admin", "Permission granted");
+    /// # };
+    /// ```
+    pub fn with_reserved_keys(mut self, reserved_keys: ReservedKeys) -> Self {
+        self.reserved_keys = reserved_keys;
+        self
+    }
+
+    /// Call `handler` with the metadata of the event or span and the key of every field
+    /// left out by [`ReservedKeys::Reject`].
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::{JsonFormattingLayer, ReservedKeys};
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_reserved_keys(ReservedKeys::Reject)
+    ///     .with_rejected_key_handler(|metadata, key| {
+    ///         eprintln!("{}: field `{}` is reserved", metadata.target(), key);
+    ///     });
+    /// ```
+    pub fn with_rejected_key_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&Metadata<'_>, &str) + Send + Sync + 'static,
+    {
+        self.rejected_key_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Prefix the span fields of event records, e.g. `ctx.user_id`, to tell them apart from
+    /// the fields recorded on the event itself.
+    ///
+    /// Span START and END records keep unprefixed keys. Prefixed keys can't collide with
+    /// event fields, [`JsonFormattingLayer::with_key_collision`] then has no effect.
+    pub fn with_span_field_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.span_field_prefix = Some(prefix.into());
+        self
+    }
+
+    /// How records are separated from one another in the output, see [`Framing`].
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// Strip or escape the control characters (newlines, tabs, escape sequences, ...) of the
+    /// message and string field values, see [`ControlCharacters`].
+    ///
+    /// JSON output always escapes them, this protects line-oriented consumers of
+    /// [`PrettyEncoder`] output or of records re-emitted by other tools from broken lines
+    /// and terminal escape sequences. Invalid UTF-8 can't reach records: strings are valid by
+    /// construction and byte fields are encoded by the storage layer.
+    pub fn with_control_characters(mut self, control_characters: ControlCharacters) -> Self {
+        self.control_characters = control_characters;
+        self
+    }
+
+    /// Run `transformer` on the values of the span and event fields named `key` before
+    /// they are serialized; returning `None` drops the field from the record.
+    ///
+    /// Several transformers can be registered for the same key, they run in registration order.
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::JsonFormattingLayer;
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_value_transformer("email", |_key, value| {
+    ///         value.as_str().map(|email| email.to_lowercase().into())
+    ///     })
+    ///     .with_value_transformer("password", |_key, _value| None);
+    /// ```
+    pub fn with_value_transformer<F>(mut self, key: impl Into<String>, transformer: F) -> Self
+    where
+        F: Fn(&str, Value) -> Option<Value> + Send + Sync + 'static,
+    {
+        self.transformers
+            .entry(key.into())
+            .or_default()
+            .push(Box::new(transformer));
+        self
+    }
+
+    /// Call `hook` for every record to derive core fields from the metadata of its callsite,
+    /// appended after the source location.
+    ///
+    /// Core fields from `level` on are serialized once per callsite: the fields must only
+    /// depend on the metadata. They aren't part of the [`Schema`] of the layer.
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::JsonFormattingLayer;
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_core_fields(|metadata, core| {
+    ///         let subsystem = metadata.target().split("::").nth(1).unwrap_or("core");
+    ///         core.insert("subsystem", subsystem);
+    ///     });
+    /// ```
+    pub fn with_core_fields<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Metadata<'_>, &mut CoreFields<'_, '_>) + Send + Sync + 'static,
+    {
+        self.core_field_hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Call `enricher` for every record, after its span and event fields have been
+    /// collected, to add computed fields with [`Record::insert`].
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::JsonFormattingLayer;
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_enricher(|_metadata, record| {
+    ///         record.insert("thread", format!("{:?}", std::thread::current().id()));
+    ///     });
+    /// ```
+    pub fn with_enricher<F>(mut self, enricher: F) -> Self
+    where
+        F: Fn(&Metadata<'_>, &mut Record<'_>) + Send + Sync + 'static,
+    {
+        self.enrichers.push(Box::new(enricher));
+        self
+    }
+
+    /// Add `service.version` and, when known, `service.revision` to every record.
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::JsonFormattingLayer;
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_build_info(env!("CARGO_PKG_VERSION"), option_env!("GIT_SHA"));
+    /// ```
+    pub fn with_build_info(mut self, version: &str, revision: Option<&str>) -> Self {
+        self.static_fields
+            .push(("service.version".to_string(), Value::from(version)));
+        if let Some(revision) = revision {
+            self.static_fields
+                .push(("service.revision".to_string(), Value::from(revision)));
+        }
+        self
+    }
+
+    /// Add `busy_ns` and `idle_ns` to span END records: the time spent inside the span
+    /// (between enters and exits) and outside of it, across all of its enters.
+    ///
+    /// Async spans are entered every time their future is polled, the split tells apart
+    /// spans doing work from spans waiting on something else.
+    pub fn with_span_timings(mut self, span_timings: bool) -> Self {
+        self.span_timings = span_timings;
+        self
+    }
+
+    /// Report the [span timings](Self::with_span_timings) in `unit`, nanoseconds by default.
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::{JsonFormattingLayer, TimingUnit};
+    ///
+    /// // `busy_ms` and `idle_ms`, next to `elapsed_milliseconds`.
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_span_timings(true)
+    ///     .with_span_timing_unit(TimingUnit::Milliseconds);
+    /// ```
+    pub fn with_span_timing_unit(mut self, unit: TimingUnit) -> Self {
+        self.timing_unit = unit;
+        self
+    }
+
+    /// Emit each root span as a single record, for ingestion systems bundling logs per
+    /// request: the records of the events and child spans inside a root span are held
+    /// until it closes, then emitted in an `events` array of its END record, as objects with
+    /// sorted keys, without the `name`, `host` and `pid` fields they share with it. They are
+    /// always encoded as JSON, whatever the encoder. Root span START records aren't
+    /// emitted, and END records are, even with [`SpanEvents::None`].
+    ///
+    /// Root spans whose level is filtered out when they open are left out of transactions.
+    /// Once a root span holds records, its END record is emitted even if it's quiet
+    /// (`log_span = false`) or if its level was filtered out since it opened.
+    ///
+    /// Records are held in memory for as long as the root span is open: keep it to bounded
+    /// units of work, such as a request. Events outside of any span are emitted as usual.
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::JsonFormattingLayer;
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout).with_transactions(true);
+    ///
+    /// // {..., "message": "[REQUEST - END]", ..., "events": [{..., "message": "[REQUEST - EVENT] Routing", ...}]}
+    /// tracing::info_span!("request").in_scope(|| tracing::info!("Routing"));
+    /// ```
+    pub fn with_transactions(mut self, transactions: bool) -> Self {
+        self.transactions = transactions;
+        self
+    }
+
+    /// Add `proc_start`, the time the process started formatted like `time`, and
+    /// `uptime_ms`, the milliseconds elapsed since, to every record: how long the process
+    /// had been running shows from any record, e.g. the last one before a crash.
+    ///
+    /// On Linux, the start is read from `/proc`, to within 10 ms. Elsewhere, it's the first
+    /// time a layer is configured with it, early in `main`.
+    pub fn with_uptime(mut self, uptime: bool) -> Self {
+        if uptime {
+            process::start();
+        }
+        self.uptime = uptime;
+        self
+    }
+
+    /// Add `started_at` to span END records: the time the span was created, formatted like
+    /// `time`, so that timelines can be rebuilt from END records alone.
+    pub fn with_started_at(mut self, started_at: bool) -> Self {
+        self.started_at = started_at;
+        self
+    }
+
+    /// Add a `final_fields` object to span END records, holding the values recorded with
+    /// `Span::record` over the lifetime of the span.
+    ///
+    /// Span fields are still emitted at the top level as well, `final_fields` tells apart
+    /// the values known at creation time from those recorded afterwards.
+    pub fn with_final_fields(mut self, final_fields: bool) -> Self {
+        self.final_fields = final_fields;
+        self
+    }
+
+    /// Attach the AWS Lambda invocation context to every record, see [`LambdaContext`].
+    pub fn with_lambda_context(self) -> Self {
+        let context = LambdaContext::from_env();
+        self.with_enricher(move |metadata, record| context.enrich(metadata, record))
+    }
+
+    /// How the `time` field is formatted, see [`TimeFormat`].
+    pub fn with_time_format(mut self, time_format: TimeFormat) -> Self {
+        self.time_format = time_format;
+        self
+    }
+
+    /// How the `level` field is serialized, see [`LevelFormat`].
+    ///
+    /// ```rust
+    /// use tracing::Level;
+    /// use tracing_json_formatter::{JsonFormattingLayer, LevelFormat};
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_level_format(LevelFormat::Custom(|level| match *level {
+    ///         Level::WARN => "warning",
+    ///         Level::ERROR => "critical",
+    ///         _ => "info",
+    ///     }));
+    /// ```
+    pub fn with_level_format(mut self, level_format: LevelFormat) -> Self {
+        self.level_format = level_format;
+        self
+    }
+
+    /// How the span context is rendered into the `message` field, see [`MessageFormat`].
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::{JsonFormattingLayer, MessageFormat};
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_message_format(MessageFormat::Custom(Box::new(|parts| {
+    ///         match (parts.span_name, parts.message) {
+    ///             (Some(span), Some(message)) => format!("{}: {}", span, message),
+    ///             (Some(span), None) => format!("{} {}", span, parts.ty),
+    ///             (None, message) => message.unwrap_or_default().to_owned(),
+    ///         }
+    ///     })));
+    /// ```
+    pub fn with_message_format(mut self, message_format: MessageFormat) -> Self {
+        self.message_format = message_format;
+        self
+    }
+
+    /// What the `message` of events recorded without one is, their target by default.
+    pub fn with_missing_message(mut self, missing_message: MissingMessage) -> Self {
+        self.missing_message = missing_message;
+        self
+    }
+
+    /// Add a `msg_raw` field to events, their message as recorded: without the span
+    /// context of [`MessageFormat::SpanContext`], or the truncation and sanitization of
+    /// [`JsonFormattingLayer::with_max_field_length`] and
+    /// [`JsonFormattingLayer::with_control_characters`].
+    pub fn with_raw_message(mut self, raw_message: bool) -> Self {
+        self.raw_message = raw_message;
+        self
+    }
+
+    /// Split multi-line messages, such as those carrying a stack trace: the first line
+    /// stays in `message`, the following ones go to the `field` field, e.g. `stack` or
+    /// `details`.
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::JsonFormattingLayer;
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_message_details("stack");
+    /// ```
+    pub fn with_message_details(mut self, field: impl Into<String>) -> Self {
+        self.message_details = Some(field.into());
+        self
+    }
+
+    /// Write the string values of the `field` field as the JSON they hold, e.g. a request
+    /// body that's already serialized, instead of escaping them as a string.
+    ///
+    /// Values are copied verbatim when they're valid JSON on a single line, re-serialized
+    /// when they span several lines, and written as strings when they aren't valid JSON.
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::JsonFormattingLayer;
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_raw_json_field("body");
+    ///
+    /// // {..., "body": {"id": 42}}
+    /// # let _ = || {
+    /// tracing::info!(body = r#"{"id": 42}"#, "Request received");
+    /// # };
+    /// ```
+    pub fn with_raw_json_field(mut self, field: impl Into<String>) -> Self {
+        self.raw_json_fields.push(field.into());
+        self
+    }
+
+    /// Capture a backtrace when an event of `level` or more severe is emitted, attached
+    /// as a `stack` array of frames (`function at file:line:column`), innermost first.
+    ///
+    /// Capturing is slow: keep it to levels that are rarely emitted, typically `ERROR`.
+    /// Frames of `tracing` and of this crate are left out.
+    pub fn with_backtraces(mut self, level: Level) -> Self {
+        self.backtrace_level = Some(level);
+        self
+    }
+
+    /// Replace the `START`, `END` and `EVENT` labels of the kinds of records.
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::{JsonFormattingLayer, LifecycleLabels};
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+    ///     .with_lifecycle_labels(LifecycleLabels {
+    ///         start: "span.start",
+    ///         end: "span.end",
+    ///         event: "event",
+    ///     })
+    ///     .with_lifecycle_field(true);
+    /// ```
+    pub fn with_lifecycle_labels(mut self, labels: LifecycleLabels) -> Self {
+        self.lifecycle_labels = labels;
+        self
+    }
+
+    /// Emit the kind of record as a dedicated `lifecycle` field instead of including it in
+    /// the message, which becomes e.g. `[HANDLE] Done`.
+    pub fn with_lifecycle_field(mut self, lifecycle_field: bool) -> Self {
+        self.lifecycle_field = lifecycle_field;
+        self
+    }
+
+    /// Which span lifecycle records are emitted, see [`SpanEvents`].
+    pub fn with_span_events(mut self, span_events: SpanEvents) -> Self {
+        self.span_events = span_events;
+        self
+    }
+
+    /// Make the output reproducible, for snapshot and golden-file tests: the time, pid and
+    /// host of records are fixed by `snapshot`, the time is written in UTC (independently
+    /// from the time zone of the machine), the fields are sorted and span durations (and the
+    /// uptime, with [`with_uptime`](Self::with_uptime)) are written as 0.
+    pub fn with_snapshot(mut self, snapshot: Snapshot) -> Self {
+        self.clock = Box::new(FixedClock(snapshot.time));
+        self.time_format = TimeFormat::Utc;
+        self.pid = Some(snapshot.pid);
+        self.hostname = Some(snapshot.hostname);
+        self.hostname_resolver = None;
+        self.sorted_fields = true;
+        self.snapshot = true;
+        self.with_value_transformer(fields::ELAPSED_MILLISECONDS, |_, _| Some(Value::from(0)))
+    }
+
+    /// Read the `time` of records from `clock` instead of the system time, see [`Clock`].
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Attach a unique `record_id` to every record, so that records delivered several
+    /// times by an at-least-once pipeline can be deduplicated downstream.
+    #[cfg(feature = "record-id")]
+    pub fn with_record_id(self, format: RecordIdFormat) -> Self {
+        self.with_enricher(move |_metadata, record| record.insert("record_id", format.generate()))
+    }
+
+    fn message(&self, span_name: Option<&str>, ty: &Type, message: Option<&str>) -> String {
+        self.message_format.format(&MessageParts {
+            span_name,
+            ty,
+            label: (!self.lifecycle_field).then(|| self.lifecycle_labels.label(ty)),
+            message,
+        })
+    }
+
+    fn record<'a>(
+        &'a self,
+        callsite: &'a CallsiteFields,
+        ty: Type,
+        time: Timestamp,
+        message: String,
+    ) -> Record<'a> {
+        let metadata = callsite.metadata;
+        let (message, details) = match &self.message_details {
+            Some(_) => split_first_line(message),
+            None => (message, None),
+        };
+        let message = match self.max_field_length {
+            Some(max_length) => cap_length(&message, max_length).unwrap_or(message),
+            None => message,
+        };
+        let message = self
+            .control_characters
+            .sanitize(&message)
+            .unwrap_or(message);
+        // Room for every core field, optional ones included.
+        let mut core: Vec<Entry<'a>> = Vec::with_capacity(11);
+        core.push((fields::TIME.into(), self.time_format.format(time).into()));
+        core.push((fields::NAME.into(), self.name.as_str().into()));
+        if let Some(resolver) = &self.hostname_resolver {
+            if let Some(hostname) = resolver.hostname() {
+                core.push((fields::HOST.into(), Value::from(hostname).into()));
+            }
+        } else if let Some(hostname) = &self.hostname {
+            core.push((fields::HOST.into(), hostname.as_str().into()));
+        }
+        core.push((fields::MESSAGE.into(), Value::from(message.as_str()).into()));
+        // From `level` to the source location, fields only depend on the callsite.
+        let callsite_start = core.len();
+        core.extend(
+            callsite
+                .entries
+                .iter()
+                .map(|(key, value)| (Cow::Borrowed(key.as_ref()), value.borrowed())),
+        );
+        let callsite_range = callsite_start..core.len();
+        if self.lifecycle_field {
+            core.push((
+                fields::LIFECYCLE.into(),
+                self.lifecycle_labels.label(&ty).into(),
+            ));
+        }
+        let mut record = Record::new(ty, metadata, time, message, core);
+        if !self.level_remaps.is_empty() {
+            record.set_level(self.level(metadata));
+        }
+        if let Some(serialized) = &callsite.serialized {
+            record.set_serialized_callsite_fields(callsite_range, serialized.clone());
+        }
+        if let (Some(field), Some(details)) = (&self.message_details, details) {
+            record.push(field.as_str(), Value::from(details));
+        }
+        for (key, value) in &self.static_fields {
+            record.push(key.as_str(), value);
+        }
+        if self.uptime {
+            let (start, uptime) = match self.snapshot {
+                true => (time, Duration::ZERO),
+                false => {
+                    let (start, instant) = process::start();
+                    (start, instant.elapsed())
+                }
+            };
+            record.push(fields::PROC_START, self.time_format.format(start));
+            record.push(fields::UPTIME_MS, Value::from(uptime.as_millis() as u64));
+        }
+        log_context::with_fields(|fields| {
+            for (key, value) in fields {
+                record.push(*key, value.clone());
+            }
+        });
+        record
+    }
+
+    fn serialize_span<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
+        &self,
+        span: &SpanRef<S>,
+        ty: Type,
+        time: Timestamp,
+    ) {
+        let root = self.transactions.then(|| work_root(span)).flatten();
+        let is_root = root.as_ref().is_some_and(|root| root.id() == span.id());
+        let transaction = root
+            .as_ref()
+            .and_then(|root| root.extensions().get::<Transaction>().cloned());
+        if let (Some(_), true, Type::EnterSpan) = (&transaction, is_root, &ty) {
+            return;
+        }
+        // A quiet root span still carries the records it holds, in its END record.
+        let bundles_records =
+            is_root && transaction.as_ref().is_some_and(Transaction::holds_records);
+        if span.extensions().get::<QuietSpan>().is_some() && !bundles_records {
+            return;
+        }
+        let message = self.message(Some(span.metadata().name()), &ty, None);
+        let callsite = self.callsite(span.metadata());
+        let mut record = self.record(&callsite, ty, time, message);
+        record.set_spans(span_names(span));
+
+        self.cache_span_fields(span);
+        let extensions = span.extensions();
+        self.push_span_fields(&mut record, &extensions, None);
+        if let (Type::ExitSpan, true) = (record.ty(), self.span_timings) {
+            if let Some(timings) = extensions.get::<Timings>() {
+                let (busy, idle) = (self.duration(timings.busy), self.duration(timings.idle));
+                match self.timing_unit {
+                    TimingUnit::Nanoseconds => {
+                        record.insert(fields::BUSY_NS, duration_nanos(busy));
+                        record.insert(fields::IDLE_NS, duration_nanos(idle));
+                    }
+                    TimingUnit::Milliseconds => {
+                        record.insert(fields::BUSY_MS, duration_millis(busy));
+                        record.insert(fields::IDLE_MS, duration_millis(idle));
+                    }
+                }
+            }
+        }
+        if let (Type::ExitSpan, true) = (record.ty(), self.started_at) {
+            if let Some(StartedAt(started_at)) = extensions.get::<StartedAt>() {
+                record.insert(fields::STARTED_AT, self.time_format.format(*started_at));
+            }
+        }
+        if let (Type::ExitSpan, true) = (record.ty(), self.final_fields) {
+            let final_fields = extensions
+                .get::<RecordedFields>()
+                .map(|RecordedFields(recorded)| {
+                    recorded
+                        .values()
+                        .iter()
+                        .map(|(key, value)| (key.to_string(), value.clone()))
+                        .collect()
+                })
+                .unwrap_or_default();
+            record.insert(fields::FINAL_FIELDS, Value::Object(final_fields));
+        }
+        match transaction {
+            Some(transaction) if is_root => {
+                if let Some(repeated) = self
+                    .deduplicator
+                    .as_ref()
+                    .and_then(|deduplicator| deduplicator.take_pending_in(&transaction))
+                {
+                    self.emit_repeated(repeated);
+                }
+                record.insert(fields::EVENTS, Value::Array(transaction.take()));
+                self.emit(record);
+            }
+            transaction => self.emit_in(record, transaction.as_ref()),
+        }
+    }
+
+    /// Add a span or event field to the record, applying the configured value processing.
+    fn push_field<'a>(&self, record: &mut Record<'a>, key: &'a str, value: &'a Value) {
+        self.push_field_as(record, key.into(), key, value);
+    }
+
+    /// Add a field to the record under `name`, processing it as the field `key`.
+    fn push_field_as<'a>(
+        &self,
+        record: &mut Record<'a>,
+        name: Cow<'a, str>,
+        key: &str,
+        value: &'a Value,
+    ) {
+        let reserved = self.reserved_keys != ReservedKeys::Permissive
+            && record.core_fields().iter().any(|(core, _)| *core == name);
+        let name = match (reserved, self.reserved_keys) {
+            (true, ReservedKeys::Rename(prefix)) => Cow::Owned(format!("{}{}", prefix, name)),
+            (true, _) => {
+                if let Some(handler) = &self.rejected_key_handler {
+                    handler(record.metadata(), &name);
+                }
+                return;
+            }
+            (false, _) => name,
+        };
+        let mut value = Cow::Borrowed(value);
+        if let Some(transformers) = self.transformers.get(key) {
+            for transformer in transformers {
+                match transformer(key, value.into_owned()) {
+                    Some(transformed) => value = Cow::Owned(transformed),
+                    None => return,
+                }
+            }
+        }
+        if self.raw_json_fields.iter().any(|field| field == key) {
+            if let Some(raw) = raw_json(&value) {
+                record.push(name, raw);
+                return;
+            }
+        }
+        if let (Some(max_length), Value::String(s)) = (self.max_field_length, value.as_ref()) {
+            if let Some(capped) = cap_length(s, max_length) {
+                value = Cow::Owned(Value::String(capped));
+            }
+        }
+        if let Value::String(s) = value.as_ref() {
+            if let Some(sanitized) = self.control_characters.sanitize(s) {
+                value = Cow::Owned(Value::String(sanitized));
+            }
+        }
+        record.push(name, value);
+    }
+
+    /// Push the core fields that only depend on the callsite, from `level` to the source
+    /// location.
+    fn push_callsite_fields(
+        &self,
+        metadata: &'static Metadata<'static>,
+        core: &mut Vec<Entry<'static>>,
+    ) {
+        core.push((
+            fields::LEVEL.into(),
+            self.level_format.format(&self.level(metadata)),
+        ));
+        if let Some(pid) = self.pid {
+            core.push((fields::PID.into(), Value::from(pid).into()));
+        }
+        core.push((fields::TARGET.into(), metadata.target().into()));
+        if self.module_path {
+            core.push((
+                fields::MODULE_PATH.into(),
+                metadata
+                    .module_path()
+                    .map_or(Value::Null.into(), FieldValue::Str),
+            ));
+        }
+        if self.callsite_name && metadata.is_event() {
+            core.push((fields::CALLSITE.into(), metadata.name().into()));
+        }
+        if self.nested_src {
+            let mut src = serde_json::Map::new();
+            src.insert(fields::FILE.to_owned(), Value::from(metadata.file()));
+            src.insert(fields::LINE.to_owned(), Value::from(metadata.line()));
+            if metadata.is_span() {
+                src.insert("func".to_owned(), Value::from(metadata.name()));
+            }
+            core.push((fields::SRC.into(), Value::Object(src).into()));
+        } else {
+            core.push((fields::LINE.into(), Value::from(metadata.line()).into()));
+            core.push((
+                fields::FILE.into(),
+                metadata.file().map_or(Value::Null.into(), FieldValue::Str),
+            ));
+        }
+        let mut core = CoreFields::new(core);
+        for hook in &self.core_field_hooks {
+            hook(metadata, &mut core);
+        }
+    }
+
+    /// The core fields of the callsite of `metadata`, built the first time.
+    fn callsite(&self, metadata: &'static Metadata<'static>) -> Arc<CallsiteFields> {
+        let callsite = metadata.callsite();
+        if let Some(fields) = self
+            .callsites
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&callsite)
+        {
+            return fields.clone();
+        }
+        let mut entries = Vec::new();
+        self.push_callsite_fields(metadata, &mut entries);
+        let fields = Arc::new(CallsiteFields {
+            metadata,
+            serialized: serialize_entries(&entries).map(Into::into),
+            entries,
+        });
+        self.callsites
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(callsite)
+            .or_insert(fields)
+            .clone()
+    }
+
+    /// `duration`, or zero in [snapshot mode](Self::with_snapshot).
+    fn duration(&self, duration: Duration) -> Duration {
+        if self.snapshot {
+            Duration::ZERO
+        } else {
+            duration
+        }
+    }
+
+    /// Whether span fields go through the record untouched, in which case their
+    /// serialized form can be cached and reused.
+    fn splices_span_fields(&self) -> bool {
+        self.transformers.is_empty()
+            && self.raw_json_fields.is_empty()
+            && self.reserved_keys == ReservedKeys::Permissive
+            && self.max_field_length.is_none()
+            && self.control_characters == ControlCharacters::Keep
+    }
+
+    /// Serialize the span fields once, until they change, for [`JsonEncoder`] to splice them
+    /// into every record of the span.
+    fn cache_span_fields<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
+        &self,
+        span: &SpanRef<S>,
+    ) {
+        if !self.splices_span_fields() {
+            return;
+        }
+        let mut extensions = span.extensions_mut();
+        if extensions.get_mut::<SerializedSpanFields>().is_some() {
+            return;
+        }
+        let serialized = extensions
+            .get_mut::<JsonStorage>()
+            .and_then(|visitor| SerializedSpanFields::new(visitor).ok());
+        if let Some(serialized) = serialized {
+            extensions.insert(serialized);
+        }
+    }
+
+    /// Add the span fields to the record, resolving collisions with the keys of
+    /// `event_fields` as configured.
+    fn push_span_fields<'a>(
+        &self,
+        record: &mut Record<'a>,
+        extensions: &'a Extensions<'_>,
+        event_fields: Option<&JsonStorage<'_>>,
+    ) {
+        let Some(visitor) = extensions.get::<JsonStorage>() else {
+            return;
+        };
+        let start = record.entries().len();
+        if let (Some(prefix), Some(_)) = (&self.span_field_prefix, event_fields) {
+            for (key, value) in visitor.values() {
+                let name = format!("{}{}", prefix, key);
+                self.push_field_as(record, name.into(), key, value);
+            }
+            return;
+        }
+        let mut altered = false;
+        for (key, value) in visitor.values() {
+            let collides = event_fields
+                .is_some_and(|event| *key != "message" && event.values().contains_key(key));
+            match (collides, self.key_collision) {
+                (false, _) | (true, KeyCollision::SpanWins) => self.push_field(record, key, value),
+                (true, KeyCollision::EventWins) => altered = true,
+                (true, KeyCollision::PrefixSpan(prefix)) => {
+                    altered = true;
+                    let name = format!("{}{}", prefix, key);
+                    self.push_field_as(record, name.into(), key, value);
+                }
+            }
+        }
+        if self.splices_span_fields() && !altered {
+            if let Some(serialized) = extensions.get::<SerializedSpanFields>() {
+                record.set_serialized_span_fields(start, serialized.as_bytes());
+            }
+        }
+    }
+
+    /// Replace span END records with periodic summaries per span name, see
+    /// [`SpanSummaries`].
+    pub fn with_span_summaries(mut self, span_summaries: SpanSummaries) -> Self {
+        self.span_summaries = Some(span_summaries);
+        self
+    }
+
+    /// Emit the span summaries of the last interval, if it has elapsed.
+    fn emit_span_summaries(&self, span_summaries: &SpanSummaries) {
+        let Some(summaries) = span_summaries.take_summaries() else {
+            return;
+        };
+        for summary in summaries {
+            let message = format!("{} spans closed", summary.count);
+            let callsite = self.callsite(SPAN_SUMMARY_METADATA);
+            let mut record = self.record(&callsite, Type::Event, self.clock.now(), message);
+            record.push("span", summary.name);
+            record.push("count", Value::from(summary.count));
+            record.push("p50_ms", Value::from(summary.p50_ms));
+            record.push("p95_ms", Value::from(summary.p95_ms));
+            record.push("error_rate", Value::from(summary.error_rate));
+            self.emit(record);
+        }
+    }
+
+    /// Emit the last of a run of suppressed duplicate events, with their count.
+    fn emit_repeated(&self, repeated: Repeated) {
+        let callsite = self.callsite(repeated.metadata);
+        let mut record = self.record(&callsite, Type::Event, self.clock.now(), repeated.message);
+        for (key, value) in repeated.fields {
+            record.push(key, value);
+        }
+        record.push(fields::REPEAT_COUNT, Value::from(repeated.count));
+        self.emit_in(record, repeated.transaction.as_ref());
+    }
+
+    /// Emit the count of the repeats of the last event, if it's still waiting for a
+    /// different event.
+    fn emit_pending_repeat(&self) {
+        if let Some(repeated) = self
+            .deduplicator
+            .as_ref()
+            .and_then(Deduplicator::take_pending)
+        {
+            self.emit_repeated(repeated);
+        }
+    }
+
+    /// Record a failure of the root span of `span` if it closes with an `error` field set to
+    /// `true`, and emit the held events once the root span closes after a failure, see
+    /// [`JsonFormattingLayer::with_tail_sampling`].
+    fn close_tail<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
+        &self,
+        span: &SpanRef<S>,
+    ) {
+        let Some(root) = work_root(span) else {
+            return;
+        };
+        let Some(tail) = root.extensions().get::<TailBuffer>().cloned() else {
+            return;
+        };
+        let errored = span
+            .extensions()
+            .get::<JsonStorage>()
+            .and_then(|storage| storage.get("error"))
+            == Some(&Value::Bool(true));
+        if errored {
+            tail.fail();
+        }
+        if root.id() != span.id() {
+            return;
+        }
+        let Some(held) = tail.take_if_failed() else {
+            return;
+        };
+        let transaction = root.extensions().get::<Transaction>().cloned();
+        for event in held {
+            let callsite = self.callsite(event.metadata);
+            let mut record = self.record(&callsite, Type::Event, event.time, event.message);
+            if event.omit_message {
+                record.remove_message();
+            }
+            if event.audited {
+                record.set_audit();
+            }
+            record.set_spans(event.spans);
+            for (key, value) in event.fields {
+                record.push(key, value);
+            }
+            self.emit_in(record, transaction.as_ref());
+        }
+    }
+
+    /// Emit the record describing the configuration of the layer, see
+    /// [`JsonFormattingLayer::with_init_record`].
+    fn emit_init_record(&self) {
+        let level = |level: LevelFilter| Value::from(level.to_string());
+        let max_level = self.reload.read().max_level;
+        let target_levels = self
+            .target_levels
+            .iter()
+            .map(|(target, filter)| (target.clone(), level(*filter)))
+            .collect();
+        let message = "Logger initialized".to_owned();
+        let callsite = self.callsite(INIT_METADATA);
+        let mut record = self.record(&callsite, Type::Event, self.clock.now(), message);
+        record.push("version", env!("CARGO_PKG_VERSION"));
+        record.push("encoder", Value::from(short_type_name(self.encoder_name)));
+        record.push("writer", Value::from(short_type_name(type_name::<W>())));
+        record.push("sinks", Value::from(self.sinks.len()));
+        record.push("max_level", max_level.map_or(Value::Null, level));
+        record.push("target_levels", Value::Object(target_levels));
+        record.push(
+            "time_format",
+            Value::from(format!("{:?}", self.time_format)),
+        );
+        record.push(
+            "span_events",
+            Value::from(format!("{:?}", self.span_events)),
+        );
+        self.emit(record);
+    }
+
+    fn emit_sampling_summary(&self, summary: Vec<(&str, u64)>) {
+        let total: u64 = summary.iter().map(|(_, suppressed)| suppressed).sum();
+        let message = format!("Suppressed {} events", total);
+        let callsite = self.callsite(SUMMARY_METADATA);
+        let mut record = self.record(&callsite, Type::Event, self.clock.now(), message);
+        let suppressed = summary
+            .into_iter()
+            .map(|(target, suppressed)| (target.to_owned(), Value::from(suppressed)))
+            .collect();
+        record.push("suppressed", Value::Object(suppressed));
+        self.emit(record);
+    }
+
+    /// Counters of emitted and lost records, shared with the layer.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Count into `metrics` rather than counters of its own, to share them with a background
+    /// writer and observe the whole pipeline at once:
+    ///
+    /// ```rust
+    /// use std::sync::Arc;
+    /// use tracing_json_formatter::{JsonFormattingLayer, Metrics};
+    ///
+    /// let metrics = Arc::new(Metrics::default());
+    /// let layer = JsonFormattingLayer::new("app".into(), std::io::stdout).with_metrics(metrics.clone());
+    /// ```
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// The core fields of the records written to the main writer with the current
+    /// configuration, as named by its encoder.
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::{fields, EcsEncoder, JsonFormattingLayer};
+    ///
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout);
+    /// assert_eq!(layer.schema().fields()[0].name, fields::TIME);
+    ///
+    /// let layer = layer.with_encoder(EcsEncoder);
+    /// assert!(layer.schema().field("log.level").is_some());
+    /// ```
+    pub fn schema(&self) -> Schema {
+        let time = match self.time_format {
+            TimeFormat::Local | TimeFormat::Utc | TimeFormat::UtcNanos => {
+                SchemaField::new(fields::TIME, FieldKind::String)
+            }
+            TimeFormat::Epoch => SchemaField::new(fields::TIME, FieldKind::Number),
+            TimeFormat::EpochNanos => SchemaField::new(fields::TIME, FieldKind::Number).nullable(),
+        };
+        let mut core = vec![time, SchemaField::new(fields::NAME, FieldKind::String)];
+        if self.hostname_resolver.is_some() {
+            core.push(SchemaField::new(fields::HOST, FieldKind::String).sometimes_present());
+        } else if self.hostname.is_some() {
+            core.push(SchemaField::new(fields::HOST, FieldKind::String));
+        }
+        let message = SchemaField::new(fields::MESSAGE, FieldKind::String);
+        core.push(match self.missing_message {
+            MissingMessage::Target => message,
+            MissingMessage::Omit => message.sometimes_present(),
+        });
+        core.push(match self.level_format {
+            LevelFormat::Bunyan => SchemaField::new(fields::LEVEL, FieldKind::Number),
+            _ => SchemaField::new(fields::LEVEL, FieldKind::String),
+        });
+        if self.pid.is_some() {
+            core.push(SchemaField::new(fields::PID, FieldKind::Number));
+        }
+        core.push(SchemaField::new(fields::TARGET, FieldKind::String));
+        if self.module_path {
+            core.push(SchemaField::new(fields::MODULE_PATH, FieldKind::String).nullable());
+        }
+        if self.callsite_name {
+            core.push(SchemaField::new(fields::CALLSITE, FieldKind::String).sometimes_present());
+        }
+        if self.nested_src {
+            core.push(SchemaField::new(fields::SRC, FieldKind::Object));
+        } else {
+            core.push(SchemaField::new(fields::LINE, FieldKind::Number).nullable());
+            core.push(SchemaField::new(fields::FILE, FieldKind::String).nullable());
+        }
+        if self.lifecycle_field {
+            core.push(SchemaField::new(fields::LIFECYCLE, FieldKind::String));
+        }
+        let reloadable = self.reload.read();
+        reloadable
+            .encoder
+            .as_deref()
+            .unwrap_or(self.encoder.as_ref())
+            .schema(Schema::new(core))
+    }
+
+    /// Get a handle changing the encoder, the maximum level or the default fields of the
+    /// layer once installed, see [`ReloadHandle`].
+    pub fn reload_handle(&self) -> ReloadHandle {
+        self.reload.clone()
+    }
+
+    /// Whether records of this level are enabled by the level of their target, or else by the
+    /// maximum level of the [`ReloadHandle`].
+    fn level_enabled(&self, metadata: &Metadata<'_>) -> bool {
+        let target = metadata.target();
+        let level = self.level(metadata);
+        let target_level = self
+            .target_levels
+            .iter()
+            .find(|(prefix, _)| in_target(target, prefix));
+        if let Some((_, filter)) = target_level {
+            return level <= *filter;
+        }
+        self.reload
+            .read()
+            .max_level
+            .is_none_or(|max_level| level <= max_level)
+    }
+
+    /// Whether the START and END records of spans of `metadata` are emitted.
+    fn span_level_enabled(&self, metadata: &Metadata<'_>) -> bool {
+        match self.span_level {
+            Some(span_level) => self.level(metadata) <= span_level,
+            None => self.level_enabled(metadata),
+        }
+    }
+
+    /// The level of the records of `metadata`, once remapped.
+    fn level(&self, metadata: &Metadata<'_>) -> Level {
+        let level = *metadata.level();
+        self.level_remaps
+            .iter()
+            .find(|remap| remap.from == level && in_target(metadata.target(), &remap.target))
+            .map_or(level, |remap| remap.to)
+    }
+
+    /// Run the enrichers, then encode the record once per destination and write it,
+    /// counting failures.
+    fn emit(&self, mut record: Record<'_>) {
+        if self.shut_down.load(Ordering::Relaxed) {
+            Metrics::incr(&self.metrics.dropped);
+            return;
+        }
+        let reloadable = self.reload.read();
+        self.finish_record(&mut record, &reloadable.fields);
+        if let Some(audit) = self.audit.as_ref().filter(|_| record.is_audit()) {
+            self.write_audit_record(audit, record);
+            return;
+        }
+        self.write_record(
+            reloadable
+                .encoder
+                .as_deref()
+                .unwrap_or(self.encoder.as_ref()),
+            &mut self.make_writer.make_writer_for(record.metadata()),
+            &record,
+        );
+        for sink in &self.sinks {
+            self.write_record(
+                sink.encoder.as_ref(),
+                &mut sink.make_writer.make_writer_for(record.metadata()),
+                &record,
+            );
+        }
+    }
+
+    /// Emit `record`, or add it to the events of `transaction`, see
+    /// [`JsonFormattingLayer::with_transactions`].
+    fn emit_in(&self, mut record: Record<'_>, transaction: Option<&Transaction>) {
+        let Some(transaction) = transaction else {
+            return self.emit(record);
+        };
+        if self.shut_down.load(Ordering::Relaxed) {
+            Metrics::incr(&self.metrics.dropped);
+            return;
+        }
+        self.finish_record(&mut record, &self.reload.read().fields);
+        if let Some(audit) = self.audit.as_ref().filter(|_| record.is_audit()) {
+            self.write_audit_record(audit, record);
+            return;
+        }
+        let event = with_buffer(|buffer| {
+            JsonEncoder::new().encode(&record, buffer)?;
+            Ok::<_, std::io::Error>(serde_json::from_slice::<Value>(buffer)?)
+        });
+        match event {
+            Ok(Value::Object(mut event)) => {
+                for shared in [fields::NAME, fields::HOST, fields::PID] {
+                    event.remove(shared);
+                }
+                transaction.push(Value::Object(event));
+            }
+            _ => Metrics::incr(&self.metrics.serialization_errors),
+        }
+    }
+
+    /// Add the reloadable fields, run the enrichers and sort the fields if configured to.
+    fn finish_record(&self, record: &mut Record<'_>, reloadable_fields: &[(String, Value)]) {
+        for (key, value) in reloadable_fields {
+            record.insert(key.as_str(), value.clone());
+        }
+        for enricher in &self.enrichers {
+            enricher(record.metadata(), record);
+        }
+        if self.sorted_fields {
+            record.sort_fields();
+        }
+    }
+
+    /// Format an event outside of a subscriber, e.g. to replay stored events or to write an
+    /// audit record, and write it to `writer` rather than to the writer of the layer.
+    ///
+    /// The event is formatted from `metadata`, `time` and its `fields`, a `message` field
+    /// among them, as if it had been emitted outside of any span: with the configuration
+    /// of the layer and the encoder of its main writer. The level filters and sampling of
+    /// the layer don't apply, and the metrics don't count it.
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::{JsonFormattingLayer, Timestamp};
+    ///
+    /// # let metadata = tracing::info_span!("audit").metadata().unwrap();
+    /// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout);
+    /// let fields = [
+    ///     ("message", "Role granted".into()),
+    ///     ("user_id", 42.into()),
+    /// ];
+    /// let mut audit_log = Vec::new();
+    /// layer
+    ///     .format_event_to_writer(metadata, Timestamp::now(), &fields, &mut audit_log)
+    ///     .unwrap();
+    /// ```
+    pub fn format_event_to_writer(
+        &self,
+        metadata: &'static Metadata<'static>,
+        time: Timestamp,
+        fields: &[(&str, Value)],
+        writer: &mut impl Write,
+    ) -> std::io::Result<()> {
+        let raw_message = fields
+            .iter()
+            .find_map(|(key, value)| (*key == fields::MESSAGE).then(|| value.as_str()))
+            .flatten();
+        let message = self.message(
+            None,
+            &Type::Event,
+            Some(raw_message.unwrap_or_else(|| metadata.target())),
+        );
+        let callsite = self.callsite(metadata);
+        let mut record = self.record(&callsite, Type::Event, time, message);
+        if raw_message.is_none() && self.missing_message == MissingMessage::Omit {
+            record.remove_message();
+        }
+        for (key, value) in fields.iter().filter(|(key, _)| *key != fields::MESSAGE) {
+            self.push_field(&mut record, key, value);
+        }
+        if let (Some(raw_message), true) = (raw_message, self.raw_message) {
+            record.push(fields::MSG_RAW, raw_message);
+        }
+        let reloadable = self.reload.read();
+        self.finish_record(&mut record, &reloadable.fields);
+        let encoder = reloadable
+            .encoder
+            .as_deref()
+            .unwrap_or(self.encoder.as_ref());
+        with_buffer(|buffer| {
+            encoder.encode(&record, buffer)?;
+            if let Some(max_size) = self.max_record_size {
+                if buffer.len() > max_size {
+                    shrink(encoder, &record, max_size, buffer)?;
+                }
+            }
+            self.framing.frame(buffer);
+            writer.write_all(buffer)
+        })
+    }
+
+    /// Chain `record` to the previous audit record and write it to the audit log.
+    fn write_audit_record(&self, audit: &AuditLog, mut record: Record<'_>) {
+        let mut chain = audit.chain();
+        record.push(fields::PREV_HASH, Value::from(chain.as_str()));
+        with_buffer(|buffer| {
+            if JsonEncoder::new().encode(&record, buffer).is_err() {
+                Metrics::incr(&self.metrics.serialization_errors);
+                return;
+            }
+            let hash = sha256_hex(buffer);
+            record.push(fields::HASH, Value::from(hash.as_str()));
+            buffer.clear();
+            if JsonEncoder::new().encode(&record, buffer).is_err() {
+                Metrics::incr(&self.metrics.serialization_errors);
+                return;
+            }
+            self.framing.frame(buffer);
+            let written = self.write_buffer(
+                &mut audit.make_writer.make_writer_for(record.metadata()),
+                &record,
+                buffer,
+            );
+            // A record missing from the audit log must not be part of its chain.
+            if written {
+                *chain = hash;
+            }
+        })
+    }
+
+    fn write_record(&self, encoder: &dyn Encoder, writer: &mut impl Write, record: &Record<'_>) {
+        with_buffer(|buffer| {
+            if encoder.encode(record, buffer).is_err() {
+                Metrics::incr(&self.metrics.serialization_errors);
+                return;
+            }
+            if let Some(max_size) = self.max_record_size {
+                if buffer.len() > max_size && shrink(encoder, record, max_size, buffer).is_err() {
+                    Metrics::incr(&self.metrics.serialization_errors);
+                    return;
+                }
+            }
+            self.framing.frame(buffer);
+            self.write_buffer(writer, record, buffer);
+        })
+    }
+
+    /// Write an encoded record, retrying and falling back as configured, counting the
+    /// outcome. Whether `writer` took the record, without the fallback.
+    fn write_buffer(&self, writer: &mut impl Write, record: &Record<'_>, buffer: &[u8]) -> bool {
+        let mut sent = 0;
+        let mut retries = 0;
+        let mut backoff = self.write_backoff;
+        let written = loop {
+            if sent == buffer.len() {
+                break Ok(());
+            }
+            match writer.write(&buffer[sent..]) {
+                Ok(0) => break Err(std::io::Error::from(std::io::ErrorKind::WriteZero)),
+                Ok(n) => sent += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) if sent == 0 && retries < self.write_retries => {
+                    retries += 1;
+                    std::thread::sleep(backoff.min(MAX_WRITE_BACKOFF));
+                    backoff *= 2;
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        if written.is_ok() {
+            Metrics::incr(&self.metrics.emitted);
+            return true;
+        }
+        Metrics::incr(&self.metrics.write_errors);
+        if let Some(fallback) = &self.fallback {
+            match fallback
+                .make_writer_for(record.metadata())
+                .write_all(buffer)
+            {
+                Ok(()) => Metrics::incr(&self.metrics.emitted),
+                Err(_) => Metrics::incr(&self.metrics.write_errors),
+            }
+        }
+        false
+    }
+}
+
+impl<W: for<'a> MakeWriter<'a> + Send + Sync + 'static> JsonFormattingLayer<W> {
+    /// Get a guard flushing the destinations of the layer when dropped.
+    ///
+    /// Call it once the layer is fully configured: destinations added afterwards with
+    /// [`JsonFormattingLayer::with_sink`] are not flushed by the guard.
+    pub fn guard(&self) -> FlushGuard {
+        let shut_down = self.shut_down.clone();
+        let pending: Option<Emitter> = self.deduplicator.is_some().then(|| {
+            Box::new(move || {
+                // The guard can't borrow the layer: find it in the current subscriber.
+                tracing::dispatcher::get_default(|dispatch| {
+                    if let Some(layer) = dispatch.downcast_ref::<Self>() {
+                        if Arc::ptr_eq(&layer.shut_down, &shut_down) {
+                            layer.emit_pending_repeat();
+                        }
+                    }
+                });
+            }) as Emitter
+        });
+        FlushGuard {
+            flushers: self.flushers(),
+            pending,
+            shut_down: self.shut_down.clone(),
+        }
     }
 
-    pub fn with_default_fields(name: String, make_writer: W) -> Self {
-        Self {
-            make_writer,
-            name,
-            pid: std::process::id(),
-            hostname: gethostname::gethostname().to_string_lossy().into_owned(),
+    /// Flush every destination, returning the first error encountered.
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.emit_pending_repeat();
+        flush_all(&self.flushers())
+    }
+
+    /// Stop emitting records and flush every destination.
+    ///
+    /// Records emitted after the shutdown are discarded and counted as dropped.
+    pub fn shutdown(&self) -> std::io::Result<()> {
+        self.emit_pending_repeat();
+        self.shut_down.store(true, Ordering::SeqCst);
+        self.flush()
+    }
+
+    fn flushers(&self) -> Vec<Flusher> {
+        let make_writer = self.make_writer.clone();
+        let mut flushers: Vec<Flusher> = vec![Box::new(move || make_writer.make_writer().flush())];
+        for sink in &self.sinks {
+            let make_writer = sink.make_writer.clone();
+            flushers.push(Box::new(move || make_writer.make_writer().flush()));
+        }
+        if let Some(audit) = &self.audit {
+            let make_writer = audit.make_writer.clone();
+            flushers.push(Box::new(move || make_writer.make_writer().flush()));
         }
+        if let Some(fallback) = &self.fallback {
+            let make_writer = fallback.clone();
+            flushers.push(Box::new(move || make_writer.make_writer().flush()));
+        }
+        flushers
     }
+}
 
-    fn serialize_span<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
-        &self,
-        span: &SpanRef<S>,
-        ty: Type,
-    ) -> Result<Vec<u8>, std::io::Error> {
-        let mut buffer = Vec::new();
-        let mut serializer = serde_json::Serializer::new(&mut buffer);
-        let mut map_serializer = serializer.serialize_map(None)?;
-        let message = format_span_context(span, ty);
-        map_serializer.serialize_entry(
-            "time",
-            &Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
-        )?;
-        map_serializer.serialize_entry("name", &self.name)?;
-        map_serializer.serialize_entry("host", &self.hostname)?;
-        map_serializer.serialize_entry("message", &message)?;
-        map_serializer.serialize_entry("level", &span.metadata().level().to_string())?;
-        map_serializer.serialize_entry("pid", &self.pid)?;
-        map_serializer.serialize_entry("target", span.metadata().target())?;
-        map_serializer.serialize_entry("line", &span.metadata().line())?;
-        map_serializer.serialize_entry("file", &span.metadata().file())?;
+/// Serialized as an u64, which is more than enough for our purposes.
+fn duration_nanos(duration: Duration) -> u64 {
+    duration.as_nanos().try_into().unwrap_or(u64::MAX)
+}
 
-        let extensions = span.extensions();
-        if let Some(visitor) = extensions.get::<JsonStorage>() {
-            for (key, value) in visitor.values() {
-                map_serializer.serialize_entry(key, value)?;
+fn duration_millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// The frames of `backtrace` as `function at file:line:column` strings, starting from
+/// the caller of the logging macro.
+fn backtrace_frames(backtrace: &Backtrace) -> Value {
+    // `Backtrace::frames` isn't stable, parse the `Display` output instead: a line per
+    // frame (`  3: function`) followed by its location (`      at src/main.rs:5:9`).
+    let backtrace = backtrace.to_string();
+    let mut frames: Vec<String> = Vec::new();
+    for line in backtrace.lines() {
+        let line = line.trim();
+        if let Some(location) = line.strip_prefix("at ") {
+            if let Some(frame) = frames.last_mut() {
+                frame.push_str(" at ");
+                frame.push_str(location);
+            }
+        } else if let Some((index, function)) = line.split_once(": ") {
+            if index.chars().all(|c| c.is_ascii_digit()) {
+                frames.push(function.to_owned());
+            }
+        }
+    }
+    // Up to the logging macro, frames belong to this crate, to `tracing` or to the
+    // standard library (thread locals, closures).
+    let internal = |frame: &String| {
+        let function = frame.trim_start_matches('<');
+        [
+            concat!(env!("CARGO_CRATE_NAME"), "::"),
+            "tracing::",
+            "tracing_core::",
+            "tracing_subscriber::",
+            "std::",
+            "core::",
+            "alloc::",
+        ]
+        .iter()
+        .any(|prefix| function.starts_with(prefix))
+    };
+    let skipped = frames.iter().take_while(|frame| internal(frame)).count();
+    Value::from(frames.split_off(skipped))
+}
+
+/// The first line of a multi-line `message`, and the following ones.
+fn split_first_line(message: String) -> (String, Option<String>) {
+    match message.split_once('\n') {
+        Some((first, rest)) if !rest.trim().is_empty() => (
+            first.trim_end_matches('\r').to_owned(),
+            Some(rest.trim_end().to_owned()),
+        ),
+        _ => (message, None),
+    }
+}
+
+/// The JSON held by a string value: borrowed verbatim when it fits on a line, parsed
+/// otherwise so that records stay on a single line. `None` if it isn't valid JSON.
+fn raw_json<'a>(value: &Cow<'a, Value>) -> Option<FieldValue<'a>> {
+    if let Cow::Borrowed(Value::String(s)) = value {
+        if !s.contains(['\n', '\r']) {
+            return serde_json::from_str(s).ok().map(FieldValue::Raw);
+        }
+    }
+    serde_json::from_str::<Value>(value.as_str()?)
+        .ok()
+        .map(FieldValue::from)
+}
+
+/// `s` cut to `max_length` characters followed by an ellipsis, if it is longer than that.
+fn cap_length(s: &str, max_length: usize) -> Option<String> {
+    let (cut, _) = s.char_indices().nth(max_length)?;
+    Some(format!("{}…", &s[..cut]))
+}
+
+/// How a key recorded both on an event and on its span is resolved, so that records never
+/// carry duplicate keys.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyCollision {
+    /// Keep the value recorded on the event.
+    #[default]
+    EventWins,
+    /// Keep the value recorded on the span.
+    SpanWins,
+    /// Keep both, prefixing the key of the span field, e.g. `span.user_id`.
+    PrefixSpan(&'static str),
+}
+
+/// How fields named like a core field of records are handled, see
+/// [`JsonFormattingLayer::with_reserved_keys`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReservedKeys {
+    /// Write them as-is, next to the core field of the same name.
+    #[default]
+    Permissive,
+    /// Prefix their key, e.g. `field.level`.
+    Rename(&'static str),
+    /// Leave them out, reporting them to the
+    /// [rejected key handler](JsonFormattingLayer::with_rejected_key_handler).
+    Reject,
+}
+
+/// The `message` of events recorded without one, see
+/// [`JsonFormattingLayer::with_missing_message`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingMessage {
+    /// The target of the event, decorated like other messages.
+    #[default]
+    Target,
+    /// No `message` field at all.
+    Omit,
+}
+
+/// How encoded records are delimited, see [`JsonFormattingLayer::with_framing`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Records end with `\n`, i.e. newline-delimited JSON.
+    #[default]
+    Newline,
+    /// Records end with `\r\n`.
+    Crlf,
+    /// Records end with a NUL byte, as expected by some journald and Vector pipelines.
+    Nul,
+    /// Records are preceded by their length in bytes, as a 4-byte big-endian integer.
+    LengthPrefixed,
+    /// Records are written as-is, e.g. for writers handling one record per write.
+    None,
+}
+
+impl Framing {
+    fn frame(self, buffer: &mut Vec<u8>) {
+        match self {
+            Framing::Newline => buffer.push(b'\n'),
+            Framing::Crlf => buffer.extend_from_slice(b"\r\n"),
+            Framing::Nul => buffer.push(0),
+            Framing::LengthPrefixed => {
+                let len = u32::try_from(buffer.len()).unwrap_or(u32::MAX);
+                buffer.splice(0..0, len.to_be_bytes());
+            }
+            Framing::None => {}
+        }
+    }
+}
+
+/// What to do with the control characters of user-provided strings, see
+/// [`JsonFormattingLayer::with_control_characters`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ControlCharacters {
+    /// Leave them to the encoder.
+    #[default]
+    Keep,
+    /// Remove them.
+    Strip,
+    /// Replace them with their escaped form, e.g. `\n` or `\u{1b}`, as plain characters.
+    Escape,
+}
+
+impl ControlCharacters {
+    /// The sanitized version of `s`, `None` if it doesn't need any change.
+    fn sanitize(self, s: &str) -> Option<String> {
+        if self == ControlCharacters::Keep || !s.contains(char::is_control) {
+            return None;
+        }
+        let mut sanitized = String::with_capacity(s.len());
+        for c in s.chars() {
+            match (self, c.is_control()) {
+                (_, false) => sanitized.push(c),
+                (ControlCharacters::Escape, true) => sanitized.extend(c.escape_default()),
+                _ => {}
+            }
+        }
+        Some(sanitized)
+    }
+}
+
+/// Halve the maximum length of string values until the encoded record fits.
+fn shrink(
+    encoder: &dyn Encoder,
+    record: &Record<'_>,
+    max_size: usize,
+    buffer: &mut Vec<u8>,
+) -> Result<(), std::io::Error> {
+    let mut max_len = record.longest_string();
+    while buffer.len() > max_size && max_len > 0 {
+        max_len /= 2;
+        buffer.clear();
+        encoder.encode(&record.truncate_strings(max_len), buffer)?;
+    }
+    Ok(())
+}
+
+/// How the `time` field of records is formatted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimeFormat {
+    /// Local time without offset, e.g. `2023-04-12 15:30:12`.
+    #[default]
+    Local,
+    /// RFC 3339 UTC time with milliseconds, e.g. `2023-04-12T13:30:12.345Z`.
+    Utc,
+    /// Milliseconds since the Unix epoch, as a number.
+    Epoch,
+    /// RFC 3339 UTC time with nanoseconds, e.g. `2023-04-12T13:30:12.345678901Z`.
+    UtcNanos,
+    /// Nanoseconds since the Unix epoch, as a number.
+    EpochNanos,
+}
+
+impl TimeFormat {
+    fn format(self, time: Timestamp) -> Value {
+        match self {
+            TimeFormat::Local => Value::from(timestamp::local(&time).date_time()),
+            TimeFormat::Utc => Value::from(timestamp::utc(&time).rfc3339(3)),
+            TimeFormat::Epoch => Value::from(timestamp::unix_millis(&time)),
+            TimeFormat::UtcNanos => Value::from(timestamp::utc(&time).rfc3339(9)),
+            TimeFormat::EpochNanos => Value::from(timestamp::unix_nanos(&time)),
+        }
+    }
+}
+
+/// How the `level` field of records is serialized.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum LevelFormat {
+    /// `"TRACE"`, `"DEBUG"`, `"INFO"`, `"WARN"` or `"ERROR"`.
+    #[default]
+    Uppercase,
+    /// `"trace"`, `"debug"`, `"info"`, `"warn"` or `"error"`.
+    Lowercase,
+    /// The numeric Bunyan levels: 10 for `TRACE` up to 50 for `ERROR`.
+    Bunyan,
+    /// A user-supplied mapping.
+    Custom(fn(&Level) -> &'static str),
+}
+
+impl LevelFormat {
+    fn format(self, level: &Level) -> FieldValue<'static> {
+        match self {
+            LevelFormat::Uppercase => level.as_str().into(),
+            LevelFormat::Lowercase => match *level {
+                Level::TRACE => "trace",
+                Level::DEBUG => "debug",
+                Level::INFO => "info",
+                Level::WARN => "warn",
+                Level::ERROR => "error",
             }
+            .into(),
+            LevelFormat::Bunyan => Value::from(match *level {
+                Level::TRACE => 10,
+                Level::DEBUG => 20,
+                Level::INFO => 30,
+                Level::WARN => 40,
+                Level::ERROR => 50,
+            })
+            .into(),
+            LevelFormat::Custom(map) => map(level).into(),
         }
-        map_serializer.end()?;
-        buffer.write_all(b"\n")?;
-        Ok(buffer)
     }
+}
+
+/// Which span lifecycle records are emitted, events are always emitted.
+///
+/// ```rust
+/// use tracing_json_formatter::{JsonFormattingLayer, SpanEvents};
+///
+/// // One line per span, when it closes.
+/// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+///     .with_span_events(SpanEvents::Close);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpanEvents {
+    /// Neither START nor END records.
+    None,
+    /// Only END records: a single line per span, carrying all of its fields (including
+    /// those recorded after its creation) and its duration, `elapsed_milliseconds`.
+    Close,
+    /// Both START and END records.
+    #[default]
+    Full,
+}
+
+/// The unit of the span timings, see [`JsonFormattingLayer::with_span_timing_unit`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimingUnit {
+    /// `busy_ns` and `idle_ns`.
+    #[default]
+    Nanoseconds,
+    /// `busy_ms` and `idle_ms`, truncated, in the unit of `elapsed_milliseconds`.
+    Milliseconds,
+}
+
+/// How the `span` field of event records is written, see
+/// [`JsonFormattingLayer::with_span_path`].
+///
+/// Events outside of any span have no `span` field.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpanPath {
+    /// No `span` field.
+    #[default]
+    Off,
+    /// The span names joined by `/`, e.g. `"handle_request/query"`.
+    Joined,
+    /// An array of span names, e.g. `["handle_request", "query"]`.
+    Array,
+}
+
+impl SpanPath {
+    fn format(self, spans: &[&str]) -> Option<Value> {
+        if spans.is_empty() {
+            return None;
+        }
+        match self {
+            SpanPath::Off => None,
+            SpanPath::Joined => Some(Value::from(spans.join("/"))),
+            SpanPath::Array => Some(Value::from(spans.to_vec())),
+        }
+    }
+}
+
+/// How the `message` field of records is built, see
+/// [`JsonFormattingLayer::with_message_format`].
+#[derive(Default)]
+pub enum MessageFormat {
+    /// Prefix messages with the name of the current span and the kind of record, e.g.
+    /// `[HANDLE - EVENT] Done` or `[HANDLE - START]`.
+    #[default]
+    SpanContext,
+    /// The raw message of events, the name of the span for span records.
+    Raw,
+    /// A user-supplied formatter.
+    Custom(Box<dyn Fn(&MessageParts<'_>) -> String + Send + Sync>),
+}
+
+/// What the `message` field of a record is built from.
+#[derive(Clone, Debug)]
+pub struct MessageParts<'a> {
+    /// The name of the span the record belongs to, if any.
+    pub span_name: Option<&'a str>,
+    pub ty: &'a Type,
+    /// The label of `ty`, `None` when it is emitted as a separate `lifecycle` field.
+    pub label: Option<&'a str>,
+    /// The message of the event, `None` for span records.
+    pub message: Option<&'a str>,
+}
+
+impl MessageFormat {
+    fn format(&self, parts: &MessageParts<'_>) -> String {
+        match self {
+            MessageFormat::SpanContext => {
+                let Some(span) = parts.span_name else {
+                    return parts.message.unwrap_or_default().to_owned();
+                };
+                let context = match parts.label {
+                    Some(label) => format!("[{} - {}]", span.to_uppercase(), label),
+                    None => format!("[{}]", span.to_uppercase()),
+                };
+                match parts.message {
+                    Some(message) => format!("{} {}", context, message),
+                    None => context,
+                }
+            }
+            MessageFormat::Raw => parts
+                .message
+                .or(parts.span_name)
+                .unwrap_or_default()
+                .to_owned(),
+            MessageFormat::Custom(format) => format(parts),
+        }
+    }
+}
+
+/// The labels of the kinds of records, in messages or in the `lifecycle` field.
+#[derive(Clone, Copy, Debug)]
+pub struct LifecycleLabels {
+    pub start: &'static str,
+    pub end: &'static str,
+    pub event: &'static str,
+}
+
+impl LifecycleLabels {
+    fn label(&self, ty: &Type) -> &'static str {
+        match ty {
+            Type::EnterSpan => self.start,
+            Type::ExitSpan => self.end,
+            Type::Event => self.event,
+        }
+    }
+}
 
-    fn emit(&self, buffer: &[u8]) -> Result<(), std::io::Error> {
-        self.make_writer.make_writer().write_all(buffer)
+/// `START`, `END` and `EVENT`.
+impl Default for LifecycleLabels {
+    fn default() -> Self {
+        Self {
+            start: "START",
+            end: "END",
+            event: "EVENT",
+        }
     }
 }
 
@@ -93,33 +2411,159 @@ impl fmt::Display for Type {
     }
 }
 
-fn format_span_context<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
-    span: &SpanRef<S>,
-    ty: Type,
-) -> String {
-    format!("[{} - {}]", span.metadata().name().to_uppercase(), ty)
+/// Marks a span during which an `ERROR` event was emitted, for [`SpanSummaries`].
+struct FailedSpan;
+
+/// The records emitted inside a root span, held until it closes, see
+/// [`JsonFormattingLayer::with_transactions`].
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Transaction(Arc<Mutex<Vec<Value>>>);
+
+impl Transaction {
+    fn push(&self, event: Value) {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).push(event);
+    }
+
+    fn take(&self) -> Vec<Value> {
+        std::mem::take(&mut *self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    fn holds_records(&self) -> bool {
+        !self.0.lock().unwrap_or_else(|e| e.into_inner()).is_empty()
+    }
+
+    pub(crate) fn ptr_eq(&self, other: &Transaction) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// The events held inside a root span, and whether it failed, see
+/// [`JsonFormattingLayer::with_tail_sampling`].
+#[derive(Clone, Default)]
+struct TailBuffer(Arc<Mutex<Tail>>);
+
+#[derive(Default)]
+struct Tail {
+    failed: bool,
+    held: VecDeque<HeldEvent>,
+}
+
+/// An event left out by the level filters, to be emitted if its root span fails.
+struct HeldEvent {
+    metadata: &'static Metadata<'static>,
+    time: Timestamp,
+    message: String,
+    omit_message: bool,
+    audited: bool,
+    spans: Vec<&'static str>,
+    /// The event and span fields, the fields of the layer are added again on replay.
+    fields: Vec<(String, Value)>,
+}
+
+/// The longest wait before retrying a failed write, on the logging thread.
+const MAX_WRITE_BACKOFF: Duration = Duration::from_millis(10);
+
+/// The maximum number of events held per root span, the oldest are discarded first.
+const MAX_HELD_EVENTS: usize = 1000;
+
+impl TailBuffer {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Tail> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn fail(&self) {
+        self.lock().failed = true;
+    }
+
+    fn hold(&self, event: HeldEvent) {
+        let mut tail = self.lock();
+        if tail.held.len() == MAX_HELD_EVENTS {
+            tail.held.pop_front();
+        }
+        tail.held.push_back(event);
+    }
+
+    /// The held events if the span failed, emptying the buffer either way.
+    fn take_if_failed(&self) -> Option<VecDeque<HeldEvent>> {
+        let mut tail = self.lock();
+        let held = std::mem::take(&mut tail.held);
+        tail.failed.then_some(held)
+    }
+}
+
+/// The time a span was created, stored in its extensions for
+/// [`JsonFormattingLayer::with_started_at`].
+struct StartedAt(Timestamp);
+
+/// The names of `span` and its ancestors, from the root.
+/// Whether `target` is `prefix` or one of its submodules.
+fn in_target(target: &str, prefix: &str) -> bool {
+    target
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+/// `name` without module paths, e.g. `BatchedMakeWriter<fn() -> Stdout>`.
+fn short_type_name(name: &str) -> String {
+    let mut short = String::with_capacity(name.len());
+    let mut segment_start = 0;
+    for c in name.chars() {
+        if c == ':' && short.ends_with(':') {
+            short.truncate(segment_start);
+        } else {
+            short.push(c);
+            if !(c.is_alphanumeric() || c == '_' || c == ':') {
+                segment_start = short.len();
+            }
+        }
+    }
+    short
+}
+
+fn span_names<S>(span: &SpanRef<'_, S>) -> Vec<&'static str>
+where
+    S: for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    span.scope()
+        .from_root()
+        .filter(|span| span.extensions().get::<ChildSpan>().is_none())
+        .map(|span| span.name())
+        .collect()
+}
+
+/// The outermost span of `span` delimiting work, i.e. skipping the spans binding fields
+/// around it: the root span of transactions and tail sampling.
+fn work_root<'a, S>(span: &SpanRef<'a, S>) -> Option<SpanRef<'a, S>>
+where
+    S: for<'l> tracing_subscriber::registry::LookupSpan<'l>,
+{
+    span.scope()
+        .from_root()
+        .find(|span| !is_binding_span(span.metadata()))
+}
+
+/// The name of `span`, or of its closest ancestor when it's a [`child_span`](crate::child_span).
+fn decorating_span_name<S>(span: &SpanRef<'_, S>) -> Option<&'static str>
+where
+    S: for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    span.scope()
+        .find(|span| span.extensions().get::<ChildSpan>().is_none())
+        .map(|span| span.name())
 }
 
-fn format_event_message<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
-    current_span: &Option<SpanRef<S>>,
-    event: &Event,
-    event_visitor: &JsonStorage<'_>,
-) -> String {
-    let mut message = event_visitor
+/// The message recorded on an event, if any.
+fn event_message<'a>(event_visitor: &'a JsonStorage<'_>) -> Option<&'a str> {
+    event_visitor
         .values()
         .get("message")
-        .and_then(|v| match v {
-            Value::String(s) => Some(s.as_str()),
-            _ => None,
-        })
-        .unwrap_or_else(|| event.metadata().target())
-        .to_owned();
+        .and_then(Value::as_str)
+}
 
-    if let Some(span) = &current_span {
-        message = format!("{} {}", format_span_context(span, Type::Event), message);
+impl<W: for<'a> MakeWriter<'a> + 'static> Drop for JsonFormattingLayer<W> {
+    fn drop(&mut self) {
+        self.emit_pending_repeat();
     }
-
-    message
 }
 
 impl<S, W> Layer<S> for JsonFormattingLayer<W>
@@ -128,70 +2572,365 @@ where
     W: for<'a> MakeWriter<'a> + 'static,
 {
     fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
-        let current_span = ctx.lookup_current();
+        // Read the clock first, so that slow processing doesn't skew the record time.
+        let time = self.clock.now();
+        let held = !self.level_enabled(event.metadata());
+        if held
+            && self
+                .tail_sampling
+                .is_none_or(|level| self.level(event.metadata()) > level)
+        {
+            return;
+        }
+
+        // Honour explicit parents (`event!(parent: &span, ...)`) before falling back to the
+        // current span.
+        let current_span = ctx.event_span(event);
+        let root = current_span
+            .as_ref()
+            .filter(|_| self.transactions || self.tail_sampling.is_some())
+            .and_then(work_root);
+        let transaction = root
+            .as_ref()
+            .and_then(|root| root.extensions().get::<Transaction>().cloned());
+        let tail = root
+            .as_ref()
+            .and_then(|root| root.extensions().get::<TailBuffer>().cloned());
+        match &tail {
+            None if held => return,
+            Some(tail) if self.level(event.metadata()) == Level::ERROR => tail.fail(),
+            _ => {}
+        }
+
+        if let Some(span_summaries) = self.span_summaries.as_ref().filter(|_| !held) {
+            if self.level(event.metadata()) == Level::ERROR {
+                for span in ctx
+                    .event_span(event)
+                    .into_iter()
+                    .flat_map(|span| span.scope())
+                {
+                    let mut extensions = span.extensions_mut();
+                    if extensions.get_mut::<FailedSpan>().is_none() {
+                        extensions.insert(FailedSpan);
+                    }
+                }
+            }
+            self.emit_span_summaries(span_summaries);
+        }
+        let audited = self
+            .audit
+            .as_ref()
+            .is_some_and(|audit| audit.is_audit_event(event));
+        if let Some(sampler) = self.sampler.as_ref().filter(|_| !audited && !held) {
+            if let Some(summary) = sampler.take_summary() {
+                self.emit_sampling_summary(summary);
+            }
+            if !sampler.sample(event.metadata()) {
+                Metrics::incr(&self.metrics.dropped);
+                return;
+            }
+        }
 
-        let mut event_visitor = JsonStorage::default();
+        let storage_config = self
+            .storage_config
+            .get()
+            .copied()
+            .unwrap_or(StorageConfig::DEFAULT);
+        let mut event_visitor =
+            JsonStorage::with_config(event.metadata().fields().len(), storage_config);
         event.record(&mut event_visitor);
+        event_visitor.record_unset(event.metadata().fields());
 
-        let format = || {
-            let mut buffer = Vec::new();
-
-            let mut serializer = serde_json::Serializer::new(&mut buffer);
-            let mut map_serializer = serializer.serialize_map(None)?;
-
-            let message = format_event_message(&current_span, event, &event_visitor);
-            map_serializer.serialize_entry(
-                "time",
-                &Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
-            )?;
-            map_serializer.serialize_entry("name", &self.name)?;
-            map_serializer.serialize_entry("host", &self.hostname)?;
-            map_serializer.serialize_entry("message", &message)?;
-            map_serializer.serialize_entry("level", &event.metadata().level().to_string())?;
-            map_serializer.serialize_entry("pid", &self.pid)?;
-            map_serializer.serialize_entry("target", event.metadata().target())?;
-            map_serializer.serialize_entry("line", &event.metadata().line())?;
-            map_serializer.serialize_entry("file", &event.metadata().file())?;
-
-            for (key, value) in event_visitor
-                .values()
-                .iter()
-                .filter(|(&key, _)| key != "message")
+        let raw_message = event_message(&event_visitor);
+        let message = self.message(
+            current_span.as_ref().and_then(decorating_span_name),
+            &Type::Event,
+            Some(raw_message.unwrap_or_else(|| event.metadata().target())),
+        );
+        let callsite = self.callsite(event.metadata());
+        let mut record = self.record(&callsite, Type::Event, time, message);
+        let omit_message = raw_message.is_none() && self.missing_message == MissingMessage::Omit;
+        if omit_message {
+            record.remove_message();
+        }
+        if audited {
+            record.set_audit();
+        }
+        // The entries added by `record` are added again if the event is replayed.
+        let event_start = record.entries().len();
+
+        if let Some(span) = &current_span {
+            record.set_spans(span_names(span));
+            self.cache_span_fields(span);
+        }
+        let extensions = current_span.as_ref().map(|span| span.extensions());
+        let span_fields = extensions
+            .as_ref()
+            .and_then(|extensions| extensions.get::<JsonStorage>());
+        if let Some(sampler) = self.sampler.as_ref().filter(|_| !audited && !held) {
+            let keep = sampler.sample_fields(|key| {
+                event_visitor
+                    .get(key)
+                    .or_else(|| span_fields.and_then(|span| span.get(key)))
+            });
+            if !keep {
+                Metrics::incr(&self.metrics.dropped);
+                return;
+            }
+        }
+
+        for (key, value) in event_visitor
+            .values()
+            .iter()
+            .filter(|(&key, _)| key != "message")
+        {
+            if self.key_collision == KeyCollision::SpanWins
+                && self.span_field_prefix.is_none()
+                && span_fields.is_some_and(|span| span.values().contains_key(key))
             {
-                map_serializer.serialize_entry(key, value)?;
+                continue;
             }
+            self.push_field(&mut record, key, value);
+        }
+        let event_end = record.entries().len();
 
-            if let Some(span) = &current_span {
-                let extensions = span.extensions();
-                if let Some(visitor) = extensions.get::<JsonStorage>() {
-                    for (key, value) in visitor.values() {
-                        map_serializer.serialize_entry(key, value)?;
-                    }
+        if let Some(extensions) = &extensions {
+            self.push_span_fields(&mut record, extensions, Some(&event_visitor));
+        }
+        if let (Some(raw_message), true) = (raw_message, self.raw_message) {
+            record.push(fields::MSG_RAW, raw_message);
+        }
+        if let Some(span_path) = self.span_path.format(record.spans()) {
+            record.push(fields::SPAN, span_path);
+        }
+        if self
+            .backtrace_level
+            .is_some_and(|level| self.level(event.metadata()) <= level)
+        {
+            record.push(fields::STACK, backtrace_frames(&Backtrace::force_capture()));
+        }
+
+        if let Some(tail) = tail.as_ref().filter(|_| held) {
+            tail.hold(HeldEvent {
+                metadata: record.metadata(),
+                time,
+                message: record.message().to_owned(),
+                omit_message,
+                audited,
+                spans: record.spans().to_vec(),
+                fields: record.entries()[event_start..]
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_value()))
+                    .collect(),
+            });
+            return;
+        }
+        if let Some(deduplicator) = self.deduplicator.as_ref().filter(|_| !audited) {
+            match deduplicator.check(&record, event_start..event_end, transaction.as_ref()) {
+                Verdict::Suppress => {
+                    Metrics::incr(&self.metrics.dropped);
+                    return;
                 }
+                Verdict::Emit(Some(repeated)) => self.emit_repeated(repeated),
+                Verdict::Emit(None) => {}
             }
-            map_serializer.end()?;
-            buffer.write_all(b"\n")?;
+        }
+        self.emit_in(record, transaction.as_ref());
+    }
 
-            Ok(buffer)
-        };
+    /// Build the core fields of the callsite ahead of its first record.
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        self.callsite(metadata);
+        Interest::always()
+    }
 
-        let result: std::io::Result<Vec<u8>> = format();
-        if let Ok(formatted) = result {
-            let _ = self.emit(&formatted);
+    /// Store event fields like the [`JsonStorageLayer`] of the subscriber stores span fields.
+    fn on_register_dispatch(&self, subscriber: &Dispatch) {
+        if let Some(storage_layer) = subscriber.downcast_ref::<JsonStorageLayer>() {
+            let _ = self.storage_config.set(storage_layer.config());
+        }
+        if self.init_record && !self.initialized.swap(true, Ordering::Relaxed) {
+            self.emit_init_record();
         }
     }
 
     fn on_new_span(&self, _attrs: &Attributes, id: &Id, ctx: Context<'_, S>) {
+        if self.transactions || self.tail_sampling.is_some() {
+            let span = ctx.span(id).expect("Span not found, this is a bug");
+            if work_root(&span).is_some_and(|root| root.id() == span.id()) {
+                let mut extensions = span.extensions_mut();
+                if self.transactions && self.span_level_enabled(span.metadata()) {
+                    extensions.insert(Transaction::default());
+                }
+                if self.tail_sampling.is_some() {
+                    extensions.insert(TailBuffer::default());
+                }
+            }
+        }
+        if self.span_events == SpanEvents::None
+            || (self.span_events != SpanEvents::Full && !self.started_at)
+        {
+            return;
+        }
+        let time = self.clock.now();
         let span = ctx.span(id).expect("Span not found, this is a bug");
-        if let Ok(serialized) = self.serialize_span(&span, Type::EnterSpan) {
-            let _ = self.emit(&serialized);
+        if self.started_at {
+            let mut extensions = span.extensions_mut();
+            if extensions.get_mut::<StartedAt>().is_none() {
+                extensions.insert(StartedAt(time));
+            }
+        }
+        if self.span_events != SpanEvents::Full || !self.span_level_enabled(span.metadata()) {
+            return;
         }
+        self.serialize_span(&span, Type::EnterSpan, time);
     }
 
     fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let time = self.clock.now();
         let span = ctx.span(&id).expect("Span not found, this is a bug");
-        if let Ok(serialized) = self.serialize_span(&span, Type::ExitSpan) {
-            let _ = self.emit(&serialized);
+        if self.tail_sampling.is_some() {
+            self.close_tail(&span);
+        }
+        let is_transaction = span.extensions().get::<Transaction>().is_some();
+        if let Some(span_summaries) = &self.span_summaries {
+            if self.span_level_enabled(span.metadata()) {
+                let extensions = span.extensions();
+                if let Some(timings) = extensions.get::<Timings>() {
+                    let failed = extensions.get::<FailedSpan>().is_some();
+                    span_summaries.record(span.name(), timings.busy + timings.idle, failed);
+                }
+            }
+            self.emit_span_summaries(span_summaries);
+            if !is_transaction {
+                return;
+            }
+        }
+        if self.span_events == SpanEvents::None && !is_transaction {
+            return;
+        }
+        // The level filters may have changed since the span opened: the records held by a
+        // root span are emitted all the same.
+        let holds_records = span
+            .extensions()
+            .get::<Transaction>()
+            .is_some_and(Transaction::holds_records);
+        if !self.span_level_enabled(span.metadata()) && !holds_records {
+            return;
+        }
+        self.serialize_span(&span, Type::ExitSpan, time);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_no_field, CapturingMakeWriter};
+    use std::io;
+    use tracing_subscriber::prelude::*;
+
+    /// Replays a script of write calls to the captured output: `Some(n)` writes at most `n`
+    /// bytes, `None` fails. Calls past the end of the script write everything.
+    #[derive(Clone, Default)]
+    struct ScriptedMakeWriter {
+        output: CapturingMakeWriter,
+        script: Arc<Mutex<VecDeque<Option<usize>>>>,
+    }
+
+    impl ScriptedMakeWriter {
+        fn new(script: impl IntoIterator<Item = Option<usize>>) -> Self {
+            Self {
+                output: CapturingMakeWriter::default(),
+                script: Arc::new(Mutex::new(script.into_iter().collect())),
+            }
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for ScriptedMakeWriter {
+        type Writer = &'a ScriptedMakeWriter;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self
+        }
+    }
+
+    impl Write for &ScriptedMakeWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match self.script.lock().unwrap().pop_front() {
+                Some(None) => Err(io::ErrorKind::BrokenPipe.into()),
+                Some(Some(n)) => self.output.make_writer().write(&buf[..n.min(buf.len())]),
+                None => self.output.make_writer().write(buf),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
         }
     }
+
+    fn emit_to(make_writer: &ScriptedMakeWriter) -> Arc<Metrics> {
+        let metrics = Arc::new(Metrics::default());
+        let layer = JsonFormattingLayer::new("test".into(), make_writer.clone())
+            .with_write_retries(3, Duration::from_millis(1))
+            .with_metrics(metrics.clone());
+        let subscriber = tracing_subscriber::registry()
+            .with(JsonStorageLayer::new())
+            .with(layer);
+        tracing::subscriber::with_default(subscriber, || tracing::info!("Request handled"));
+        metrics
+    }
+
+    #[test]
+    fn failed_writes_are_retried() {
+        let make_writer = ScriptedMakeWriter::new([None, None]);
+        let metrics = emit_to(&make_writer);
+
+        assert_eq!(make_writer.output.records().len(), 1);
+        assert_eq!(metrics.emitted(), 1);
+        assert_eq!(metrics.write_errors(), 0);
+    }
+
+    #[test]
+    fn partly_written_records_are_completed_not_written_again() {
+        let make_writer = ScriptedMakeWriter::new([Some(10), Some(10)]);
+        let metrics = emit_to(&make_writer);
+
+        assert_eq!(make_writer.output.records().len(), 1);
+        assert_eq!(metrics.emitted(), 1);
+    }
+
+    #[test]
+    fn partly_written_records_are_not_retried() {
+        let make_writer = ScriptedMakeWriter::new([Some(10), None]);
+        let metrics = emit_to(&make_writer);
+
+        assert_eq!(make_writer.output.contents().len(), 10);
+        assert_eq!(metrics.emitted(), 0);
+        assert_eq!(metrics.write_errors(), 1);
+    }
+
+    #[test]
+    fn span_timings_are_reported_in_their_unit() {
+        let end_record = |unit| {
+            let make_writer = CapturingMakeWriter::default();
+            let layer = JsonFormattingLayer::new("test".into(), make_writer.clone())
+                .with_span_events(SpanEvents::Close)
+                .with_span_timings(true)
+                .with_span_timing_unit(unit);
+            let subscriber = tracing_subscriber::registry()
+                .with(JsonStorageLayer::new())
+                .with(layer);
+            tracing::subscriber::with_default(subscriber, || {
+                tracing::info_span!("request").in_scope(|| {});
+            });
+            make_writer.records().remove(0)
+        };
+
+        let record = end_record(TimingUnit::Nanoseconds);
+        assert!(record["busy_ns"].is_u64() && record["idle_ns"].is_u64());
+        assert_no_field(&record, "busy_ms");
+        let record = end_record(TimingUnit::Milliseconds);
+        assert!(record["busy_ms"].is_u64() && record["idle_ms"].is_u64());
+        assert_no_field(&record, "busy_ns");
+    }
 }
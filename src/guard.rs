@@ -0,0 +1,75 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+pub(crate) type Flusher = Box<dyn Fn() -> io::Result<()> + Send + Sync>;
+
+/// Emits the records the layer still holds, ahead of a flush.
+pub(crate) type Emitter = Box<dyn Fn() + Send + Sync>;
+
+/// Flushes the destinations of a [`JsonFormattingLayer`](crate::JsonFormattingLayer) when dropped.
+///
+/// Obtained with [`JsonFormattingLayer::guard`](crate::JsonFormattingLayer::guard), keep it
+/// alive in `main` so that buffered records are written before the process exits.
+///
+/// The pending count of [deduplicated](crate::JsonFormattingLayer::with_deduplication)
+/// repeats is emitted first, provided the layer is part of the current default subscriber.
+///
+/// ```rust
+/// use tracing_json_formatter::{JsonFormattingLayer, JsonStorageLayer};
+/// use tracing_subscriber::prelude::*;
+///
+/// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout);
+/// let _guard = layer.guard();
+/// tracing_subscriber::registry()
+///     .with(JsonStorageLayer::new())
+///     .with(layer)
+///     .init();
+/// ```
+#[must_use = "destinations are flushed as soon as the guard is dropped"]
+pub struct FlushGuard {
+    pub(crate) flushers: Vec<Flusher>,
+    pub(crate) pending: Option<Emitter>,
+    pub(crate) shut_down: Arc<AtomicBool>,
+}
+
+impl FlushGuard {
+    /// Flush every destination, returning the first error encountered.
+    pub fn flush(&self) -> io::Result<()> {
+        self.emit_pending();
+        flush_all(&self.flushers)
+    }
+
+    /// Stop emitting records and flush every destination.
+    ///
+    /// Records emitted after the shutdown are discarded and counted as dropped.
+    pub fn shutdown(self) -> io::Result<()> {
+        self.emit_pending();
+        self.shut_down.store(true, Ordering::SeqCst);
+        self.flush()
+    }
+
+    fn emit_pending(&self) {
+        if let Some(pending) = &self.pending {
+            pending();
+        }
+    }
+}
+
+impl Drop for FlushGuard {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+pub(crate) fn flush_all(flushers: &[Flusher]) -> io::Result<()> {
+    let mut result = Ok(());
+    for flusher in flushers {
+        if let Err(e) = flusher() {
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+    result
+}
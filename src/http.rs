@@ -0,0 +1,128 @@
+use crate::storage_layer::with_span_storage;
+use serde_json::{Map, Value};
+use tracing::Span;
+
+/// The request of an HTTP server span, following the Bunyan `req` convention.
+///
+/// Only the headers added with [`HttpRequestFields::with_header`] are logged, leave out
+/// credentials such as `Authorization` or `Cookie`.
+///
+/// ```rust
+/// use tracing_json_formatter::HttpRequestFields;
+///
+/// let span = tracing::info_span!("request");
+/// HttpRequestFields::new("GET", "/users/42")
+///     .with_header("user-agent", "curl/8.0.1")
+///     .with_remote_address("127.0.0.1:51234")
+///     .record(&span);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HttpRequestFields {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub remote_address: Option<String>,
+}
+
+impl HttpRequestFields {
+    pub fn new(method: impl Into<String>, url: impl Into<String>) -> Self {
+        Self {
+            method: method.into(),
+            url: url.into(),
+            ..Self::default()
+        }
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn with_remote_address(mut self, remote_address: impl Into<String>) -> Self {
+        self.remote_address = Some(remote_address.into());
+        self
+    }
+
+    /// `{"method": ..., "url": ..., "headers": {...}, "remoteAddress": ...}`, as logged by
+    /// Bunyan's standard `req` serializer.
+    pub fn to_value(&self) -> Value {
+        let mut req = Map::new();
+        req.insert("method".to_owned(), Value::from(self.method.as_str()));
+        req.insert("url".to_owned(), Value::from(self.url.as_str()));
+        req.insert("headers".to_owned(), headers(&self.headers));
+        if let Some(remote_address) = &self.remote_address {
+            req.insert(
+                "remoteAddress".to_owned(),
+                Value::from(remote_address.as_str()),
+            );
+        }
+        Value::Object(req)
+    }
+
+    /// Attach the request to `span` as a nested `req` object, carried by every record of
+    /// the span and of the spans created inside it afterwards.
+    ///
+    /// Returns `false` when the span isn't recorded by a `Registry` with a
+    /// [`JsonStorageLayer`](crate::JsonStorageLayer).
+    pub fn record(&self, span: &Span) -> bool {
+        with_span_storage(span, |storage| storage.set("req", self.to_value()))
+    }
+}
+
+/// The response of an HTTP server span, following the Bunyan `res` convention.
+///
+/// Record it before the span closes for its END record to carry the response:
+///
+/// ```rust
+/// use tracing_json_formatter::HttpResponseFields;
+///
+/// let span = tracing::info_span!("request");
+/// // ...
+/// HttpResponseFields::new(200)
+///     .with_header("content-type", "application/json")
+///     .record(&span);
+/// drop(span);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HttpResponseFields {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpResponseFields {
+    pub fn new(status_code: u16) -> Self {
+        Self {
+            status_code,
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// `{"statusCode": ..., "headers": {...}}`, as logged by Bunyan's standard `res`
+    /// serializer.
+    pub fn to_value(&self) -> Value {
+        let mut res = Map::new();
+        res.insert("statusCode".to_owned(), Value::from(self.status_code));
+        res.insert("headers".to_owned(), headers(&self.headers));
+        Value::Object(res)
+    }
+
+    /// Attach the response to `span` as a nested `res` object, see
+    /// [`HttpRequestFields::record`].
+    pub fn record(&self, span: &Span) -> bool {
+        with_span_storage(span, |storage| storage.set("res", self.to_value()))
+    }
+}
+
+/// Header names are lowercased, as HTTP/2 requires and Bunyan expects.
+fn headers(headers: &[(String, String)]) -> Value {
+    headers
+        .iter()
+        .map(|(name, value)| (name.to_ascii_lowercase(), Value::from(value.as_str())))
+        .collect::<Map<_, _>>()
+        .into()
+}
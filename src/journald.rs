@@ -0,0 +1,258 @@
+use serde_json::Value;
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Keys of the JSON record that are either redundant with the trusted fields
+/// journald adds on its own or that are mapped to a dedicated journal field.
+const CORE_KEYS: [&str; 9] = [
+    "time", "name", "host", "pid", "level", "message", "target", "line", "file",
+];
+
+/// Where the serialized JSON record ends up in the journal entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournaldJson {
+    /// The whole JSON record is used as `MESSAGE`.
+    Message,
+    /// `MESSAGE` only contains the record message, the JSON record is stored in a `JSON` field.
+    Field,
+}
+
+/// A [`MakeWriter`] delivering records to journald using its
+/// [native protocol](https://systemd.io/JOURNAL_NATIVE_PROTOCOL/).
+///
+/// Every record sets `PRIORITY` (from the level of the span or event), `SYSLOG_IDENTIFIER`,
+/// `CODE_FILE`, `CODE_LINE` and `TARGET`; user fields are added as uppercase journal fields
+/// (`request_id` becomes `REQUEST_ID`).
+///
+/// Records are sent as a single datagram: entries above the socket buffer size are
+/// rejected by the kernel and reported as write errors.
+#[derive(Debug)]
+pub struct JournaldMakeWriter {
+    socket: UnixDatagram,
+    socket_path: PathBuf,
+    identifier: String,
+    json: JournaldJson,
+}
+
+impl JournaldMakeWriter {
+    pub fn new(identifier: impl Into<String>) -> io::Result<Self> {
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            socket_path: PathBuf::from(JOURNALD_SOCKET),
+            identifier: identifier.into(),
+            json: JournaldJson::Field,
+        })
+    }
+
+    /// Send entries to a different socket, `/run/systemd/journal/socket` by default.
+    pub fn with_socket_path(mut self, path: impl AsRef<Path>) -> Self {
+        self.socket_path = path.as_ref().to_path_buf();
+        self
+    }
+
+    /// Choose where the JSON record is stored, a `JSON` field by default.
+    pub fn with_json(mut self, json: JournaldJson) -> Self {
+        self.json = json;
+        self
+    }
+
+    fn writer(&self, level: &Level) -> JournaldWriter<'_> {
+        JournaldWriter {
+            make_writer: self,
+            priority: crate::syslog_severity(level),
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for JournaldMakeWriter {
+    type Writer = JournaldWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.writer(&Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.writer(meta.level())
+    }
+}
+
+/// The writer returned by [`JournaldMakeWriter`]: each `write` call is sent as one journal entry.
+#[derive(Debug)]
+pub struct JournaldWriter<'a> {
+    make_writer: &'a JournaldMakeWriter,
+    priority: u8,
+}
+
+impl JournaldWriter<'_> {
+    fn entry(&self, record: &[u8]) -> Vec<u8> {
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        let parsed: Option<serde_json::Map<String, Value>> = serde_json::from_slice(record).ok();
+
+        let mut entry = Vec::with_capacity(record.len() * 2);
+        put_field(&mut entry, "PRIORITY", self.priority.to_string().as_bytes());
+        put_field(
+            &mut entry,
+            "SYSLOG_IDENTIFIER",
+            self.make_writer.identifier.as_bytes(),
+        );
+
+        let Some(fields) = parsed else {
+            // Not a JSON object, forward it untouched.
+            put_field(&mut entry, "MESSAGE", record);
+            return entry;
+        };
+
+        match self.make_writer.json {
+            JournaldJson::Message => put_field(&mut entry, "MESSAGE", record),
+            JournaldJson::Field => {
+                if let Some(message) = fields.get("message") {
+                    put_field(&mut entry, "MESSAGE", value_bytes(message).as_slice());
+                }
+                put_field(&mut entry, "JSON", record);
+            }
+        }
+        if let Some(file) = fields.get("file") {
+            put_field(&mut entry, "CODE_FILE", value_bytes(file).as_slice());
+        }
+        if let Some(line) = fields.get("line") {
+            put_field(&mut entry, "CODE_LINE", value_bytes(line).as_slice());
+        }
+        if let Some(target) = fields.get("target") {
+            put_field(&mut entry, "TARGET", value_bytes(target).as_slice());
+        }
+
+        for (key, value) in fields
+            .iter()
+            .filter(|(key, _)| !CORE_KEYS.contains(&key.as_str()))
+        {
+            if let Some(name) = journal_field_name(key) {
+                put_field(&mut entry, &name, value_bytes(value).as_slice());
+            }
+        }
+        entry
+    }
+}
+
+impl Write for JournaldWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let entry = self.entry(buf);
+        self.make_writer
+            .socket
+            .send_to(&entry, &self.make_writer.socket_path)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Journal field names only allow uppercase ASCII letters, digits and underscores,
+/// cannot start with a digit or an underscore (reserved to trusted fields) and are
+/// at most 64 characters long.
+fn journal_field_name(key: &str) -> Option<String> {
+    let name: String = key
+        .chars()
+        .map(|c| match c {
+            'a'..='z' => c.to_ascii_uppercase(),
+            'A'..='Z' | '0'..='9' => c,
+            _ => '_',
+        })
+        .skip_while(|c| *c == '_' || c.is_ascii_digit())
+        .take(64)
+        .collect();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Strings are stored verbatim, any other JSON value is stored serialized.
+fn value_bytes(value: &Value) -> Vec<u8> {
+    match value {
+        Value::String(s) => s.as_bytes().to_vec(),
+        other => other.to_string().into_bytes(),
+    }
+}
+
+/// Append a field to the entry, using the binary-safe encoding for values
+/// spanning multiple lines.
+fn put_field(entry: &mut Vec<u8>, name: &str, value: &[u8]) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains(&b'\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value);
+    entry.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_become_valid_journal_field_names() {
+        let name = |key: &str| journal_field_name(key);
+
+        assert_eq!(name("request_id").as_deref(), Some("REQUEST_ID"));
+        assert_eq!(name("http.method").as_deref(), Some("HTTP_METHOD"));
+        assert_eq!(name("Content-Type").as_deref(), Some("CONTENT_TYPE"));
+        assert_eq!(name("_private").as_deref(), Some("PRIVATE"));
+        assert_eq!(name("2fa_enabled").as_deref(), Some("FA_ENABLED"));
+        assert_eq!(name("_1_x").as_deref(), Some("X"));
+        assert_eq!(name(&"a".repeat(100)).map(|name| name.len()), Some(64));
+        assert_eq!(name("__"), None);
+        assert_eq!(name("123"), None);
+        assert_eq!(name("日本"), None);
+    }
+
+    #[test]
+    fn multiline_values_use_the_binary_encoding() {
+        let mut entry = Vec::new();
+        put_field(&mut entry, "MESSAGE", b"one line");
+        put_field(&mut entry, "STACK", b"a\nb");
+
+        let mut expected = b"MESSAGE=one line\nSTACK\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\n");
+        assert_eq!(entry, expected);
+    }
+
+    #[test]
+    fn records_are_sent_as_journal_entries() {
+        let path = std::env::temp_dir().join(format!("journald-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let journal = UnixDatagram::bind(&path).unwrap();
+        let make_writer = JournaldMakeWriter::new("my-app")
+            .unwrap()
+            .with_socket_path(&path);
+        let record = br#"{"level":50,"message":"Disk full","target":"app","time":"t","disk.id":7}"#;
+        make_writer.writer(&Level::ERROR).write_all(record).unwrap();
+
+        let mut entry = vec![0; 4096];
+        let len = journal.recv(&mut entry).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let entry = String::from_utf8(entry[..len].to_vec()).unwrap();
+        let fields: Vec<_> = entry.lines().collect();
+        assert_eq!(
+            fields,
+            [
+                "PRIORITY=3",
+                "SYSLOG_IDENTIFIER=my-app",
+                "MESSAGE=Disk full",
+                &format!("JSON={}", std::str::from_utf8(record).unwrap()),
+                "TARGET=app",
+                "DISK_ID=7",
+            ]
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use crate::record::Record;
+use std::sync::RwLock;
+use tracing::Metadata;
+
+/// The request ID of the invocation being processed.
+///
+/// A Lambda execution environment processes a single invocation at a time, a process-wide
+/// value is therefore correct even when the handler runs on several async tasks.
+static REQUEST_ID: RwLock<Option<String>> = RwLock::new(None);
+
+/// Clears the current Lambda request ID when dropped, see [`set_lambda_request_id`].
+#[must_use = "the request ID is cleared as soon as the guard is dropped"]
+#[derive(Debug)]
+pub struct LambdaRequestGuard(());
+
+impl Drop for LambdaRequestGuard {
+    fn drop(&mut self) {
+        *REQUEST_ID.write().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+/// Set the request ID of the invocation being processed, attached as `aws_request_id` to
+/// every record by [`LambdaContext`] until the returned guard is dropped.
+///
+/// ```rust
+/// use tracing_json_formatter::set_lambda_request_id;
+///
+/// fn handler(request_id: &str) {
+///     let _guard = set_lambda_request_id(request_id);
+///     tracing::info!("Processing invocation");
+/// }
+/// ```
+pub fn set_lambda_request_id(request_id: impl Into<String>) -> LambdaRequestGuard {
+    *REQUEST_ID.write().unwrap_or_else(|e| e.into_inner()) = Some(request_id.into());
+    LambdaRequestGuard(())
+}
+
+/// The request ID set with [`set_lambda_request_id`], if any.
+pub fn lambda_request_id() -> Option<String> {
+    REQUEST_ID.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// An enricher attaching the AWS Lambda invocation context to every record.
+///
+/// `function_name`, `function_version` and `function_memory_size` are read once from the
+/// environment variables set by the Lambda runtime; `aws_request_id` comes from
+/// [`set_lambda_request_id`] and `xray_trace_id` from `_X_AMZN_TRACE_ID`, which the runtime
+/// updates for each invocation.
+///
+/// ```rust
+/// use tracing_json_formatter::{JsonFormattingLayer, LambdaContext};
+///
+/// let layer = JsonFormattingLayer::new("my-function".into(), std::io::stdout)
+///     .with_lambda_context();
+///
+/// // Equivalent to
+/// let context = LambdaContext::from_env();
+/// let layer = JsonFormattingLayer::new("my-function".into(), std::io::stdout)
+///     .with_enricher(move |metadata, record| context.enrich(metadata, record));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct LambdaContext {
+    function_name: Option<String>,
+    function_version: Option<String>,
+    memory_size: Option<String>,
+}
+
+impl LambdaContext {
+    pub fn from_env() -> Self {
+        Self {
+            function_name: std::env::var("AWS_LAMBDA_FUNCTION_NAME").ok(),
+            function_version: std::env::var("AWS_LAMBDA_FUNCTION_VERSION").ok(),
+            memory_size: std::env::var("AWS_LAMBDA_FUNCTION_MEMORY_SIZE").ok(),
+        }
+    }
+
+    pub fn enrich(&self, _metadata: &Metadata<'_>, record: &mut Record<'_>) {
+        if let Some(function_name) = &self.function_name {
+            record.insert("function_name", function_name.as_str());
+        }
+        if let Some(function_version) = &self.function_version {
+            record.insert("function_version", function_version.as_str());
+        }
+        if let Some(memory_size) = &self.memory_size {
+            record.insert("function_memory_size", memory_size.as_str());
+        }
+        if let Some(request_id) = lambda_request_id() {
+            record.insert("aws_request_id", request_id);
+        }
+        if let Ok(trace_id) = std::env::var("_X_AMZN_TRACE_ID") {
+            record.insert("xray_trace_id", trace_id);
+        }
+    }
+}
@@ -1,6 +1,128 @@
 #![allow(clippy::needless_doctest_main)]
+
+/// Declare the `&'static Metadata` of records emitted by the layer on its own behalf
+/// (e.g. sampling summaries), which don't originate from a `tracing` callsite.
+macro_rules! synthetic_metadata {
+    ($vis:vis $ident:ident, $name:expr, $level:expr) => {
+        $vis static $ident: &tracing::Metadata<'static> = {
+            static CALLSITE: tracing_core::callsite::DefaultCallsite =
+                tracing_core::callsite::DefaultCallsite::new(&META);
+            static META: tracing::Metadata<'static> = tracing::Metadata::new(
+                $name,
+                module_path!(),
+                $level,
+                None,
+                None,
+                Some(module_path!()),
+                tracing_core::field::FieldSet::new(
+                    &[],
+                    tracing_core::identify_callsite!(&CALLSITE),
+                ),
+                tracing_core::metadata::Kind::EVENT,
+            );
+            &META
+        };
+    };
+}
+
+mod audit;
+mod batching;
+mod buffer;
+#[cfg(feature = "cbor")]
+mod cbor;
+mod child;
+mod clock;
+mod context;
+mod dedup;
+mod encoder;
+#[cfg(all(windows, feature = "eventlog"))]
+mod eventlog;
+#[cfg(any(
+    feature = "fluentd",
+    feature = "loki",
+    feature = "otlp",
+    feature = "splunk"
+))]
+mod export;
+#[cfg(feature = "fluentd")]
+mod fluentd;
 mod formatting_layer;
+mod guard;
+mod http;
+#[cfg(all(unix, feature = "journald"))]
+mod journald;
+mod lambda;
+pub mod log_context;
+#[cfg(feature = "loki")]
+mod loki;
+mod metrics;
+#[cfg(feature = "otlp")]
+mod otlp;
+mod panic;
+mod process;
+mod record;
+#[cfg(feature = "record-id")]
+mod record_id;
+mod reload;
+#[cfg(feature = "rolling")]
+mod rolling;
+mod sampling;
+mod schema;
+mod span_summary;
+#[cfg(feature = "splunk")]
+mod splunk;
+mod stdio;
 mod storage_layer;
+mod syslog;
+mod tee;
+pub mod test_support;
+mod timestamp;
+mod trace_context;
 
+pub use batching::*;
+#[cfg(feature = "cbor")]
+pub use cbor::*;
+pub use child::*;
+pub use clock::*;
+pub use context::*;
+pub use encoder::*;
+#[cfg(all(windows, feature = "eventlog"))]
+pub use eventlog::*;
+#[cfg(any(
+    feature = "fluentd",
+    feature = "loki",
+    feature = "otlp",
+    feature = "splunk"
+))]
+pub use export::*;
+#[cfg(feature = "fluentd")]
+pub use fluentd::*;
 pub use formatting_layer::*;
+pub use guard::*;
+pub use http::*;
+#[cfg(all(unix, feature = "journald"))]
+pub use journald::*;
+pub use lambda::*;
+#[cfg(feature = "loki")]
+pub use loki::*;
+pub use metrics::*;
+#[cfg(feature = "otlp")]
+pub use otlp::*;
+pub use panic::*;
+pub use record::*;
+#[cfg(feature = "record-id")]
+pub use record_id::*;
+pub use reload::*;
+#[cfg(feature = "rolling")]
+pub use rolling::*;
+pub use sampling::*;
+pub use schema::*;
+pub use span_summary::*;
+#[cfg(feature = "splunk")]
+pub use splunk::*;
+pub use stdio::*;
 pub use storage_layer::*;
+pub use syslog::*;
+pub use tee::*;
+pub use timestamp::*;
+pub use trace_context::*;
@@ -0,0 +1,151 @@
+//! Fields added to every record emitted by the current task, including records from
+//! third-party code or outside of any span, e.g. a `request_id`.
+//!
+//! Fields are local to a task wrapped with [`scope`], or to a closure run with
+//! [`sync_scope`]; outside of those, they are local to the thread. They are written after
+//! the static fields of the layer.
+//!
+//! ```rust
+//! use tracing_json_formatter::log_context;
+//!
+//! # fn spawn(future: impl std::future::Future<Output = ()> + Send + 'static) {}
+//! # fn hyper_call() {}
+//! spawn(log_context::scope(async {
+//!     log_context::set("request_id", "7a6d1c");
+//!     // Third-party records carry the field too: {..., "request_id": "7a6d1c"}
+//!     hyper_call();
+//! }));
+//! ```
+//!
+//! [`LogContext::current`](crate::LogContext::current) captures the fields, to carry them
+//! over to work spawned elsewhere.
+//!
+//! The context isn't a task-local of the runtime: it's a thread-local, which [`scope`]
+//! installs while it polls its future and puts back in between. Hence:
+//! - fields set outside of a scope belong to the worker thread, and show up in the records
+//!   of every task it runs afterwards: set them inside a [`scope`] in async code;
+//! - a task spawned from a scope starts without its fields, unless it's wrapped with
+//!   [`scope`] (which copies them when called) or attached to a `LogContext`;
+//! - records emitted while the future isn't being polled, e.g. by its `Drop`, don't carry
+//!   the fields.
+
+use serde_json::Value;
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+pub(crate) type Fields = Vec<(&'static str, Value)>;
+
+thread_local! {
+    static FIELDS: RefCell<Fields> = const { RefCell::new(Vec::new()) };
+}
+
+/// Set `key` in the current context, replacing its previous value.
+pub fn set(key: &'static str, value: impl Into<Value>) {
+    let value = value.into();
+    FIELDS.with_borrow_mut(|fields| match fields.iter_mut().find(|(k, _)| *k == key) {
+        Some((_, existing)) => *existing = value,
+        None => fields.push((key, value)),
+    });
+}
+
+/// The value of `key` in the current context.
+pub fn get(key: &str) -> Option<Value> {
+    FIELDS.with_borrow(|fields| {
+        fields
+            .iter()
+            .find_map(|(k, value)| (*k == key).then(|| value.clone()))
+    })
+}
+
+/// Remove `key` from the current context.
+pub fn remove(key: &str) {
+    FIELDS.with_borrow_mut(|fields| fields.retain(|(k, _)| *k != key));
+}
+
+/// Run `f` in a context of its own, starting with the fields of the current one: the
+/// fields it sets are gone once it returns.
+pub fn sync_scope<R>(f: impl FnOnce() -> R) -> R {
+    sync_scope_with(fields(), f)
+}
+
+pub(crate) fn sync_scope_with<R>(fields: Fields, f: impl FnOnce() -> R) -> R {
+    let _swap = Swap::new(fields);
+    f()
+}
+
+/// Wrap `future` in a context of its own, starting with the fields of the current one,
+/// like a task-local: the fields it sets only apply while it's polled.
+pub fn scope<F: Future>(future: F) -> Scoped<F> {
+    scope_with(fields(), future)
+}
+
+pub(crate) fn scope_with<F: Future>(fields: Fields, future: F) -> Scoped<F> {
+    Scoped {
+        future: Box::pin(future),
+        fields,
+    }
+}
+
+/// A copy of the fields of the current context.
+pub(crate) fn fields() -> Fields {
+    FIELDS.with_borrow(Clone::clone)
+}
+
+/// A future with a context of its own, see [`scope`].
+#[derive(Debug)]
+pub struct Scoped<F> {
+    future: Pin<Box<F>>,
+    fields: Fields,
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = &mut *self;
+        let swap = Swap::new(std::mem::take(&mut this.fields));
+        let poll = this.future.as_mut().poll(cx);
+        this.fields = swap.restore();
+        poll
+    }
+}
+
+/// Installs fields as the current context until dropped, even on panics, putting the
+/// previous ones back.
+struct Swap {
+    previous: Option<Fields>,
+}
+
+impl Swap {
+    fn new(fields: Fields) -> Self {
+        Self {
+            previous: Some(FIELDS.replace(fields)),
+        }
+    }
+
+    /// Put the previous fields back, returning the installed ones.
+    fn restore(mut self) -> Fields {
+        FIELDS.replace(self.previous.take().unwrap_or_default())
+    }
+}
+
+impl Drop for Swap {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            FIELDS.set(previous);
+        }
+    }
+}
+
+/// Call `f` with the fields of the current context, unless there are none.
+pub(crate) fn with_fields(f: impl FnOnce(&[(&'static str, Value)])) {
+    FIELDS.with(|fields| {
+        if let Ok(fields) = fields.try_borrow() {
+            if !fields.is_empty() {
+                f(&fields);
+            }
+        }
+    });
+}
@@ -0,0 +1,243 @@
+use crate::export::{record_time, BatchConfig, HttpEndpoint, Worker};
+use serde_json::{json, Map, Value};
+use std::io::{self, Write};
+use std::sync::OnceLock;
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+type Labels = Vec<(String, String)>;
+
+/// A record waiting to be pushed, with the labels of its stream.
+struct LokiEntry {
+    timestamp: String,
+    labels: Labels,
+    line: String,
+}
+
+/// A [`MakeWriter`] pushing records to Grafana Loki through its HTTP push API.
+///
+/// Records are queued and pushed in batches from a background thread, failed pushes are
+/// retried with exponential backoff. Records written while the queue is full are handled
+/// according to a [`BackpressurePolicy`](crate::BackpressurePolicy). Use a [`FlushGuard`](crate::FlushGuard) to push
+/// the last batch on exit.
+///
+/// Streams are identified by static labels and by labels taken from fields of the records,
+/// keep the latter to low-cardinality fields such as `level` or `target`.
+///
+/// Only `http://` endpoints are supported, reach Loki over HTTPS through a local proxy.
+///
+/// ```rust
+/// use tracing_json_formatter::{JsonFormattingLayer, LokiMakeWriter};
+///
+/// let make_writer = LokiMakeWriter::new("http://localhost:3100/loki/api/v1/push")
+///     .unwrap()
+///     .with_label("service", "my-app")
+///     .with_field_label("level");
+/// let layer = JsonFormattingLayer::new("my-app".into(), make_writer);
+/// ```
+pub struct LokiMakeWriter {
+    endpoint: HttpEndpoint,
+    labels: Vec<(String, String)>,
+    field_labels: Vec<String>,
+    headers: Vec<(String, String)>,
+    batch: BatchConfig,
+    worker: OnceLock<Option<Worker<LokiEntry>>>,
+}
+
+impl LokiMakeWriter {
+    /// Push to `url`, the full URL of the push API (`.../loki/api/v1/push`).
+    pub fn new(url: &str) -> io::Result<Self> {
+        Ok(Self {
+            endpoint: HttpEndpoint::new(url)?,
+            labels: Vec::new(),
+            field_labels: Vec::new(),
+            headers: Vec::new(),
+            batch: BatchConfig::default(),
+            worker: OnceLock::new(),
+        })
+    }
+
+    /// Add a label with a fixed value to every stream.
+    pub fn with_label(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.push((name.into(), value.into()));
+        self
+    }
+
+    /// Label streams with the value of a field of the records, e.g. `level`.
+    pub fn with_field_label(mut self, field: impl Into<String>) -> Self {
+        self.field_labels.push(field.into());
+        self
+    }
+
+    /// Push to the given tenant of a multi-tenant Loki, with the `X-Scope-OrgID` header.
+    pub fn with_tenant(mut self, tenant: impl Into<String>) -> Self {
+        self.headers
+            .push(("X-Scope-OrgID".to_owned(), tenant.into()));
+        self
+    }
+
+    /// Batch, queue and retry records as configured by `batch`: by default, the writer
+    /// pushes up to 500 records at once, at most a second after they were written.
+    pub fn with_batch(mut self, batch: BatchConfig) -> Self {
+        self.batch = batch;
+        self
+    }
+
+    fn worker(&self) -> Option<&Worker<LokiEntry>> {
+        self.worker
+            .get_or_init(|| {
+                let endpoint = self.endpoint.clone();
+                let headers = self.headers.clone();
+                Worker::spawn("loki-exporter", self.batch.clone(), move |entries| {
+                    endpoint.post("application/json", &headers, &push_request(entries))
+                })
+                .ok()
+            })
+            .as_ref()
+    }
+
+    fn entry(&self, record: &[u8]) -> LokiEntry {
+        let line = String::from_utf8_lossy(record).trim_end().to_owned();
+        let record = serde_json::from_str::<Value>(&line).unwrap_or_default();
+        let timestamp = record_time(&record).unix_nanos().max(0).to_string();
+        let mut labels = self.labels.clone();
+        if !self.field_labels.is_empty() {
+            if let Value::Object(fields) = &record {
+                for field in &self.field_labels {
+                    match fields.get(field) {
+                        Some(Value::String(value)) => labels.push((field.clone(), value.clone())),
+                        Some(Value::Null) | None => {}
+                        Some(value) => labels.push((field.clone(), value.to_string())),
+                    }
+                }
+            }
+        }
+        labels.sort();
+        LokiEntry {
+            timestamp,
+            labels,
+            line,
+        }
+    }
+}
+
+/// The body of a push request, grouping entries by stream.
+fn push_request(entries: &[LokiEntry]) -> Vec<u8> {
+    let mut streams: Vec<(&Labels, Vec<Value>)> = Vec::new();
+    for entry in entries {
+        let value = json!([entry.timestamp, entry.line]);
+        match streams
+            .iter_mut()
+            .find(|(labels, _)| **labels == entry.labels)
+        {
+            Some((_, values)) => values.push(value),
+            None => streams.push((&entry.labels, vec![value])),
+        }
+    }
+    let streams: Vec<Value> = streams
+        .into_iter()
+        .map(|(labels, values)| {
+            let stream: Map<String, Value> = labels
+                .iter()
+                .map(|(name, value)| (name.clone(), Value::from(value.as_str())))
+                .collect();
+            json!({ "stream": stream, "values": values })
+        })
+        .collect();
+    serde_json::to_vec(&json!({ "streams": streams })).unwrap_or_default()
+}
+
+impl<'a> MakeWriter<'a> for LokiMakeWriter {
+    type Writer = LokiWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LokiWriter {
+            make_writer: self,
+            level: None,
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        LokiWriter {
+            make_writer: self,
+            level: Some(*meta.level()),
+        }
+    }
+}
+
+/// The writer returned by [`LokiMakeWriter`]: each `write` call is queued as one record.
+pub struct LokiWriter<'a> {
+    make_writer: &'a LokiMakeWriter,
+    level: Option<Level>,
+}
+
+impl Write for LokiWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let worker = self
+            .make_writer
+            .worker()
+            .ok_or_else(|| io::Error::other("the Loki exporter thread couldn't be started"))?;
+        worker.push(self.make_writer.entry(buf), self.level.as_ref())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.make_writer.worker.get() {
+            Some(Some(worker)) => worker.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_grouped_by_stream() {
+        let make_writer = LokiMakeWriter::new("http://localhost:3100/loki/api/v1/push")
+            .unwrap()
+            .with_label("service", "app")
+            .with_field_label("level")
+            .with_field_label("tenant");
+        let entries: Vec<_> = [
+            r#"{"level":30,"time":"1970-01-01T00:00:01Z","msg":"a"}"#,
+            r#"{"level":50,"time":"1970-01-01T00:00:02Z","msg":"b"}"#,
+            r#"{"level":30,"time":"1970-01-01T00:00:03Z","msg":"c","tenant":null}"#,
+            r#"{"level":30,"time":"1970-01-01T00:00:04Z","msg":"d","tenant":"acme"}"#,
+        ]
+        .iter()
+        .map(|record| make_writer.entry(format!("{}\n", record).as_bytes()))
+        .collect();
+
+        let request: Value = serde_json::from_slice(&push_request(&entries)).unwrap();
+        let streams = request["streams"].as_array().unwrap();
+        assert_eq!(streams.len(), 3);
+        assert_eq!(
+            streams[0]["stream"],
+            json!({ "level": "30", "service": "app" })
+        );
+        assert_eq!(
+            streams[0]["values"],
+            json!([
+                [
+                    "1000000000",
+                    r#"{"level":30,"time":"1970-01-01T00:00:01Z","msg":"a"}"#
+                ],
+                [
+                    "3000000000",
+                    r#"{"level":30,"time":"1970-01-01T00:00:03Z","msg":"c","tenant":null}"#
+                ],
+            ])
+        );
+        assert_eq!(
+            streams[1]["stream"],
+            json!({ "level": "50", "service": "app" })
+        );
+        assert_eq!(streams[1]["values"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            streams[2]["stream"],
+            json!({ "level": "30", "service": "app", "tenant": "acme" })
+        );
+    }
+}
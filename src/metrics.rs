@@ -0,0 +1,73 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters describing the health of the logging pipeline itself.
+///
+/// Obtained through [`JsonFormattingLayer::metrics`](crate::JsonFormattingLayer::metrics),
+/// they can be polled to alert when records are being lost. Records are counted once
+/// per destination they are written to.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub(crate) emitted: AtomicU64,
+    pub(crate) serialization_errors: AtomicU64,
+    pub(crate) write_errors: AtomicU64,
+    pub(crate) dropped: AtomicU64,
+    pub(crate) backpressure_dropped: AtomicU64,
+}
+
+/// A point-in-time copy of [`Metrics`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub emitted: u64,
+    pub serialization_errors: u64,
+    pub write_errors: u64,
+    pub dropped: u64,
+    pub backpressure_dropped: u64,
+}
+
+impl Metrics {
+    /// Records successfully written.
+    pub fn emitted(&self) -> u64 {
+        self.emitted.load(Ordering::Relaxed)
+    }
+
+    /// Records that couldn't be encoded.
+    pub fn serialization_errors(&self) -> u64 {
+        self.serialization_errors.load(Ordering::Relaxed)
+    }
+
+    /// Records that were encoded but couldn't be written.
+    pub fn write_errors(&self) -> u64 {
+        self.write_errors.load(Ordering::Relaxed)
+    }
+
+    /// Records discarded by the layer's own filtering (sampling, thresholds, deduplication,
+    /// ...).
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Records discarded by a buffering writer: a background writer whose queue was full,
+    /// see [`BackpressurePolicy`](crate::BackpressurePolicy), or records it gave up on
+    /// delivering.
+    pub fn backpressure_dropped(&self) -> u64 {
+        self.backpressure_dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            emitted: self.emitted(),
+            serialization_errors: self.serialization_errors(),
+            write_errors: self.write_errors(),
+            dropped: self.dropped(),
+            backpressure_dropped: self.backpressure_dropped(),
+        }
+    }
+
+    pub(crate) fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add(counter: &AtomicU64, records: usize) {
+        counter.fetch_add(records as u64, Ordering::Relaxed);
+    }
+}
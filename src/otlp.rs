@@ -0,0 +1,244 @@
+use crate::export::{record_time, BatchConfig, HttpEndpoint, Worker};
+use crate::process;
+use crate::schema::fields;
+use serde_json::{json, Map, Value};
+use std::io::{self, Write};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A [`MakeWriter`] exporting records as OpenTelemetry log records, over OTLP/HTTP with
+/// the JSON encoding.
+///
+/// The `message` of a record becomes the body of the log record and its level the severity.
+/// The other fields become attributes, except for `time`, `name`, `host` and `pid`, which are
+/// described by the resource: `service.name`, `host.name` and `process.pid`. A `trace_id`
+/// field, as set by [`TraceContext`](crate::TraceContext), becomes the trace ID of the log
+/// record. Records are posted in batches from a background thread with the same queueing,
+/// retries and flushing as [`LokiMakeWriter`](crate::LokiMakeWriter).
+///
+/// Only `http://` endpoints are supported, reach collectors over HTTPS through a local
+/// proxy. OTLP/gRPC isn't supported: collectors accept OTLP/HTTP on port 4318 by default.
+///
+/// ```rust
+/// use tracing_json_formatter::{JsonFormattingLayer, OtlpMakeWriter};
+///
+/// let make_writer = OtlpMakeWriter::new("http://localhost:4318/v1/logs", "my-app")
+///     .unwrap()
+///     .with_resource_attribute("deployment.environment", "production");
+/// let layer = JsonFormattingLayer::new("my-app".into(), make_writer);
+/// ```
+pub struct OtlpMakeWriter {
+    endpoint: HttpEndpoint,
+    headers: Vec<(String, String)>,
+    resource: Vec<(String, Value)>,
+    batch: BatchConfig,
+    worker: OnceLock<Option<Worker<Value>>>,
+}
+
+impl OtlpMakeWriter {
+    /// Post to `url`, the full URL of the logs endpoint (`.../v1/logs`), describing the
+    /// records as coming from the `service_name` service.
+    pub fn new(url: &str, service_name: impl Into<String>) -> io::Result<Self> {
+        let mut resource = vec![("service.name".to_owned(), Value::from(service_name.into()))];
+        if let Some(hostname) = process::hostname() {
+            resource.push(("host.name".to_owned(), Value::from(hostname)));
+        }
+        if let Some(pid) = process::pid() {
+            resource.push(("process.pid".to_owned(), Value::from(pid)));
+        }
+        Ok(Self {
+            endpoint: HttpEndpoint::new(url)?,
+            headers: Vec::new(),
+            resource,
+            batch: BatchConfig::default(),
+            worker: OnceLock::new(),
+        })
+    }
+
+    /// Describe the resource with an additional attribute, replacing the one of the same
+    /// key, e.g. `host.name`.
+    pub fn with_resource_attribute(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Self {
+        let key = key.into();
+        self.resource.retain(|(existing, _)| *existing != key);
+        self.resource.push((key, value.into()));
+        self
+    }
+
+    /// Send an additional header with every request, e.g. to authenticate.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Batch, queue and retry records as configured by `batch`: by default, the writer
+    /// posts up to 500 records at once, at most a second after they were written.
+    pub fn with_batch(mut self, batch: BatchConfig) -> Self {
+        self.batch = batch;
+        self
+    }
+
+    fn worker(&self) -> Option<&Worker<Value>> {
+        self.worker
+            .get_or_init(|| {
+                let endpoint = self.endpoint.clone();
+                let headers = self.headers.clone();
+                let resource = attributes(self.resource.iter().map(|(k, v)| (k.as_str(), v)));
+                Worker::spawn(
+                    "otlp-exporter",
+                    self.batch.clone(),
+                    move |log_records: &[Value]| {
+                        let request = export_request(&resource, log_records);
+                        endpoint.post("application/json", &headers, &request)
+                    },
+                )
+                .ok()
+            })
+            .as_ref()
+    }
+
+    /// The log record of a record, records that aren't JSON become the body.
+    fn log_record(&self, record: &[u8], level: Option<&Level>) -> Value {
+        let record = String::from_utf8_lossy(record);
+        let record = record.trim_end();
+        let parsed = serde_json::from_str::<Value>(record).unwrap_or_default();
+        let observed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos());
+        let time = record_time(&parsed).unix_nanos().max(0);
+        let mut log_record = Map::new();
+        log_record.insert("timeUnixNano".to_owned(), Value::from(time.to_string()));
+        log_record.insert(
+            "observedTimeUnixNano".to_owned(),
+            Value::from(observed.to_string()),
+        );
+        if let Some(level) = level {
+            log_record.insert("severityNumber".to_owned(), Value::from(severity(level)));
+            log_record.insert("severityText".to_owned(), Value::from(level.as_str()));
+        }
+        let Value::Object(mut entries) = parsed else {
+            log_record.insert("body".to_owned(), any_value(&Value::from(record)));
+            return Value::Object(log_record);
+        };
+        if let Some(message) = entries.remove(fields::MESSAGE) {
+            log_record.insert("body".to_owned(), any_value(&message));
+        }
+        if let Some(Value::String(trace_id)) = entries.remove("trace_id") {
+            log_record.insert("traceId".to_owned(), Value::from(trace_id));
+        }
+        for resource_field in [fields::TIME, fields::NAME, fields::HOST, fields::PID] {
+            entries.remove(resource_field);
+        }
+        if level.is_some() {
+            entries.remove(fields::LEVEL);
+        }
+        let attributes = attributes(entries.iter().map(|(k, v)| (k.as_str(), v)));
+        log_record.insert("attributes".to_owned(), attributes);
+        Value::Object(log_record)
+    }
+}
+
+/// The OpenTelemetry severity number of `level`, the lowest of its range.
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::TRACE => 1,
+        Level::DEBUG => 5,
+        Level::INFO => 9,
+        Level::WARN => 13,
+        Level::ERROR => 17,
+    }
+}
+
+/// The body of an export request, with a single resource and scope.
+fn export_request(resource: &Value, log_records: &[Value]) -> Vec<u8> {
+    let request = json!({
+        "resourceLogs": [{
+            "resource": { "attributes": resource },
+            "scopeLogs": [{
+                "scope": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+                "logRecords": log_records,
+            }],
+        }],
+    });
+    serde_json::to_vec(&request).unwrap_or_default()
+}
+
+/// A list of OTLP key-value pairs.
+fn attributes<'a>(entries: impl Iterator<Item = (&'a str, &'a Value)>) -> Value {
+    entries
+        .map(|(key, value)| json!({ "key": key, "value": any_value(value) }))
+        .collect()
+}
+
+/// The OTLP `AnyValue` of a JSON value: 64-bit integers are encoded as strings and `null`
+/// as an empty value.
+fn any_value(value: &Value) -> Value {
+    match value {
+        Value::Null => json!({}),
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(n) => match n.as_i64() {
+            Some(n) => json!({ "intValue": n.to_string() }),
+            None => json!({ "doubleValue": n.as_f64() }),
+        },
+        Value::String(s) => json!({ "stringValue": s }),
+        Value::Array(values) => {
+            let values: Vec<Value> = values.iter().map(any_value).collect();
+            json!({ "arrayValue": { "values": values } })
+        }
+        Value::Object(fields) => {
+            let values = attributes(fields.iter().map(|(k, v)| (k.as_str(), v)));
+            json!({ "kvlistValue": { "values": values } })
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for OtlpMakeWriter {
+    type Writer = OtlpWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        OtlpWriter {
+            make_writer: self,
+            level: None,
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        OtlpWriter {
+            make_writer: self,
+            level: Some(*meta.level()),
+        }
+    }
+}
+
+/// The writer returned by [`OtlpMakeWriter`]: each `write` call is queued as one log record.
+pub struct OtlpWriter<'a> {
+    make_writer: &'a OtlpMakeWriter,
+    level: Option<Level>,
+}
+
+impl Write for OtlpWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let worker = self
+            .make_writer
+            .worker()
+            .ok_or_else(|| io::Error::other("the OTLP exporter thread couldn't be started"))?;
+        let log_record = self.make_writer.log_record(buf, self.level.as_ref());
+        worker.push(log_record, self.level.as_ref())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.make_writer.worker.get() {
+            Some(Some(worker)) => worker.flush(),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::panic::PanicHookInfo;
+
+/// Replace the default panic hook with one reporting panics as `ERROR` events, so that
+/// they are formatted like any other record instead of showing up as raw text.
+///
+/// The event has the `panic` target and carries the panic message, its location
+/// (`panic.file`, `panic.line`, `panic.column`), the name of the panicking thread and,
+/// when enabled through `RUST_BACKTRACE`, a `backtrace` field.
+///
+/// Install it after the subscriber, events emitted while no subscriber is set are lost.
+///
+/// ```rust
+/// use tracing_json_formatter::{install_panic_hook, JsonFormattingLayer, JsonStorageLayer};
+/// use tracing_subscriber::prelude::*;
+///
+/// let subscriber = tracing_subscriber::registry()
+///     .with(JsonStorageLayer::new())
+///     .with(JsonFormattingLayer::new("my-app".into(), std::io::stdout));
+/// tracing::subscriber::set_global_default(subscriber).unwrap();
+/// install_panic_hook();
+/// ```
+pub fn install_panic_hook() {
+    std::panic::set_hook(Box::new(panic_hook));
+}
+
+fn panic_hook(info: &PanicHookInfo<'_>) {
+    let payload = info.payload();
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>");
+    let thread = std::thread::current();
+    let thread = thread.name().unwrap_or("<unnamed>");
+    let location = info.location();
+    let backtrace = Backtrace::capture();
+    let backtrace =
+        (backtrace.status() == BacktraceStatus::Captured).then(|| backtrace.to_string());
+
+    tracing::error!(
+        target: "panic",
+        thread,
+        backtrace,
+        panic.file = location.map(|l| l.file()),
+        panic.line = location.map(|l| l.line()),
+        panic.column = location.map(|l| l.column()),
+        "{}",
+        message,
+    );
+}
@@ -0,0 +1,75 @@
+//! Information about the running process, unavailable on some targets.
+
+use crate::timestamp::{self, Timestamp};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The name of the host, `None` on targets without one (e.g. `wasm32`).
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn hostname() -> Option<String> {
+    Some(gethostname::gethostname().to_string_lossy().into_owned())
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn hostname() -> Option<String> {
+    None
+}
+
+/// The ID of the process, `None` on targets without processes (e.g. `wasm32`).
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn pid() -> Option<u32> {
+    Some(std::process::id())
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn pid() -> Option<u32> {
+    None
+}
+
+/// When the process started: the time, and the instant to measure the uptime from.
+///
+/// Read from `/proc` on Linux, to within 10 ms; elsewhere, or if `/proc` can't be read,
+/// the process is taken to start the first time this is called.
+pub(crate) fn start() -> (Timestamp, Instant) {
+    static START: OnceLock<(Timestamp, Instant)> = OnceLock::new();
+    *START.get_or_init(|| {
+        let (now, instant) = (SystemTime::now(), Instant::now());
+        let age = age().unwrap_or_default();
+        match (now.checked_sub(age), instant.checked_sub(age)) {
+            (Some(start), Some(instant)) => (timestamp::from_system_time(start), instant),
+            _ => (timestamp::from_system_time(now), instant),
+        }
+    })
+}
+
+/// How long the process has been running, from the boot times of the system and of the
+/// process.
+#[cfg(target_os = "linux")]
+fn age() -> Option<Duration> {
+    // The unit `/proc` counts clock ticks in, `USER_HZ`, is 100 on every architecture
+    // supported by Rust.
+    const TICKS_PER_SECOND: f64 = 100.0;
+
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name, the second field, may hold spaces and parentheses: `starttime`,
+    // the 22nd field, is the 20th after it.
+    let start_ticks: u64 = stat
+        .rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .nth(19)?
+        .parse()
+        .ok()?;
+    let uptime: f64 = std::fs::read_to_string("/proc/uptime")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()?;
+    Duration::try_from_secs_f64(uptime - start_ticks as f64 / TICKS_PER_SECOND).ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn age() -> Option<Duration> {
+    None
+}
@@ -0,0 +1,368 @@
+use crate::formatting_layer::Type;
+use crate::schema::fields;
+use crate::timestamp::Timestamp;
+use serde::{Serialize, Serializer};
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+use tracing::{Level, Metadata};
+
+/// A single entry of a [`Record`], borrowing from the span/event storage whenever possible.
+pub type Entry<'a> = (Cow<'a, str>, FieldValue<'a>);
+
+/// The value of a [`Record`] entry.
+///
+/// Strings borrowed from the layer or the callsite metadata (name, hostname, target, ...)
+/// are kept as-is instead of being copied into a `serde_json::Value` for every record.
+#[derive(Clone, Debug)]
+pub enum FieldValue<'a> {
+    Str(&'a str),
+    Json(Cow<'a, Value>),
+    /// Pre-serialized JSON, written verbatim, see
+    /// [`JsonFormattingLayer::with_raw_json_field`](crate::JsonFormattingLayer::with_raw_json_field).
+    Raw(&'a RawValue),
+}
+
+impl PartialEq for FieldValue<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (FieldValue::Str(a), FieldValue::Str(b)) => a == b,
+            (FieldValue::Json(a), FieldValue::Json(b)) => a == b,
+            (FieldValue::Raw(a), FieldValue::Raw(b)) => a.get() == b.get(),
+            _ => false,
+        }
+    }
+}
+
+impl FieldValue<'_> {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            FieldValue::Str(s) => Some(s),
+            FieldValue::Json(value) => value.as_str(),
+            FieldValue::Raw(_) => None,
+        }
+    }
+
+    /// The value, borrowed.
+    pub(crate) fn borrowed(&self) -> FieldValue<'_> {
+        match self {
+            FieldValue::Str(s) => FieldValue::Str(s),
+            FieldValue::Json(value) => FieldValue::Json(Cow::Borrowed(value.as_ref())),
+            FieldValue::Raw(raw) => FieldValue::Raw(raw),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            FieldValue::Str(s) => Value::from(*s),
+            FieldValue::Json(value) => value.as_ref().clone(),
+            FieldValue::Raw(raw) => serde_json::from_str(raw.get()).unwrap_or(Value::Null),
+        }
+    }
+
+    pub fn into_value(self) -> Value {
+        match self {
+            FieldValue::Str(s) => Value::from(s),
+            FieldValue::Json(value) => value.into_owned(),
+            FieldValue::Raw(raw) => serde_json::from_str(raw.get()).unwrap_or(Value::Null),
+        }
+    }
+}
+
+impl Serialize for FieldValue<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            FieldValue::Str(s) => serializer.serialize_str(s),
+            FieldValue::Json(value) => value.serialize(serializer),
+            FieldValue::Raw(raw) => raw.serialize(serializer),
+        }
+    }
+}
+
+/// Values are displayed as JSON, like `serde_json::Value`.
+impl fmt::Display for FieldValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldValue::Str(s) => write!(f, "{}", Value::from(*s)),
+            FieldValue::Json(value) => write!(f, "{}", value),
+            FieldValue::Raw(raw) => write!(f, "{}", raw),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for FieldValue<'a> {
+    fn from(s: &'a str) -> Self {
+        FieldValue::Str(s)
+    }
+}
+
+impl<'a> From<&'a Value> for FieldValue<'a> {
+    fn from(value: &'a Value) -> Self {
+        FieldValue::Json(Cow::Borrowed(value))
+    }
+}
+
+impl<'a> From<Cow<'a, Value>> for FieldValue<'a> {
+    fn from(value: Cow<'a, Value>) -> Self {
+        FieldValue::Json(value)
+    }
+}
+
+impl From<Value> for FieldValue<'_> {
+    fn from(value: Value) -> Self {
+        FieldValue::Json(Cow::Owned(value))
+    }
+}
+
+/// A span lifecycle or event record, as handed over to an [`Encoder`](crate::Encoder).
+///
+/// Entries are kept in output order: core fields (`time`, `name`, `host`, `message`, ...)
+/// come first, followed by the fields recorded on the event and its spans.
+#[derive(Clone, Debug)]
+pub struct Record<'a> {
+    ty: Type,
+    metadata: &'static Metadata<'static>,
+    level: Level,
+    time: Timestamp,
+    message: String,
+    entries: Vec<Entry<'a>>,
+    core_len: usize,
+    span_fields: Option<SerializedFields<'a>>,
+    callsite_fields: Option<SerializedCallsiteFields>,
+    spans: Vec<&'static str>,
+    audit: bool,
+}
+
+/// The core entries of a record that only depend on its callsite, serialized once per
+/// callsite.
+#[derive(Clone, Debug)]
+pub(crate) struct SerializedCallsiteFields {
+    pub(crate) range: Range<usize>,
+    pub(crate) bytes: Arc<[u8]>,
+}
+
+/// The entries of a record that are also available in serialized form.
+#[derive(Clone, Debug)]
+pub(crate) struct SerializedFields<'a> {
+    pub(crate) range: Range<usize>,
+    pub(crate) bytes: &'a [u8],
+}
+
+impl<'a> Record<'a> {
+    pub(crate) fn new(
+        ty: Type,
+        metadata: &'static Metadata<'static>,
+        time: Timestamp,
+        message: String,
+        core: Vec<Entry<'a>>,
+    ) -> Self {
+        let core_len = core.len();
+        Self {
+            ty,
+            metadata,
+            level: *metadata.level(),
+            time,
+            message,
+            entries: core,
+            core_len,
+            span_fields: None,
+            callsite_fields: None,
+            spans: Vec::new(),
+            audit: false,
+        }
+    }
+
+    /// Override the level of the metadata, see
+    /// [`JsonFormattingLayer::with_level_remap`](crate::JsonFormattingLayer::with_level_remap).
+    pub(crate) fn set_level(&mut self, level: Level) {
+        self.level = level;
+    }
+
+    /// Mark the record as an audit record, see
+    /// [`JsonFormattingLayer::with_audit_log`](crate::JsonFormattingLayer::with_audit_log).
+    pub(crate) fn set_audit(&mut self) {
+        self.audit = true;
+    }
+
+    pub(crate) fn is_audit(&self) -> bool {
+        self.audit
+    }
+
+    pub(crate) fn set_spans(&mut self, spans: Vec<&'static str>) {
+        self.spans = spans;
+    }
+
+    pub(crate) fn push(&mut self, key: impl Into<Cow<'a, str>>, value: impl Into<FieldValue<'a>>) {
+        self.entries.push((key.into(), value.into()));
+    }
+
+    /// Flag the span fields pushed from `start` to now as available pre-serialized.
+    pub(crate) fn set_serialized_span_fields(&mut self, start: usize, bytes: &'a [u8]) {
+        self.span_fields = Some(SerializedFields {
+            range: start..self.entries.len(),
+            bytes,
+        });
+    }
+
+    pub(crate) fn serialized_span_fields(&self) -> Option<&SerializedFields<'a>> {
+        self.span_fields.as_ref()
+    }
+
+    /// Flag the core entries in `range` as available pre-serialized.
+    pub(crate) fn set_serialized_callsite_fields(&mut self, range: Range<usize>, bytes: Arc<[u8]>) {
+        self.callsite_fields = Some(SerializedCallsiteFields { range, bytes });
+    }
+
+    pub(crate) fn serialized_callsite_fields(&self) -> Option<&SerializedCallsiteFields> {
+        self.callsite_fields.as_ref()
+    }
+
+    /// Remove the core `message` field.
+    pub(crate) fn remove_message(&mut self) {
+        if let Some(index) = self.entries[..self.core_len]
+            .iter()
+            .position(|(key, _)| key == "message")
+        {
+            self.entries.remove(index);
+            self.core_len -= 1;
+            let ranges = self
+                .span_fields
+                .iter_mut()
+                .map(|serialized| &mut serialized.range)
+                .chain(
+                    self.callsite_fields
+                        .iter_mut()
+                        .map(|serialized| &mut serialized.range),
+                );
+            for range in ranges.filter(|range| index < range.start) {
+                *range = range.start - 1..range.end - 1;
+            }
+        }
+    }
+
+    /// Sort the fields by key, core fields left in place. Equal keys keep their order.
+    pub(crate) fn sort_fields(&mut self) {
+        self.span_fields = None;
+        self.entries[self.core_len..].sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
+
+    /// Append a field to the record.
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<Value>) {
+        self.push(key.into(), value.into());
+    }
+
+    /// The kind of record: span start, span end or event.
+    pub fn ty(&self) -> &Type {
+        &self.ty
+    }
+
+    /// The metadata of the span or event this record was created from.
+    pub fn metadata(&self) -> &'static Metadata<'static> {
+        self.metadata
+    }
+
+    /// The level of the record, which differs from the level of its metadata when it is
+    /// remapped.
+    pub fn level(&self) -> &Level {
+        &self.level
+    }
+
+    pub fn time(&self) -> Timestamp {
+        self.time
+    }
+
+    /// The message of the record, including its span context decoration.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The names of the spans the record belongs to, from the root to the current span
+    /// (the span itself for span records).
+    pub fn spans(&self) -> &[&'static str] {
+        &self.spans
+    }
+
+    /// All the entries of the record, core fields first.
+    pub fn entries(&self) -> &[Entry<'a>] {
+        &self.entries
+    }
+
+    /// The core fields of the record (`time`, `name`, `host`, `message`, `level`, ...).
+    pub fn core_fields(&self) -> &[Entry<'a>] {
+        &self.entries[..self.core_len]
+    }
+
+    /// The fields recorded on the event and the spans it belongs to.
+    pub fn fields(&self) -> &[Entry<'a>] {
+        &self.entries[self.core_len..]
+    }
+
+    /// Whether the entry at `index` holds user-provided data (the message or a field).
+    fn is_user_entry(&self, index: usize) -> bool {
+        index >= self.core_len || self.entries[index].0 == "message"
+    }
+
+    /// The length of the longest user-provided string value.
+    pub(crate) fn longest_string(&self) -> usize {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| self.is_user_entry(*index))
+            .filter_map(|(_, (_, value))| value.as_str())
+            .map(str::len)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// A copy of the record where user-provided string values longer than `max_len` bytes
+    /// are cut short, flagged with a `truncated` field.
+    pub(crate) fn truncate_strings(&self, max_len: usize) -> Record<'a> {
+        let mut record = self.clone();
+        record.span_fields = None;
+        record.message = truncate_str(&self.message, max_len).to_owned();
+        for (index, (_, value)) in record.entries.iter_mut().enumerate() {
+            if !self.is_user_entry(index) {
+                continue;
+            }
+            if let Some(s) = value.as_str() {
+                if s.len() > max_len {
+                    *value = Value::from(truncate_str(s, max_len)).into();
+                }
+            }
+        }
+        record.push(fields::TRUNCATED, Value::Bool(true));
+        record
+    }
+}
+
+/// The core fields of a record being built, handed over to the callbacks of
+/// [`JsonFormattingLayer::with_core_fields`](crate::JsonFormattingLayer::with_core_fields).
+pub struct CoreFields<'c, 'a> {
+    entries: &'c mut Vec<Entry<'a>>,
+}
+
+impl<'c, 'a> CoreFields<'c, 'a> {
+    pub(crate) fn new(entries: &'c mut Vec<Entry<'a>>) -> Self {
+        Self { entries }
+    }
+
+    /// Append a core field, after the source location.
+    pub fn insert(&mut self, key: impl Into<Cow<'static, str>>, value: impl Into<Value>) {
+        self.entries.push((key.into(), value.into().into()));
+    }
+}
+
+/// Cut `s` to at most `max_len` bytes, on a char boundary.
+pub(crate) fn truncate_str(s: &str, max_len: usize) -> &str {
+    if s.len() <= max_len {
+        return s;
+    }
+    let mut end = max_len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
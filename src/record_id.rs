@@ -0,0 +1,123 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The format of the `record_id` attached to every record by
+/// [`JsonFormattingLayer::with_record_id`](crate::JsonFormattingLayer::with_record_id).
+///
+/// Both formats start with a millisecond timestamp, so IDs sort roughly by creation time,
+/// followed by random bits making collisions between processes unlikely.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecordIdFormat {
+    /// A UUID version 7, e.g. `01890a5d-ac96-774b-bcce-b302099a8057`.
+    #[default]
+    UuidV7,
+    /// A ULID, e.g. `01H4567Y4P8W8R7Q1Z2B3C4D5E`.
+    Ulid,
+}
+
+impl RecordIdFormat {
+    /// Generate a new ID.
+    pub fn generate(self) -> String {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        self.format(millis, random_u128())
+    }
+
+    /// The ID of the 48-bit millisecond timestamp `millis`, with bits taken from `random`.
+    fn format(self, millis: u64, random: u128) -> String {
+        let millis = millis & 0xffff_ffff_ffff;
+        match self {
+            RecordIdFormat::UuidV7 => {
+                let bits = (u128::from(millis) << 80)
+                    | (0x7 << 76)
+                    | ((random & 0xfff) << 64)
+                    | (0b10 << 62)
+                    | (random >> 66);
+                let hex = format!("{:032x}", bits);
+                format!(
+                    "{}-{}-{}-{}-{}",
+                    &hex[..8],
+                    &hex[8..12],
+                    &hex[12..16],
+                    &hex[16..20],
+                    &hex[20..]
+                )
+            }
+            RecordIdFormat::Ulid => {
+                const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+                let bits = (u128::from(millis) << 80) | (random >> 48);
+                (0..26)
+                    .rev()
+                    .map(|index| ALPHABET[((bits >> (index * 5)) & 0x1f) as usize] as char)
+                    .collect()
+            }
+        }
+    }
+}
+
+/// 128 random bits, from the randomly seeded keys of `RandomState` hashing a counter.
+///
+/// Not suitable for cryptography, but good enough to tell records apart.
+fn random_u128() -> u128 {
+    static STATE: OnceLock<RandomState> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let state = STATE.get_or_init(RandomState::new);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut hasher = state.build_hasher();
+    hasher.write_u64(count);
+    let high = hasher.finish();
+    hasher.write_u64(count);
+    let low = hasher.finish();
+    (u128::from(high) << 64) | u128::from(low)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uuids_follow_the_version_7_layout() {
+        // The example of RFC 9562, appendix A.6: 48 bits of timestamp, version 7, 12 bits
+        // of `rand_a`, variant `0b10`, 62 bits of `rand_b`.
+        let random = (0x18c4_dc0c_0c07_398f << 66) | 0xcc3;
+        assert_eq!(
+            RecordIdFormat::UuidV7.format(0x017f_22e2_79b0, random),
+            "017f22e2-79b0-7cc3-98c4-dc0c0c07398f"
+        );
+        assert_eq!(
+            RecordIdFormat::UuidV7.format(u64::MAX, u128::MAX),
+            "ffffffff-ffff-7fff-bfff-ffffffffffff"
+        );
+        assert_eq!(
+            RecordIdFormat::UuidV7.format(0, 0),
+            "00000000-0000-7000-8000-000000000000"
+        );
+    }
+
+    #[test]
+    fn ulids_follow_the_spec_layout() {
+        // The example of the ULID spec: 48 bits of timestamp, then 80 random bits.
+        let random = 0xd676_4c61_efb9_9302_bd5b << 48;
+        assert_eq!(
+            RecordIdFormat::Ulid.format(1_469_922_850_259, random),
+            "01ARZ3NDEKTSV4RRFFQ69G5FAV"
+        );
+        assert_eq!(
+            RecordIdFormat::Ulid.format(u64::MAX, u128::MAX),
+            "7ZZZZZZZZZZZZZZZZZZZZZZZZZ"
+        );
+    }
+
+    #[test]
+    fn generated_ids_sort_by_time() {
+        for format in [RecordIdFormat::UuidV7, RecordIdFormat::Ulid] {
+            let earlier = format.format(1_000, u128::MAX);
+            let later = format.format(1_001, 0);
+            assert!(earlier < later, "{} < {}", earlier, later);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use crate::encoder::Encoder;
+use serde_json::Value;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tracing::level_filters::LevelFilter;
+
+/// The part of the configuration of a layer that can be changed once installed.
+#[derive(Default)]
+pub(crate) struct ReloadableConfig {
+    /// Replaces the encoder of the main writer when set.
+    pub(crate) encoder: Option<Box<dyn Encoder>>,
+    pub(crate) max_level: Option<LevelFilter>,
+    pub(crate) fields: Vec<(String, Value)>,
+}
+
+/// Changes the configuration of an installed [`JsonFormattingLayer`](crate::JsonFormattingLayer),
+/// e.g. from a `SIGHUP` handler or an admin endpoint.
+///
+/// Obtained with [`JsonFormattingLayer::reload_handle`](crate::JsonFormattingLayer::reload_handle).
+/// Each change applies atomically to the records emitted after it.
+///
+/// ```rust
+/// use tracing::level_filters::LevelFilter;
+/// use tracing_json_formatter::{JsonFormattingLayer, JsonStorageLayer, PrettyEncoder};
+/// use tracing_subscriber::prelude::*;
+///
+/// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout);
+/// let handle = layer.reload_handle();
+/// tracing_subscriber::registry()
+///     .with(JsonStorageLayer::new())
+///     .with(layer)
+///     .init();
+///
+/// handle.set_encoder(PrettyEncoder);
+/// handle.set_max_level(LevelFilter::DEBUG);
+/// handle.set_field("deployment", "canary");
+/// ```
+#[derive(Clone)]
+pub struct ReloadHandle {
+    pub(crate) config: Arc<RwLock<ReloadableConfig>>,
+}
+
+impl ReloadHandle {
+    /// Replace the encoder of the main writer, additional sinks keep their own.
+    pub fn set_encoder<E: Encoder + 'static>(&self, encoder: E) {
+        self.write().encoder = Some(Box::new(encoder));
+    }
+
+    /// Go back to the encoder the layer was built with.
+    pub fn reset_encoder(&self) {
+        self.write().encoder = None;
+    }
+
+    /// Only emit records whose level is enabled by `max_level`.
+    ///
+    /// This only filters the output of this layer: it can't enable levels disabled by a
+    /// filter of the subscriber.
+    pub fn set_max_level(&self, max_level: LevelFilter) {
+        self.write().max_level = Some(max_level);
+    }
+
+    /// Add a field to every record, replacing its value if it was already set.
+    pub fn set_field(&self, key: impl Into<String>, value: impl Into<Value>) {
+        let (key, value) = (key.into(), value.into());
+        let mut config = self.write();
+        match config.fields.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, existing)) => *existing = value,
+            None => config.fields.push((key, value)),
+        }
+    }
+
+    /// Stop adding a field set with [`ReloadHandle::set_field`].
+    pub fn remove_field(&self, key: &str) {
+        self.write().fields.retain(|(k, _)| k != key);
+    }
+
+    pub(crate) fn read(&self) -> RwLockReadGuard<'_, ReloadableConfig> {
+        self.config.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, ReloadableConfig> {
+        self.config.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
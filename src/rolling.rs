@@ -0,0 +1,343 @@
+use crate::timestamp::{self, Timestamp};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// When the active log file is rotated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    /// Never rotate, keep appending to the same file.
+    Never,
+    /// Rotate when the first record of a new hour is written.
+    Hourly,
+    /// Rotate when the first record of a new day is written.
+    Daily,
+    /// Rotate before a record would grow the active file past the given number of bytes.
+    Size(u64),
+}
+
+impl Rotation {
+    fn period(&self, time: Timestamp) -> Option<String> {
+        match self {
+            Rotation::Hourly => Some(timestamp::local(&time).date_hour()),
+            Rotation::Daily => Some(timestamp::local(&time).date()),
+            Rotation::Never | Rotation::Size(_) => None,
+        }
+    }
+}
+
+type RotateHook = Box<dyn Fn(&Path) + Send + Sync>;
+
+struct ActiveFile {
+    file: File,
+    size: u64,
+    period: Option<String>,
+}
+
+/// A [`MakeWriter`] appending records to `<directory>/<prefix>` and rotating it
+/// according to a [`Rotation`] policy.
+///
+/// Rotated files are renamed to `<prefix>.<suffix>`, where the suffix is the period
+/// they cover for time-based rotations (`app.log.2023-04-12`) or the rotation time for
+/// size-based rotations (`app.log.20230412T153012.123`).
+///
+/// Rotated files are not compressed: the crate has no compression library to do it with.
+/// Compress them from [`RollingFileMakeWriter::with_on_rotate`] if needed, e.g. with
+/// `flate2`; any file starting with `<prefix>.`, such as `app.log.2023-04-12.gz`, counts
+/// towards the retention limit.
+///
+/// ```rust,no_run
+/// use tracing_json_formatter::{JsonFormattingLayer, RollingFileMakeWriter, Rotation};
+///
+/// let make_writer = RollingFileMakeWriter::new("/var/log/my-app", "app.log", Rotation::Daily)
+///     .unwrap()
+///     .with_max_files(7);
+/// let layer = JsonFormattingLayer::new("my-app".into(), make_writer);
+/// ```
+pub struct RollingFileMakeWriter {
+    directory: PathBuf,
+    prefix: String,
+    rotation: Rotation,
+    max_files: Option<usize>,
+    on_rotate: Option<RotateHook>,
+    active: Mutex<ActiveFile>,
+}
+
+impl RollingFileMakeWriter {
+    pub fn new(
+        directory: impl AsRef<Path>,
+        prefix: impl Into<String>,
+        rotation: Rotation,
+    ) -> io::Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        let prefix = prefix.into();
+        fs::create_dir_all(&directory)?;
+
+        let file = open(&directory.join(&prefix))?;
+        let metadata = file.metadata()?;
+        // Resume the period of an existing file, so that a restart on the next day
+        // still rotates yesterday's records away.
+        let period = rotation.period(
+            metadata
+                .modified()
+                .map(timestamp::from_system_time)
+                .unwrap_or_else(|_| timestamp::now()),
+        );
+
+        Ok(Self {
+            directory,
+            prefix,
+            rotation,
+            max_files: None,
+            on_rotate: None,
+            active: Mutex::new(ActiveFile {
+                file,
+                size: metadata.len(),
+                period,
+            }),
+        })
+    }
+
+    /// Only keep the `max_files` most recent rotated files, deleting older ones.
+    pub fn with_max_files(mut self, max_files: usize) -> Self {
+        self.max_files = Some(max_files);
+        self
+    }
+
+    /// Call `hook` with the path of every rotated file, before old files are pruned, e.g.
+    /// to compress it. The hook runs on the logging thread, while the writer is locked.
+    pub fn with_on_rotate<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Path) + Send + Sync + 'static,
+    {
+        self.on_rotate = Some(Box::new(hook));
+        self
+    }
+
+    fn rotate(&self, active: &mut ActiveFile, suffix: &str) -> io::Result<()> {
+        active.file.flush()?;
+        let path = self.directory.join(&self.prefix);
+        let mut rotated = self.directory.join(format!("{}.{}", self.prefix, suffix));
+        let mut counter = 1;
+        while rotated.exists() {
+            rotated = self
+                .directory
+                .join(format!("{}.{}.{}", self.prefix, suffix, counter));
+            counter += 1;
+        }
+        fs::rename(&path, &rotated)?;
+        active.file = open(&path)?;
+        active.size = 0;
+
+        if let Some(hook) = &self.on_rotate {
+            hook(&rotated);
+        }
+        self.prune()
+    }
+
+    fn prune(&self) -> io::Result<()> {
+        let Some(max_files) = self.max_files else {
+            return Ok(());
+        };
+        let rotated_prefix = format!("{}.", self.prefix);
+        let mut rotated: Vec<_> = fs::read_dir(&self.directory)?
+            .filter_map(Result::ok)
+            .filter(|entry| {
+                entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|name| name.starts_with(&rotated_prefix))
+            })
+            .filter_map(|entry| {
+                let modified = entry.metadata().and_then(|m| m.modified()).ok()?;
+                Some((modified, entry.path()))
+            })
+            .collect();
+        if rotated.len() <= max_files {
+            return Ok(());
+        }
+        rotated.sort();
+        for (_, path) in &rotated[..rotated.len() - max_files] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for RollingFileMakeWriter {
+    type Writer = RollingFileWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RollingFileWriter {
+            make_writer: self,
+            active: self.active.lock().unwrap_or_else(|e| e.into_inner()),
+        }
+    }
+}
+
+/// The writer returned by [`RollingFileMakeWriter`], holding the lock on the active
+/// file so that a record is never split across two files.
+pub struct RollingFileWriter<'a> {
+    make_writer: &'a RollingFileMakeWriter,
+    active: MutexGuard<'a, ActiveFile>,
+}
+
+impl Write for RollingFileWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.make_writer.rotation {
+            Rotation::Never => {}
+            Rotation::Size(max_size) => {
+                if self.active.size > 0 && self.active.size + buf.len() as u64 > max_size {
+                    let suffix = timestamp::local(&timestamp::now()).compact_millis();
+                    self.make_writer.rotate(&mut self.active, &suffix)?;
+                }
+            }
+            Rotation::Hourly | Rotation::Daily => {
+                let period = self.make_writer.rotation.period(timestamp::now());
+                if period != self.active.period {
+                    let previous = std::mem::replace(&mut self.active.period, period);
+                    match previous {
+                        Some(previous) if self.active.size > 0 => {
+                            self.make_writer.rotate(&mut self.active, &previous)?
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        let written = self.active.file.write(buf)?;
+        self.active.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.active.file.flush()
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    /// A directory of its own for each test, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("rolling-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            Self(path)
+        }
+
+        /// The names of the files in the directory, sorted.
+        fn files(&self) -> Vec<String> {
+            let mut files: Vec<_> = fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            files.sort();
+            files
+        }
+
+        fn read(&self, name: &str) -> String {
+            fs::read_to_string(self.0.join(name)).unwrap()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn write_records(make_writer: &RollingFileMakeWriter, records: usize) {
+        for i in 0..records {
+            let record = format!("{{\"record\":{:03}}}\n", i);
+            make_writer
+                .make_writer()
+                .write_all(record.as_bytes())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn files_are_rotated_before_they_grow_past_their_size() {
+        let dir = TempDir::new("size");
+        let make_writer =
+            RollingFileMakeWriter::new(&dir.0, "app.log", Rotation::Size(20)).unwrap();
+        write_records(&make_writer, 3);
+
+        let files = dir.files();
+        assert_eq!(files.len(), 3, "{:?}", files);
+        assert_eq!(files[0], "app.log");
+        assert_eq!(dir.read("app.log"), "{\"record\":002}\n");
+        let rotated: String = files[1..].iter().map(|name| dir.read(name)).collect();
+        assert_eq!(rotated, "{\"record\":000}\n{\"record\":001}\n");
+    }
+
+    #[test]
+    fn rotated_files_are_pruned_past_the_limit() {
+        let dir = TempDir::new("prune");
+        let rotations = Arc::new(AtomicUsize::new(0));
+        let make_writer = {
+            let rotations = rotations.clone();
+            RollingFileMakeWriter::new(&dir.0, "app.log", Rotation::Size(1))
+                .unwrap()
+                .with_max_files(2)
+                .with_on_rotate(move |path| {
+                    assert!(path.exists());
+                    rotations.fetch_add(1, Ordering::Relaxed);
+                })
+        };
+        fs::write(dir.0.join("other.log"), "").unwrap();
+        write_records(&make_writer, 5);
+
+        assert_eq!(rotations.load(Ordering::Relaxed), 4);
+        let files = dir.files();
+        assert_eq!(files.len(), 4, "{:?}", files);
+        assert!(files.contains(&"other.log".to_owned()));
+        assert_eq!(dir.read("app.log"), "{\"record\":004}\n");
+        let mut kept: Vec<_> = files
+            .iter()
+            .filter(|name| name.starts_with("app.log."))
+            .map(|name| dir.read(name))
+            .collect();
+        kept.sort();
+        assert_eq!(kept, ["{\"record\":002}\n", "{\"record\":003}\n"]);
+    }
+
+    #[test]
+    fn the_period_of_an_existing_file_is_resumed() {
+        let dir = TempDir::new("resume");
+        fs::create_dir_all(&dir.0).unwrap();
+        let two_days_ago = SystemTime::now() - Duration::from_secs(2 * 86_400);
+        let file = open(&dir.0.join("app.log")).unwrap();
+        writeln!(&file, "{{\"record\":\"old\"}}").unwrap();
+        file.set_modified(two_days_ago).unwrap();
+
+        let make_writer = RollingFileMakeWriter::new(&dir.0, "app.log", Rotation::Daily).unwrap();
+        write_records(&make_writer, 1);
+
+        let date = timestamp::local(&timestamp::from_system_time(two_days_ago)).date();
+        assert_eq!(
+            dir.files(),
+            ["app.log".to_owned(), format!("app.log.{}", date)]
+        );
+        assert_eq!(
+            dir.read(&format!("app.log.{}", date)),
+            "{\"record\":\"old\"}\n"
+        );
+        assert_eq!(dir.read("app.log"), "{\"record\":000}\n");
+    }
+}
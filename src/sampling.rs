@@ -0,0 +1,259 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::Metadata;
+use tracing_core::callsite::Identifier;
+
+synthetic_metadata!(pub(crate) SUMMARY_METADATA, "sampling summary", tracing::Level::WARN);
+
+/// Rate limit applied to the events matched by a [`Sampler`] rule.
+///
+/// Up to `max_per_second` events go through every second; beyond that, one event
+/// every `one_in` is kept and the others are suppressed.
+#[derive(Clone, Debug)]
+pub struct SamplingRule {
+    max_per_second: u64,
+    one_in: u64,
+    per_callsite: bool,
+}
+
+impl SamplingRule {
+    /// Let at most `max_per_second` events through every second, dropping everything else.
+    pub fn new(max_per_second: u64) -> Self {
+        Self {
+            max_per_second,
+            one_in: 0,
+            per_callsite: false,
+        }
+    }
+
+    /// Once the rate limit is reached, keep one event every `n` instead of dropping them all.
+    pub fn then_one_in(mut self, n: u64) -> Self {
+        self.one_in = n;
+        self
+    }
+
+    /// Apply the limit to each callsite separately instead of all the events matching the rule.
+    pub fn per_callsite(mut self) -> Self {
+        self.per_callsite = true;
+        self
+    }
+}
+
+/// Sampling of the events whose `field` matches a predicate, see
+/// [`Sampler::with_field_rule`].
+///
+/// Matching events are all kept by default, or one every `n` with [`FieldRule::one_in`].
+pub struct FieldRule {
+    field: String,
+    predicate: Box<dyn Fn(&Value) -> bool + Send + Sync>,
+    one_in: u64,
+    matched: AtomicU64,
+}
+
+impl FieldRule {
+    /// Match the events whose `field`, recorded on the event or its spans, satisfies
+    /// `predicate`.
+    pub fn new<F>(field: impl Into<String>, predicate: F) -> Self
+    where
+        F: Fn(&Value) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            field: field.into(),
+            predicate: Box::new(predicate),
+            one_in: 1,
+            matched: AtomicU64::new(0),
+        }
+    }
+
+    /// Keep one matching event every `n`, `0` dropping them all.
+    pub fn one_in(mut self, n: u64) -> Self {
+        self.one_in = n;
+        self
+    }
+}
+
+impl fmt::Debug for FieldRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FieldRule")
+            .field("field", &self.field)
+            .field("one_in", &self.one_in)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+enum WindowKey {
+    Rule(usize),
+    Callsite(usize, Identifier),
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    count: u64,
+}
+
+#[derive(Debug)]
+struct SamplerState {
+    windows: HashMap<WindowKey, Window>,
+    suppressed: Vec<u64>,
+    last_summary: Instant,
+}
+
+/// Rate limiting of high-volume events, configured per target.
+///
+/// Rules match events whose target starts with the given prefix, the longest matching
+/// prefix wins. Every `summary_interval` (10 seconds by default) a `WARN` record listing how
+/// many events were suppressed for each rule is emitted, when any were.
+///
+/// ```rust
+/// use tracing_json_formatter::{JsonFormattingLayer, Sampler, SamplingRule};
+///
+/// let sampler = Sampler::default()
+///     .with_rule("sqlx::query", SamplingRule::new(100).then_one_in(10));
+/// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout).with_sampler(sampler);
+/// ```
+#[derive(Debug)]
+pub struct Sampler {
+    rules: Vec<(String, SamplingRule)>,
+    field_rules: Vec<FieldRule>,
+    summary_interval: Duration,
+    pending_summary: AtomicBool,
+    state: Mutex<SamplerState>,
+}
+
+impl Default for Sampler {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            field_rules: Vec::new(),
+            summary_interval: Duration::from_secs(10),
+            pending_summary: AtomicBool::new(false),
+            state: Mutex::new(SamplerState {
+                windows: HashMap::new(),
+                suppressed: Vec::new(),
+                last_summary: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl Sampler {
+    /// Rate limit the events whose target starts with `target`.
+    pub fn with_rule(mut self, target: impl Into<String>, rule: SamplingRule) -> Self {
+        self.rules.push((target.into(), rule));
+        self.state
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .suppressed
+            .push(0);
+        self
+    }
+
+    /// Sample events on the value of a field, e.g. keep every error response and one
+    /// successful response in a hundred:
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::{FieldRule, Sampler};
+    ///
+    /// let sampler = Sampler::default()
+    ///     .with_field_rule(FieldRule::new("status", |status| {
+    ///         status.as_u64().is_some_and(|status| status >= 500)
+    ///     }))
+    ///     .with_field_rule(FieldRule::new("status", |status| status == 200).one_in(100));
+    /// ```
+    ///
+    /// Field rules are evaluated in order once the fields of the event are collected,
+    /// after the target rules: the first matching rule decides, events matching none are
+    /// kept. Events dropped by field rules are counted in
+    /// [`Metrics::dropped`](crate::Metrics::dropped).
+    pub fn with_field_rule(mut self, rule: FieldRule) -> Self {
+        self.field_rules.push(rule);
+        self
+    }
+
+    /// How often suppressed events are reported.
+    pub fn with_summary_interval(mut self, interval: Duration) -> Self {
+        self.summary_interval = interval;
+        self
+    }
+
+    fn rule(&self, target: &str) -> Option<(usize, &SamplingRule)> {
+        self.rules
+            .iter()
+            .enumerate()
+            .filter(|(_, (prefix, _))| target.starts_with(prefix.as_str()))
+            .max_by_key(|(_, (prefix, _))| prefix.len())
+            .map(|(index, (_, rule))| (index, rule))
+    }
+
+    /// Whether the event should be kept.
+    pub(crate) fn sample(&self, metadata: &Metadata<'_>) -> bool {
+        let Some((index, rule)) = self.rule(metadata.target()) else {
+            return true;
+        };
+        let key = if rule.per_callsite {
+            WindowKey::Callsite(index, metadata.callsite())
+        } else {
+            WindowKey::Rule(index)
+        };
+
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let window = state.windows.entry(key).or_insert(Window {
+            start: now,
+            count: 0,
+        });
+        if now.duration_since(window.start) >= Duration::from_secs(1) {
+            window.start = now;
+            window.count = 0;
+        }
+        window.count += 1;
+
+        let overflow = window.count.saturating_sub(rule.max_per_second);
+        let keep = overflow == 0 || (rule.one_in > 0 && overflow % rule.one_in == 0);
+        if !keep {
+            state.suppressed[index] += 1;
+            self.pending_summary.store(true, Ordering::Relaxed);
+        }
+        keep
+    }
+
+    /// Whether the event with the fields found by `field` should be kept by the field rules.
+    pub(crate) fn sample_fields<'v>(&self, field: impl Fn(&str) -> Option<&'v Value>) -> bool {
+        let Some(rule) = self
+            .field_rules
+            .iter()
+            .find(|rule| field(&rule.field).is_some_and(|value| (rule.predicate)(value)))
+        else {
+            return true;
+        };
+        rule.one_in > 0 && rule.matched.fetch_add(1, Ordering::Relaxed) % rule.one_in == 0
+    }
+
+    /// The number of suppressed events per rule target, if a summary is due.
+    pub(crate) fn take_summary(&self) -> Option<Vec<(&str, u64)>> {
+        if !self.pending_summary.load(Ordering::Relaxed) {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.last_summary.elapsed() < self.summary_interval {
+            return None;
+        }
+        state.last_summary = Instant::now();
+        self.pending_summary.store(false, Ordering::Relaxed);
+
+        let summary = self
+            .rules
+            .iter()
+            .zip(state.suppressed.iter_mut())
+            .filter(|(_, suppressed)| **suppressed > 0)
+            .map(|((target, _), suppressed)| (target.as_str(), std::mem::take(suppressed)))
+            .collect();
+        Some(summary)
+    }
+}
@@ -0,0 +1,121 @@
+/// The names of the fields written by the layer, for code reading its records.
+///
+/// The core fields are described, in order and for a given configuration, by
+/// [`JsonFormattingLayer::schema`](crate::JsonFormattingLayer::schema).
+pub mod fields {
+    pub const TIME: &str = "time";
+    pub const NAME: &str = "name";
+    pub const HOST: &str = "host";
+    pub const MESSAGE: &str = "message";
+    pub const LEVEL: &str = "level";
+    pub const PID: &str = "pid";
+    pub const TARGET: &str = "target";
+    pub const MODULE_PATH: &str = "module_path";
+    /// The name of the callsite of an event, e.g. `event src/main.rs:42`.
+    pub const CALLSITE: &str = "callsite";
+    /// The source location as an object, with
+    /// [`JsonFormattingLayer::with_nested_src`](crate::JsonFormattingLayer::with_nested_src).
+    pub const SRC: &str = "src";
+    pub const LINE: &str = "line";
+    pub const FILE: &str = "file";
+    pub const LIFECYCLE: &str = "lifecycle";
+    /// The duration of a span, on the record of its end.
+    pub const ELAPSED_MILLISECONDS: &str = "elapsed_milliseconds";
+    /// The names of the spans of an event, see
+    /// [`JsonFormattingLayer::with_span_path`](crate::JsonFormattingLayer::with_span_path).
+    pub const SPAN: &str = "span";
+    pub const STARTED_AT: &str = "started_at";
+    /// The time the process started, see
+    /// [`JsonFormattingLayer::with_uptime`](crate::JsonFormattingLayer::with_uptime).
+    pub const PROC_START: &str = "proc_start";
+    pub const UPTIME_MS: &str = "uptime_ms";
+    /// The number of events emitted inside a span, on the record of its end, see
+    /// [`JsonStorageLayer::with_event_counts`](crate::JsonStorageLayer::with_event_counts).
+    pub const EVENT_COUNT: &str = "event_count";
+    pub const TRACE_COUNT: &str = "trace_count";
+    pub const DEBUG_COUNT: &str = "debug_count";
+    pub const INFO_COUNT: &str = "info_count";
+    pub const WARN_COUNT: &str = "warn_count";
+    pub const ERROR_COUNT: &str = "error_count";
+    pub const BUSY_NS: &str = "busy_ns";
+    pub const IDLE_NS: &str = "idle_ns";
+    pub const BUSY_MS: &str = "busy_ms";
+    pub const IDLE_MS: &str = "idle_ms";
+    pub const FINAL_FIELDS: &str = "final_fields";
+    pub const MSG_RAW: &str = "msg_raw";
+    pub const STACK: &str = "stack";
+    pub const TRUNCATED: &str = "truncated";
+    pub const REPEAT_COUNT: &str = "repeat_count";
+    /// The records emitted inside a root span, on the record of its end, see
+    /// [`JsonFormattingLayer::with_transactions`](crate::JsonFormattingLayer::with_transactions).
+    pub const EVENTS: &str = "events";
+    /// The hash chain of audit records, see
+    /// [`JsonFormattingLayer::with_audit_log`](crate::JsonFormattingLayer::with_audit_log).
+    pub const PREV_HASH: &str = "prev_hash";
+    pub const HASH: &str = "hash";
+}
+
+/// The JSON type of a field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldKind {
+    String,
+    Number,
+    Object,
+}
+
+/// A core field of the records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SchemaField {
+    pub name: &'static str,
+    pub kind: FieldKind,
+    /// Whether the field is on every record. When it isn't, the field is only left out of
+    /// some records: the message of events without one, for instance.
+    pub always_present: bool,
+    /// Whether the value can be `null`, e.g. the file of callsites without one.
+    pub nullable: bool,
+}
+
+impl SchemaField {
+    pub(crate) fn new(name: &'static str, kind: FieldKind) -> Self {
+        Self {
+            name,
+            kind,
+            always_present: true,
+            nullable: false,
+        }
+    }
+
+    pub(crate) fn nullable(mut self) -> Self {
+        self.nullable = true;
+        self
+    }
+
+    pub(crate) fn sometimes_present(mut self) -> Self {
+        self.always_present = false;
+        self
+    }
+}
+
+/// The core fields of the records written by a layer, in order, as returned by
+/// [`JsonFormattingLayer::schema`](crate::JsonFormattingLayer::schema).
+///
+/// The fields of events and spans follow the core fields in records.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Schema {
+    fields: Vec<SchemaField>,
+}
+
+impl Schema {
+    pub(crate) fn new(fields: Vec<SchemaField>) -> Self {
+        Self { fields }
+    }
+
+    pub fn fields(&self) -> &[SchemaField] {
+        &self.fields
+    }
+
+    /// The field named `name`, if records have one.
+    pub fn field(&self, name: &str) -> Option<&SchemaField> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+synthetic_metadata!(pub(crate) SPAN_SUMMARY_METADATA, "span summary", tracing::Level::INFO);
+
+/// Aggregated span END records: instead of a record per closed span, the layer emits one
+/// summary record per span name every `interval` (a minute by default), for metrics built
+/// from logs.
+///
+/// Summaries hold the number of spans closed during the interval (`count`), the median and
+/// 95th percentile of their duration in milliseconds (`p50_ms`, `p95_ms`) and the share of
+/// those during which an `ERROR` event was emitted (`error_rate`). Summaries are emitted
+/// when spans close or events are recorded once the interval has elapsed.
+///
+/// The durations of the interval are kept until its summary is emitted, memory grows with
+/// the number of spans closed per interval.
+///
+/// ```rust
+/// use std::time::Duration;
+/// use tracing_json_formatter::{JsonFormattingLayer, SpanSummaries};
+///
+/// let layer = JsonFormattingLayer::new("my-app".into(), std::io::stdout)
+///     .with_span_summaries(SpanSummaries::default().with_interval(Duration::from_secs(10)));
+/// ```
+#[derive(Debug)]
+pub struct SpanSummaries {
+    interval: Duration,
+    pending: AtomicBool,
+    state: Mutex<SummaryState>,
+}
+
+#[derive(Debug)]
+struct SummaryState {
+    spans: HashMap<&'static str, SpanStats>,
+    start: Instant,
+}
+
+#[derive(Debug, Default)]
+struct SpanStats {
+    durations: Vec<Duration>,
+    errors: u64,
+}
+
+/// The summary of the spans of one name over an interval.
+pub(crate) struct SpanSummary {
+    pub(crate) name: &'static str,
+    pub(crate) count: u64,
+    pub(crate) p50_ms: f64,
+    pub(crate) p95_ms: f64,
+    pub(crate) error_rate: f64,
+}
+
+impl Default for SpanSummaries {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            pending: AtomicBool::new(false),
+            state: Mutex::new(SummaryState {
+                spans: HashMap::new(),
+                start: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl SpanSummaries {
+    /// How often summaries are emitted.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Account for a closed span.
+    pub(crate) fn record(&self, name: &'static str, duration: Duration, failed: bool) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = state.spans.entry(name).or_default();
+        stats.durations.push(duration);
+        stats.errors += u64::from(failed);
+        self.pending.store(true, Ordering::Relaxed);
+    }
+
+    /// The summaries of the spans closed during the interval, if it has elapsed, sorted by
+    /// span name.
+    pub(crate) fn take_summaries(&self) -> Option<Vec<SpanSummary>> {
+        if !self.pending.load(Ordering::Relaxed) {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.start.elapsed() < self.interval {
+            return None;
+        }
+        state.start = Instant::now();
+        self.pending.store(false, Ordering::Relaxed);
+
+        let mut summaries: Vec<_> = state
+            .spans
+            .drain()
+            .map(|(name, mut stats)| {
+                stats.durations.sort_unstable();
+                let count = stats.durations.len() as u64;
+                SpanSummary {
+                    name,
+                    count,
+                    p50_ms: percentile_ms(&stats.durations, 0.5),
+                    p95_ms: percentile_ms(&stats.durations, 0.95),
+                    error_rate: stats.errors as f64 / count as f64,
+                }
+            })
+            .collect();
+        summaries.sort_by_key(|summary| summary.name);
+        Some(summaries)
+    }
+}
+
+/// The nearest-rank percentile of sorted, non-empty `durations`, in milliseconds rounded
+/// to the microsecond.
+fn percentile_ms(durations: &[Duration], percentile: f64) -> f64 {
+    let rank = (percentile * durations.len() as f64).ceil() as usize;
+    let duration = durations[rank.clamp(1, durations.len()) - 1];
+    duration.as_micros() as f64 / 1000.0
+}
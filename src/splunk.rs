@@ -0,0 +1,161 @@
+use crate::export::{record_time, BatchConfig, HttpEndpoint, Worker};
+use crate::process;
+use serde_json::{Map, Value};
+use std::io::{self, Write};
+use std::sync::OnceLock;
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A [`MakeWriter`] posting records to a Splunk HTTP Event Collector.
+///
+/// Each record is wrapped in the HEC envelope, as the `event` of
+/// `{"time": ..., "host": ..., "sourcetype": ..., "event": {...}}`, and records are posted
+/// in batches from a background thread with the same queueing, retries and flushing as
+/// [`LokiMakeWriter`](crate::LokiMakeWriter).
+///
+/// Only `http://` endpoints are supported, reach Splunk over HTTPS through a local proxy.
+///
+/// ```rust
+/// use tracing_json_formatter::{JsonFormattingLayer, SplunkMakeWriter};
+///
+/// let make_writer = SplunkMakeWriter::new(
+///     "http://localhost:8088/services/collector/event",
+///     "00000000-0000-0000-0000-000000000000",
+/// )
+/// .unwrap()
+/// .with_sourcetype("my-app")
+/// .with_index("main");
+/// let layer = JsonFormattingLayer::new("my-app".into(), make_writer);
+/// ```
+pub struct SplunkMakeWriter {
+    endpoint: HttpEndpoint,
+    headers: Vec<(String, String)>,
+    envelope: Map<String, Value>,
+    batch: BatchConfig,
+    worker: OnceLock<Option<Worker<Vec<u8>>>>,
+}
+
+impl SplunkMakeWriter {
+    /// Post to `url`, the full URL of the event endpoint (`.../services/collector/event`),
+    /// authenticating with the HEC `token`.
+    pub fn new(url: &str, token: &str) -> io::Result<Self> {
+        let mut envelope = Map::new();
+        if let Some(hostname) = process::hostname() {
+            envelope.insert("host".to_owned(), Value::from(hostname));
+        }
+        envelope.insert("sourcetype".to_owned(), Value::from("_json"));
+        Ok(Self {
+            endpoint: HttpEndpoint::new(url)?,
+            headers: vec![("Authorization".to_owned(), format!("Splunk {}", token))],
+            envelope,
+            batch: BatchConfig::default(),
+            worker: OnceLock::new(),
+        })
+    }
+
+    /// Report events from this host rather than the name of the machine.
+    pub fn with_host(self, host: impl Into<String>) -> Self {
+        self.with_envelope_field("host", host.into())
+    }
+
+    /// Report events with this source type, `_json` by default.
+    pub fn with_sourcetype(self, sourcetype: impl Into<String>) -> Self {
+        self.with_envelope_field("sourcetype", sourcetype.into())
+    }
+
+    /// Report events with this source, left to the collector's default otherwise.
+    pub fn with_source(self, source: impl Into<String>) -> Self {
+        self.with_envelope_field("source", source.into())
+    }
+
+    /// Store events in this index, left to the collector's default otherwise.
+    pub fn with_index(self, index: impl Into<String>) -> Self {
+        self.with_envelope_field("index", index.into())
+    }
+
+    /// Batch, queue and retry records as configured by `batch`: by default, the writer
+    /// posts up to 500 records at once, at most a second after they were written.
+    pub fn with_batch(mut self, batch: BatchConfig) -> Self {
+        self.batch = batch;
+        self
+    }
+
+    fn with_envelope_field(mut self, key: &str, value: String) -> Self {
+        self.envelope.insert(key.to_owned(), Value::from(value));
+        self
+    }
+
+    fn worker(&self) -> Option<&Worker<Vec<u8>>> {
+        self.worker
+            .get_or_init(|| {
+                let endpoint = self.endpoint.clone();
+                let headers = self.headers.clone();
+                Worker::spawn(
+                    "splunk-exporter",
+                    self.batch.clone(),
+                    move |events: &[Vec<u8>]| {
+                        endpoint.post("application/json", &headers, &events.concat())
+                    },
+                )
+                .ok()
+            })
+            .as_ref()
+    }
+
+    /// The HEC envelope of a record, records that aren't JSON are sent as strings.
+    fn event(&self, record: &[u8]) -> Vec<u8> {
+        let record = String::from_utf8_lossy(record);
+        let record = record.trim_end();
+        let event = serde_json::from_str(record).unwrap_or_else(|_| Value::from(record));
+        let unix_millis = record_time(&event).unix_nanos().div_euclid(1_000_000);
+        let mut envelope = Map::with_capacity(self.envelope.len() + 2);
+        envelope.insert("time".to_owned(), Value::from(unix_millis as f64 / 1000.0));
+        envelope.extend(self.envelope.clone());
+        envelope.insert("event".to_owned(), event);
+        let mut event = serde_json::to_vec(&envelope).unwrap_or_default();
+        event.push(b'\n');
+        event
+    }
+}
+
+impl<'a> MakeWriter<'a> for SplunkMakeWriter {
+    type Writer = SplunkWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SplunkWriter {
+            make_writer: self,
+            level: None,
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        SplunkWriter {
+            make_writer: self,
+            level: Some(*meta.level()),
+        }
+    }
+}
+
+/// The writer returned by [`SplunkMakeWriter`]: each `write` call is queued as one event.
+pub struct SplunkWriter<'a> {
+    make_writer: &'a SplunkMakeWriter,
+    level: Option<Level>,
+}
+
+impl Write for SplunkWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let worker = self
+            .make_writer
+            .worker()
+            .ok_or_else(|| io::Error::other("the Splunk exporter thread couldn't be started"))?;
+        worker.push(self.make_writer.event(buf), self.level.as_ref())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.make_writer.worker.get() {
+            Some(Some(worker)) => worker.flush(),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::io::{self, StderrLock, StdoutLock, Write};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A [`MakeWriter`] for the standard output or error, writing each record atomically.
+///
+/// Passing `std::io::stdout` to the layer works but takes the stream lock for each `write`
+/// call: a record written in several calls (e.g. prefixed by
+/// [`SyslogMakeWriter`](crate::SyslogMakeWriter)) can then interleave with the output of
+/// other threads. The writers of `StdioMakeWriter` hold the lock until the record is
+/// written and flushed, and the standard output being line-buffered, a record reaches the
+/// file descriptor with a single `write` syscall.
+///
+/// ```rust
+/// use tracing_json_formatter::{JsonFormattingLayer, StdioMakeWriter};
+///
+/// let layer = JsonFormattingLayer::new("my-app".into(), StdioMakeWriter::stdout());
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StdioMakeWriter {
+    stderr: bool,
+}
+
+impl StdioMakeWriter {
+    pub fn stdout() -> Self {
+        Self { stderr: false }
+    }
+
+    pub fn stderr() -> Self {
+        Self { stderr: true }
+    }
+}
+
+impl<'a> MakeWriter<'a> for StdioMakeWriter {
+    type Writer = StdioWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        if self.stderr {
+            StdioWriter::Stderr(io::stderr().lock())
+        } else {
+            StdioWriter::Stdout(io::stdout().lock())
+        }
+    }
+}
+
+/// The writer returned by [`StdioMakeWriter`], holding the lock of the stream until dropped.
+pub enum StdioWriter {
+    Stdout(StdoutLock<'static>),
+    Stderr(StderrLock<'static>),
+}
+
+impl Write for StdioWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            StdioWriter::Stdout(lock) => lock.write(buf),
+            StdioWriter::Stderr(lock) => lock.write(buf),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            StdioWriter::Stdout(lock) => lock.write_all(buf),
+            StdioWriter::Stderr(lock) => lock.write_all(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            StdioWriter::Stdout(lock) => lock.flush(),
+            StdioWriter::Stderr(lock) => lock.flush(),
+        }
+    }
+}
+
+impl Drop for StdioWriter {
+    /// Write out a record left without a trailing newline before releasing the lock.
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
@@ -1,19 +1,250 @@
-use std::collections::HashMap;
+use crate::schema::fields;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::time::Instant;
-use tracing::field::{Field, Visit};
+use std::time::{Duration, Instant};
+use tracing::field::{Field, FieldSet, Visit};
 use tracing::span::{Attributes, Record};
-use tracing::{Id, Subscriber};
+use tracing::{Event, Id, Level, Span, Subscriber};
 use tracing_subscriber::layer::Context;
-use tracing_subscriber::Layer;
+use tracing_subscriber::registry::{ExtensionsMut, LookupSpan, SpanRef};
+use tracing_subscriber::{Layer, Registry};
 
 /// This layer is only concerned with information storage, it does not do any formatting or provide any output.
 ///
 /// It's purpose is to store the fields associated to spans in an easy-to-consume format
 /// for downstream layers concerned with emitting a formatted representation of
 /// spans or events.
-#[derive(Clone, Debug)]
-pub struct JsonStorageLayer;
+///
+/// Install it once, below all the formatting layers of the subscriber: they share the
+/// fields it stores instead of each collecting their own copy.
+///
+/// Spans with a `log_span = false` field don't get START and END records, their other
+/// fields are still inherited by the events and spans created inside them:
+///
+/// ```rust
+/// #[tracing::instrument(fields(log_span = false))]
+/// fn poll_queue() {
+///     tracing::debug!("Nothing to do");
+/// }
+/// ```
+///
+/// It can be configured with the `with_*` methods, starting from its default configuration:
+///
+/// ```rust
+/// use tracing_json_formatter::{DuplicatePolicy, JsonStorageLayer};
+///
+/// let storage_layer = JsonStorageLayer::new().with_duplicate_policy(DuplicatePolicy::Collect);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct JsonStorageLayer {
+    config: StorageConfig,
+}
+
+impl JsonStorageLayer {
+    /// The storage layer with its default configuration.
+    pub const fn new() -> Self {
+        Self {
+            config: StorageConfig::DEFAULT,
+        }
+    }
+
+    pub(crate) fn config(&self) -> StorageConfig {
+        self.config
+    }
+
+    /// What to do when a field is recorded several times on the same span, see [`DuplicatePolicy`].
+    pub fn with_duplicate_policy(mut self, duplicates: DuplicatePolicy) -> Self {
+        self.config.duplicates = duplicates;
+        self
+    }
+
+    /// Store at most `max_fields` fields per span or event, guarding against callsites
+    /// recording an unbounded number of fields.
+    ///
+    /// Fields beyond the limit are discarded and counted in a `_dropped_fields` field.
+    pub fn with_max_fields(mut self, max_fields: usize) -> Self {
+        self.config.max_fields = Some(max_fields);
+        self
+    }
+
+    /// How byte slice fields are encoded, see [`BytesEncoding`].
+    pub fn with_bytes_encoding(mut self, bytes: BytesEncoding) -> Self {
+        self.config.bytes = bytes;
+        self
+    }
+
+    /// Count the events emitted inside each span, its child spans included, and store the
+    /// counts when the span closes: `event_count` on END records, see [`EventCounts`].
+    ///
+    /// All the events reaching the subscriber are counted, whatever the level of the
+    /// formatting layers.
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::{EventCounts, JsonStorageLayer};
+    ///
+    /// // {..., "event_count": 12, "trace_count": 0, ..., "warn_count": 3, "error_count": 1}
+    /// let storage_layer = JsonStorageLayer::new().with_event_counts(EventCounts::ByLevel);
+    /// ```
+    pub fn with_event_counts(mut self, event_counts: EventCounts) -> Self {
+        self.config.event_counts = event_counts;
+        self
+    }
+
+    /// Store `null` for the fields declared without a value, e.g. with `field::Empty`, so
+    /// that records always carry the declared keys. The first value recorded with
+    /// `Span::record` replaces the `null`, whatever the duplicate policy.
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::JsonStorageLayer;
+    ///
+    /// let storage_layer = JsonStorageLayer::new().with_unset_fields_as_null(true);
+    ///
+    /// // {..., "user_id": null} until `span.record("user_id", 42)` is called.
+    /// let span = tracing::info_span!("request", user_id = tracing::field::Empty);
+    /// ```
+    pub fn with_unset_fields_as_null(mut self, unset_as_null: bool) -> Self {
+        self.config.unset_as_null = unset_as_null;
+        self
+    }
+
+    /// Store the fields recorded with `?value` whose `Debug` output is a number or a boolean
+    /// (`42`, `-1.5`, `true`, ...) as JSON numbers and booleans rather than as strings.
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::JsonStorageLayer;
+    ///
+    /// let storage_layer = JsonStorageLayer::new().with_typed_debug(true);
+    ///
+    /// // {..., "attempt": 3} rather than {..., "attempt": "3"}
+    /// # let attempt = std::num::NonZeroU32::new(3).unwrap();
+    /// tracing::info!(attempt = ?attempt, "Retrying");
+    /// ```
+    pub fn with_typed_debug(mut self, typed_debug: bool) -> Self {
+        self.config.typed_debug = typed_debug;
+        self
+    }
+
+    /// Store the fields recorded with `?value` from an `Option` as their content: `null`
+    /// for `None`, and `Some(x)` as `x`, so that optional fields are queryable in log
+    /// backends. Strings are unquoted, and with [`JsonStorageLayer::with_typed_debug`]
+    /// numbers and booleans are stored as such.
+    ///
+    /// The `Debug` output is all there is to go by: any value printed as `None` or
+    /// `Some(...)` is unwrapped, e.g. a variant of the same name of another enum.
+    ///
+    /// ```rust
+    /// use tracing_json_formatter::JsonStorageLayer;
+    ///
+    /// let storage_layer = JsonStorageLayer::new().with_unwrapped_options(true);
+    ///
+    /// // {..., "user": "alice", "team": null} rather than {..., "user": "Some(\"alice\")", "team": "None"}
+    /// # let (user, team): (Option<&str>, Option<&str>) = (Some("alice"), None);
+    /// tracing::info!(user = ?user, team = ?team, "Signed in");
+    /// ```
+    pub fn with_unwrapped_options(mut self, unwrap_options: bool) -> Self {
+        self.config.unwrap_options = unwrap_options;
+        self
+    }
+}
+
+/// What to do when a field that already has a value is recorded again, e.g. with
+/// repeated `Span::record` calls.
+///
+/// Fields inherited from a parent span are always overridden by the child span.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// The last recorded value wins.
+    #[default]
+    Overwrite,
+    /// The first recorded value wins.
+    KeepFirst,
+    /// All the recorded values are collected into a JSON array.
+    Collect,
+}
+
+/// The event counters stored on spans when they close, see
+/// [`JsonStorageLayer::with_event_counts`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EventCounts {
+    #[default]
+    Off,
+    /// The number of events, `event_count`.
+    Total,
+    /// `event_count`, and the number of events of each level: `trace_count`, `debug_count`,
+    /// `info_count`, `warn_count` and `error_count`.
+    ByLevel,
+}
+
+/// How byte slice fields (`&[u8]`) are stored, as a JSON string.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BytesEncoding {
+    /// Lowercase hexadecimal, e.g. `"00ff"`.
+    #[default]
+    Hex,
+    /// Standard base64 with padding, e.g. `"AP8="`.
+    Base64,
+}
+
+impl BytesEncoding {
+    fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            BytesEncoding::Hex => bytes.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            BytesEncoding::Base64 => {
+                const ALPHABET: &[u8; 64] =
+                    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+                let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+                for chunk in bytes.chunks(3) {
+                    let n = chunk
+                        .iter()
+                        .enumerate()
+                        .fold(0u32, |n, (i, &byte)| n | u32::from(byte) << (16 - 8 * i));
+                    for i in 0..4 {
+                        if i <= chunk.len() {
+                            encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+                        } else {
+                            encoded.push('=');
+                        }
+                    }
+                }
+                encoded
+            }
+        }
+    }
+}
+
+/// How fields are stored, shared by the storage layer and the `JsonStorage` it creates.
+///
+/// Formatting layers pick it up from the subscriber (see `Layer::on_register_dispatch`) to
+/// store event fields the same way.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct StorageConfig {
+    duplicates: DuplicatePolicy,
+    bytes: BytesEncoding,
+    max_fields: Option<usize>,
+    unset_as_null: bool,
+    event_counts: EventCounts,
+    typed_debug: bool,
+    unwrap_options: bool,
+}
+
+impl StorageConfig {
+    pub(crate) const DEFAULT: Self = Self {
+        duplicates: DuplicatePolicy::Overwrite,
+        bytes: BytesEncoding::Hex,
+        max_fields: None,
+        unset_as_null: false,
+        event_counts: EventCounts::Off,
+        typed_debug: false,
+        unwrap_options: false,
+    };
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
 
 /// `JsonStorage` will collect information about a span when it's created (`new_span` handler)
 /// or when new records are attached to it (`on_record` handler) and store it in its `extensions`
@@ -25,24 +256,170 @@ pub struct JsonStorageLayer;
 ///
 /// For spans, we also store the duration of each span with the `elapsed_milliseconds` key using
 /// the `on_exit`/`on_enter` handlers.
+///
+/// Fields of a span include those inherited from its parents. Other layers registered after
+/// [`JsonStorageLayer`] can read them from the span extensions, e.g. to propagate a
+/// `request_id`:
+///
+/// ```rust
+/// use tracing::{Event, Subscriber};
+/// use tracing_json_formatter::JsonStorage;
+/// use tracing_subscriber::layer::{Context, Layer};
+/// use tracing_subscriber::registry::LookupSpan;
+///
+/// struct RequestIdLayer;
+///
+/// impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for RequestIdLayer {
+///     fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+///         let Some(span) = ctx.event_span(event) else {
+///             return;
+///         };
+///         let extensions = span.extensions();
+///         let request_id = extensions
+///             .get::<JsonStorage>()
+///             .and_then(|storage| storage.get("request_id"))
+///             .and_then(|value| value.as_str());
+///         if let Some(request_id) = request_id {
+///             // ...
+///         }
+///     }
+/// }
+/// ```
 #[derive(Clone, Debug)]
 pub struct JsonStorage<'a> {
     values: HashMap<&'a str, serde_json::Value>,
+    config: StorageConfig,
+    /// Keys copied from the parent span that haven't been recorded on this span yet,
+    /// only tracked when the duplicate policy isn't `Overwrite`.
+    inherited: HashSet<&'a str>,
+    /// The number of fields discarded because of the configured maximum field count.
+    dropped: u64,
 }
 
+const DROPPED_FIELDS: &str = "_dropped_fields";
+
+/// The field marking a span as quiet when set to `false`, see [`QuietSpan`].
+const LOG_SPAN: &str = "log_span";
+
 impl<'a> JsonStorage<'a> {
+    /// Get a new visitor with room for `capacity` fields, avoiding rehashing while
+    /// a span or event with a known set of fields is recorded.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            values: HashMap::with_capacity(capacity),
+            config: StorageConfig::DEFAULT,
+            inherited: HashSet::new(),
+            dropped: 0,
+        }
+    }
+
+    pub(crate) fn with_config(capacity: usize, config: StorageConfig) -> Self {
+        let mut storage = Self::with_capacity(capacity);
+        storage.config = config;
+        storage
+    }
+
+    /// Store a value, applying the duplicate policy if the key already has one.
+    fn insert(&mut self, key: &'a str, value: serde_json::Value) {
+        self.insert_with(key, || value);
+    }
+
+    /// Store the value built by `value`, applying the duplicate policy if the key already
+    /// has one. Values that would be discarded (over the field limit, or duplicates kept
+    /// out by the policy) are never built, sparing the allocation of strings.
+    fn insert_with(&mut self, key: &'a str, value: impl FnOnce() -> serde_json::Value) {
+        if let Some(max_fields) = self.config.max_fields {
+            if self.values.len() - usize::from(self.dropped > 0) >= max_fields
+                && !self.values.contains_key(key)
+            {
+                self.dropped += 1;
+                self.values
+                    .insert(DROPPED_FIELDS, serde_json::Value::from(self.dropped));
+                return;
+            }
+        }
+        if self.config.duplicates == DuplicatePolicy::Overwrite || self.inherited.remove(key) {
+            self.values.insert(key, value());
+            return;
+        }
+        match self.values.entry(key) {
+            Entry::Vacant(entry) => {
+                entry.insert(value());
+            }
+            Entry::Occupied(mut entry) => match (self.config.duplicates, entry.get_mut()) {
+                (DuplicatePolicy::Collect, serde_json::Value::Array(values)) => {
+                    values.push(value())
+                }
+                (DuplicatePolicy::Collect, existing) => {
+                    let first = existing.take();
+                    *existing = serde_json::Value::Array(vec![first, value()]);
+                }
+                _ => {}
+            },
+        }
+    }
+
+    /// Store `null` for the fields of `fields` that weren't recorded, when configured to, see
+    /// [`JsonStorageLayer::with_unset_fields_as_null`]. The next value recorded for them
+    /// replaces the `null`.
+    pub(crate) fn record_unset(&mut self, fields: &FieldSet) {
+        if !self.config.unset_as_null {
+            return;
+        }
+        for field in fields {
+            let name = field.name();
+            let name = name.strip_prefix("r#").unwrap_or(name);
+            if name == LOG_SPAN || name.starts_with("log.") || self.values.contains_key(name) {
+                continue;
+            }
+            self.insert(name, serde_json::Value::Null);
+            if self.config.duplicates != DuplicatePolicy::Overwrite {
+                self.inherited.insert(name);
+            }
+        }
+    }
+
     /// Get the set of stored values, as a set of keys and JSON values.
     pub fn values(&self) -> &HashMap<&'a str, serde_json::Value> {
         &self.values
     }
+
+    /// Remove the `log_span` sentinel field, returning its value if it was recorded as a
+    /// boolean.
+    fn take_log_span(&mut self) -> Option<bool> {
+        self.values.remove(LOG_SPAN)?.as_bool()
+    }
+
+    /// Store a value regardless of the duplicate policy, for fields that don't come from
+    /// `tracing` callsites.
+    pub(crate) fn set(&mut self, key: &'a str, value: serde_json::Value) {
+        self.values.insert(key, value);
+    }
+
+    /// The value stored for `key`, if any.
+    pub fn get(&self, key: &str) -> Option<&serde_json::Value> {
+        self.values.get(key)
+    }
+
+    /// Iterate over the stored keys and values, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, &serde_json::Value)> + '_ {
+        self.values.iter().map(|(key, value)| (*key, value))
+    }
+
+    /// The number of stored fields.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
 }
 
 /// Get a new visitor, with an empty bag of key-value pairs.
 impl Default for JsonStorage<'_> {
     fn default() -> Self {
-        Self {
-            values: HashMap::new(),
-        }
+        Self::with_capacity(0)
     }
 }
 
@@ -50,32 +427,51 @@ impl Default for JsonStorage<'_> {
 impl Visit for JsonStorage<'_> {
     /// Visit a signed 64-bit integer value.
     fn record_i64(&mut self, field: &Field, value: i64) {
-        self.values
-            .insert(field.name(), serde_json::Value::from(value));
+        self.insert(field.name(), serde_json::Value::from(value));
     }
 
     /// Visit an unsigned 64-bit integer value.
     fn record_u64(&mut self, field: &Field, value: u64) {
-        self.values
-            .insert(field.name(), serde_json::Value::from(value));
+        self.insert(field.name(), serde_json::Value::from(value));
+    }
+
+    /// Visit a signed 128-bit integer value, as a string when it doesn't fit in 64 bits.
+    fn record_i128(&mut self, field: &Field, value: i128) {
+        let value = i64::try_from(value)
+            .map(serde_json::Value::from)
+            .unwrap_or_else(|_| serde_json::Value::from(value.to_string()));
+        self.insert(field.name(), value);
+    }
+
+    /// Visit an unsigned 128-bit integer value, as a string when it doesn't fit in 64 bits.
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        let value = u64::try_from(value)
+            .map(serde_json::Value::from)
+            .unwrap_or_else(|_| serde_json::Value::from(value.to_string()));
+        self.insert(field.name(), value);
+    }
+
+    /// Visit a byte slice, encoded as configured with [`JsonStorageLayer::with_bytes_encoding`].
+    fn record_bytes(&mut self, field: &Field, value: &[u8]) {
+        let bytes = self.config.bytes;
+        self.insert_with(field.name(), || {
+            serde_json::Value::from(bytes.encode(value))
+        });
     }
 
     /// Visit a 64-bit floating point value.
     fn record_f64(&mut self, field: &Field, value: f64) {
-        self.values
-            .insert(field.name(), serde_json::Value::from(value));
+        self.insert(field.name(), serde_json::Value::from(value));
     }
 
     /// Visit a boolean value.
     fn record_bool(&mut self, field: &Field, value: bool) {
-        self.values
-            .insert(field.name(), serde_json::Value::from(value));
+        self.insert(field.name(), serde_json::Value::from(value));
     }
 
     /// Visit a string value.
     fn record_str(&mut self, field: &Field, value: &str) {
-        self.values
-            .insert(field.name(), serde_json::Value::from(value));
+        self.insert_with(field.name(), || serde_json::Value::from(value));
     }
 
     fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
@@ -83,17 +479,205 @@ impl Visit for JsonStorage<'_> {
             // Skip fields that are actually log metadata that have already been handled
             name if name.starts_with("log.") => (),
             name if name.starts_with("r#") => {
-                self.values
-                    .insert(&name[2..], serde_json::Value::from(format!("{:?}", value)));
+                let config = self.config;
+                self.insert_with(&name[2..], || debug_value(value, config));
             }
             name => {
-                self.values
-                    .insert(name, serde_json::Value::from(format!("{:?}", value)));
+                let config = self.config;
+                self.insert_with(name, || debug_value(value, config));
             }
         };
     }
 }
 
+/// The `Debug` output of `value`, typed and unwrapped as configured.
+fn debug_value(value: &dyn fmt::Debug, config: StorageConfig) -> serde_json::Value {
+    parse_debug(&format!("{:?}", value), config)
+}
+
+/// A `Debug` output, unwrapped when it reads as an `Option`, as a number or a boolean when
+/// it reads as one, as a string otherwise.
+fn parse_debug(debug: &str, config: StorageConfig) -> serde_json::Value {
+    if config.unwrap_options {
+        if debug == "None" {
+            return serde_json::Value::Null;
+        }
+        if let Some(inner) = debug
+            .strip_prefix("Some(")
+            .and_then(|debug| debug.strip_suffix(')'))
+        {
+            if let Ok(string @ serde_json::Value::String(_)) = serde_json::from_str(inner) {
+                return string;
+            }
+            return parse_debug(inner, config);
+        }
+    }
+    if config.typed_debug {
+        if let Ok(parsed @ (serde_json::Value::Number(_) | serde_json::Value::Bool(_))) =
+            serde_json::from_str(debug)
+        {
+            return parsed;
+        }
+    }
+    serde_json::Value::from(debug)
+}
+
+/// Run `f` on the storage of `span`, invalidating its serialized fields.
+///
+/// Returns `false` when the span isn't recorded by a `Registry` with a [`JsonStorageLayer`].
+pub(crate) fn with_span_storage(span: &Span, f: impl FnOnce(&mut JsonStorage<'static>)) -> bool {
+    with_span_extensions(span, |extensions| {
+        let Some(storage) = extensions.get_mut::<JsonStorage>() else {
+            return false;
+        };
+        f(storage);
+        extensions.remove::<SerializedSpanFields>();
+        true
+    })
+}
+
+/// Run `f` on the storage of `span`, leaving its serialized fields in place.
+///
+/// Returns `false` when the span isn't recorded by a `Registry` with a [`JsonStorageLayer`].
+pub(crate) fn read_span_storage(span: &Span, f: impl FnOnce(&JsonStorage<'static>)) -> bool {
+    with_registry_span(span, |span| {
+        let extensions = span.extensions();
+        let Some(storage) = extensions.get::<JsonStorage>() else {
+            return false;
+        };
+        f(storage);
+        true
+    })
+}
+
+/// Run `f` on the extensions of `span`, `false` when it isn't recorded by a `Registry`.
+pub(crate) fn with_span_extensions(
+    span: &Span,
+    f: impl FnOnce(&mut ExtensionsMut<'_>) -> bool,
+) -> bool {
+    with_registry_span(span, |span| f(&mut span.extensions_mut()))
+}
+
+fn with_registry_span(span: &Span, f: impl FnOnce(SpanRef<'_, Registry>) -> bool) -> bool {
+    span.with_subscriber(|(id, dispatch)| {
+        dispatch
+            .downcast_ref::<Registry>()
+            .and_then(|registry| registry.span(id))
+            .is_some_and(f)
+    })
+    .unwrap_or(false)
+}
+
+/// Time spent inside (busy) and outside (idle) a span between its creation and its closure,
+/// accumulated across all the times it was entered and exited.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Timings {
+    pub(crate) busy: Duration,
+    pub(crate) idle: Duration,
+    last: Instant,
+}
+
+impl Timings {
+    fn new() -> Self {
+        Self {
+            busy: Duration::ZERO,
+            idle: Duration::ZERO,
+            last: Instant::now(),
+        }
+    }
+
+    fn enter(&mut self) {
+        let now = Instant::now();
+        self.idle += now - self.last;
+        self.last = now;
+    }
+
+    fn exit(&mut self) {
+        let now = Instant::now();
+        self.busy += now - self.last;
+        self.last = now;
+    }
+}
+
+/// The events emitted inside a span, by level from `TRACE` to `ERROR`, see
+/// [`JsonStorageLayer::with_event_counts`].
+#[derive(Clone, Copy, Debug, Default)]
+struct EventCounter([u64; 5]);
+
+impl EventCounter {
+    const FIELDS: [&'static str; 5] = [
+        fields::TRACE_COUNT,
+        fields::DEBUG_COUNT,
+        fields::INFO_COUNT,
+        fields::WARN_COUNT,
+        fields::ERROR_COUNT,
+    ];
+
+    fn count(&mut self, level: &Level) {
+        let index = match *level {
+            Level::TRACE => 0,
+            Level::DEBUG => 1,
+            Level::INFO => 2,
+            Level::WARN => 3,
+            Level::ERROR => 4,
+        };
+        self.0[index] += 1;
+    }
+
+    fn store(&self, event_counts: EventCounts, storage: &mut JsonStorage<'_>) {
+        let total: u64 = self.0.iter().sum();
+        storage.set(fields::EVENT_COUNT, serde_json::Value::from(total));
+        if event_counts == EventCounts::ByLevel {
+            for (key, count) in Self::FIELDS.into_iter().zip(self.0) {
+                storage.set(key, serde_json::Value::from(count));
+            }
+        }
+    }
+}
+
+/// Marks the spans created by [`child_span`](crate::child_span), which only bind fields:
+/// formatting layers leave them out of messages and span breadcrumbs.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ChildSpan;
+
+/// Marks a span created or recorded with `log_span = false`: formatting layers skip its
+/// START and END records, its fields are still inherited by child spans and events.
+///
+/// The `log_span` field itself is never stored.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct QuietSpan;
+
+/// The fields recorded on a span with `Span::record` after its creation, merged over its
+/// lifetime, stored next to [`JsonStorage`] once the first one is recorded.
+#[derive(Clone, Debug)]
+pub(crate) struct RecordedFields(pub(crate) JsonStorage<'static>);
+
+/// The fields of a span, serialized as the comma-separated `"key":value` pairs of a JSON object.
+///
+/// Stored in the span extensions next to [`JsonStorage`] and removed whenever the span
+/// fields change, so that formatting layers only serialize them once.
+#[derive(Clone, Debug)]
+pub(crate) struct SerializedSpanFields(Vec<u8>);
+
+impl SerializedSpanFields {
+    pub(crate) fn new(storage: &JsonStorage<'_>) -> serde_json::Result<Self> {
+        let mut bytes = Vec::new();
+        for (key, value) in storage.values() {
+            if !bytes.is_empty() {
+                bytes.push(b',');
+            }
+            serde_json::to_writer(&mut bytes, key)?;
+            bytes.push(b':');
+            serde_json::to_writer(&mut bytes, value)?;
+        }
+        Ok(Self(bytes))
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>> Layer<S>
     for JsonStorageLayer
 {
@@ -116,14 +700,44 @@ impl<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>> Layer
         } else {
             JsonStorage::default()
         };
+        visitor.config = self.config;
+        visitor.inherited.clear();
+        if self.config.duplicates != DuplicatePolicy::Overwrite {
+            visitor.inherited.extend(visitor.values.keys());
+        }
+        // Fields on the new span are known upfront, make room for them once.
+        visitor.values.reserve(attrs.fields().len());
 
         let mut extensions = span.extensions_mut();
 
         // Register all fields.
         // Fields on the new span should override fields on the parent span if there is a conflict.
         attrs.record(&mut visitor);
+        visitor.record_unset(attrs.metadata().fields());
+        if visitor.take_log_span() == Some(false) {
+            extensions.insert(QuietSpan);
+        }
         // Associate the visitor with the Span for future usage via the Span's extensions
         extensions.insert(visitor);
+        extensions.insert(Timings::new());
+        if self.config.event_counts != EventCounts::Off {
+            extensions.insert(EventCounter::default());
+        }
+    }
+
+    /// Count the event in the spans it belongs to, when configured to.
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if self.config.event_counts == EventCounts::Off {
+            return;
+        }
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+        for span in span.scope() {
+            if let Some(counter) = span.extensions_mut().get_mut::<EventCounter>() {
+                counter.count(event.metadata().level());
+            }
+        }
     }
 
     fn on_record(&self, span: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
@@ -138,6 +752,48 @@ impl<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>> Layer
             .expect("Visitor not found on 'record', this is a bug");
         // Register all new fields
         values.record(visitor);
+        let log_span = visitor.take_log_span();
+        extensions.remove::<SerializedSpanFields>();
+        match extensions.get_mut::<RecordedFields>() {
+            Some(RecordedFields(recorded)) => {
+                values.record(recorded);
+                recorded.take_log_span();
+            }
+            None => {
+                let mut recorded = JsonStorage::with_config(values.len(), self.config);
+                values.record(&mut recorded);
+                recorded.take_log_span();
+                extensions.insert(RecordedFields(recorded));
+            }
+        }
+        match log_span {
+            Some(false) => extensions.insert(QuietSpan),
+            Some(true) => {
+                extensions.remove::<QuietSpan>();
+            }
+            None => {}
+        }
+    }
+
+    /// Store the IDs of the spans a span follows from under the `follows_from` key, so that
+    /// causal links between detached tasks show up in logs.
+    fn on_follows_from(&self, span: &Id, follows: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(span).expect("Span not found, this is a bug");
+
+        let mut extensions = span.extensions_mut();
+        let visitor = extensions
+            .get_mut::<JsonStorage>()
+            .expect("Visitor not found on 'follows_from', this is a bug");
+        let follows = serde_json::Value::from(follows.into_u64().to_string());
+        match visitor.values.get_mut("follows_from") {
+            Some(serde_json::Value::Array(ids)) => ids.push(follows),
+            _ => {
+                visitor
+                    .values
+                    .insert("follows_from", serde_json::Value::Array(vec![follows]));
+            }
+        }
+        extensions.remove::<SerializedSpanFields>();
     }
 
     /// When we enter a span **for the first time** save the timestamp in its extensions.
@@ -148,6 +804,19 @@ impl<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>> Layer
         if extensions.get_mut::<Instant>().is_none() {
             extensions.insert(Instant::now());
         }
+        if let Some(timings) = extensions.get_mut::<Timings>() {
+            timings.enter();
+        }
+    }
+
+    /// Every time we exit a span, account for the time spent inside it.
+    fn on_exit(&self, span: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(span).expect("Span not found, this is a bug");
+
+        let mut extensions = span.extensions_mut();
+        if let Some(timings) = extensions.get_mut::<Timings>() {
+            timings.exit();
+        }
     }
 
     /// When we close a span, register how long it took in milliseconds.
@@ -176,6 +845,10 @@ impl<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>> Layer
         };
 
         let mut extensions_mut = span.extensions_mut();
+        // The time since the last exit is idle time as well.
+        if let Some(timings) = extensions_mut.get_mut::<Timings>() {
+            timings.enter();
+        }
         let visitor = extensions_mut
             .get_mut::<JsonStorage>()
             .expect("Visitor not found on 'record', this is a bug");
@@ -183,5 +856,36 @@ impl<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>> Layer
         if let Ok(elapsed) = serde_json::to_value(elapsed_milliseconds) {
             visitor.values.insert("elapsed_milliseconds", elapsed);
         }
+        if let Some(counter) = extensions_mut.get_mut::<EventCounter>().copied() {
+            let visitor = extensions_mut
+                .get_mut::<JsonStorage>()
+                .expect("Visitor not found on 'record', this is a bug");
+            counter.store(self.config.event_counts, visitor);
+        }
+        extensions_mut.remove::<SerializedSpanFields>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_match_the_rfc_4648_test_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (bytes, base64) in vectors {
+            assert_eq!(BytesEncoding::Base64.encode(bytes.as_bytes()), base64);
+        }
+        assert_eq!(BytesEncoding::Base64.encode(&[0xfb, 0xff, 0xbf]), "+/+/");
+        assert_eq!(BytesEncoding::Hex.encode(b"foobar"), "666f6f626172");
+        assert_eq!(BytesEncoding::Hex.encode(&[0x00, 0xff]), "00ff");
     }
 }
@@ -0,0 +1,335 @@
+use crate::process;
+use crate::timestamp;
+use std::io::{self, Write};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Syslog facilities, as defined in RFC 5424 section 6.2.1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Facility {
+    Kern = 0,
+    User = 1,
+    Mail = 2,
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Lpr = 6,
+    News = 7,
+    Uucp = 8,
+    Cron = 9,
+    AuthPriv = 10,
+    Ftp = 11,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+/// Map a `tracing` level to a syslog severity.
+///
+/// `tracing` has no equivalent of the `emerg`, `alert`, `crit` and `notice` severities,
+/// hence `DEBUG` and `TRACE` both map to `debug` (7).
+pub fn syslog_severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+/// The static part of a RFC 5424 header, shared by all the writers handed out
+/// by a [`SyslogMakeWriter`].
+#[derive(Clone, Debug)]
+struct SyslogHeader {
+    facility: Facility,
+    hostname: String,
+    app_name: String,
+    proc_id: String,
+    msg_id: String,
+    octet_counting: bool,
+}
+
+impl SyslogHeader {
+    fn pri(&self, severity: u8) -> u8 {
+        (self.facility as u8) * 8 + severity
+    }
+
+    /// Frame `msg`, a record without its trailing newline.
+    fn frame(&self, severity: u8, msg: &[u8]) -> Vec<u8> {
+        let header = format!(
+            "<{}>1 {} {} {} {} {} - ",
+            self.pri(severity),
+            timestamp::local(&timestamp::now()).rfc3339(6),
+            self.hostname,
+            self.app_name,
+            self.proc_id,
+            self.msg_id,
+        );
+
+        let mut frame = Vec::with_capacity(header.len() + msg.len() + 8);
+        if self.octet_counting {
+            // RFC 6587 section 3.4.1: `MSG-LEN SP SYSLOG-MSG`
+            frame.extend_from_slice(format!("{} ", header.len() + msg.len()).as_bytes());
+        }
+        frame.extend_from_slice(header.as_bytes());
+        frame.extend_from_slice(msg);
+        if !self.octet_counting {
+            // RFC 6587 section 3.4.2: frames end with a newline.
+            frame.push(b'\n');
+        }
+        frame
+    }
+}
+
+/// A [`MakeWriter`] wrapper that frames every record as a RFC 5424 syslog message
+/// before handing it over to the underlying writer.
+///
+/// The `PRI` part of the header is computed from the facility and the level of
+/// the record being written, the JSON record itself, without its trailing newline, is
+/// used as `MSG`.
+///
+/// ```rust,no_run
+/// use tracing_json_formatter::{Facility, JsonFormattingLayer, SyslogMakeWriter};
+/// use std::net::TcpStream;
+/// use std::sync::Arc;
+///
+/// let stream = TcpStream::connect("127.0.0.1:601").unwrap();
+/// let make_writer = SyslogMakeWriter::new(Arc::new(stream), "my-app")
+///     .with_facility(Facility::Local0)
+///     .with_octet_counting(true);
+/// let layer = JsonFormattingLayer::new("my-app".into(), make_writer);
+/// ```
+#[derive(Clone, Debug)]
+pub struct SyslogMakeWriter<W> {
+    inner: W,
+    header: SyslogHeader,
+}
+
+impl<W: for<'a> MakeWriter<'a>> SyslogMakeWriter<W> {
+    pub fn new(inner: W, app_name: impl Into<String>) -> Self {
+        Self {
+            inner,
+            header: SyslogHeader {
+                facility: Facility::User,
+                hostname: header_field(&process::hostname().unwrap_or_default(), 255),
+                app_name: header_field(&app_name.into(), 48),
+                proc_id: process::pid().map_or_else(|| "-".to_string(), |pid| pid.to_string()),
+                msg_id: "-".to_string(),
+                octet_counting: false,
+            },
+        }
+    }
+
+    /// Set the facility used to compute `PRI`, `user` by default.
+    pub fn with_facility(mut self, facility: Facility) -> Self {
+        self.header.facility = facility;
+        self
+    }
+
+    /// Override the `HOSTNAME` header field, the machine hostname by default.
+    pub fn with_hostname(mut self, hostname: impl AsRef<str>) -> Self {
+        self.header.hostname = header_field(hostname.as_ref(), 255);
+        self
+    }
+
+    /// Set the `MSGID` header field, empty (`-`) by default.
+    pub fn with_msg_id(mut self, msg_id: impl AsRef<str>) -> Self {
+        self.header.msg_id = header_field(msg_id.as_ref(), 32);
+        self
+    }
+
+    /// Prefix every frame with its length (RFC 6587 octet counting), as expected by
+    /// syslog daemons listening on a TCP socket.
+    pub fn with_octet_counting(mut self, octet_counting: bool) -> Self {
+        self.header.octet_counting = octet_counting;
+        self
+    }
+}
+
+impl<'a, W: MakeWriter<'a>> MakeWriter<'a> for SyslogMakeWriter<W> {
+    type Writer = SyslogWriter<'a, W::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogWriter {
+            inner: self.inner.make_writer(),
+            header: &self.header,
+            severity: syslog_severity(&Level::INFO),
+            record: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        SyslogWriter {
+            inner: self.inner.make_writer_for(meta),
+            header: &self.header,
+            severity: syslog_severity(meta.level()),
+            record: Vec::new(),
+        }
+    }
+}
+
+/// The writer returned by [`SyslogMakeWriter`]: the record written to it is sent to the
+/// underlying writer as a single syslog frame when it's flushed or dropped.
+///
+/// Errors writing the frame on drop are lost, flush the writer to see them.
+#[derive(Debug)]
+pub struct SyslogWriter<'a, W: Write> {
+    inner: W,
+    header: &'a SyslogHeader,
+    severity: u8,
+    record: Vec<u8>,
+}
+
+impl<W: Write> SyslogWriter<'_, W> {
+    fn send(&mut self) -> io::Result<()> {
+        if self.record.is_empty() {
+            return Ok(());
+        }
+        let msg = self.record.strip_suffix(b"\n").unwrap_or(&self.record);
+        let frame = self.header.frame(self.severity, msg);
+        self.record.clear();
+        self.inner.write_all(&frame)
+    }
+}
+
+impl<W: Write> Write for SyslogWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.record.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for SyslogWriter<'_, W> {
+    fn drop(&mut self) {
+        let _ = self.send();
+    }
+}
+
+/// Header fields must be non-empty printable US-ASCII, without spaces, and are
+/// length-limited by the RFC.
+fn header_field(value: &str, max_len: usize) -> String {
+    let field: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max_len)
+        .collect();
+    if field.is_empty() {
+        "-".to_string()
+    } else {
+        field
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::CapturingMakeWriter;
+    use crate::{JsonFormattingLayer, JsonStorageLayer};
+    use tracing_subscriber::prelude::*;
+
+    fn syslog(output: &CapturingMakeWriter) -> SyslogMakeWriter<CapturingMakeWriter> {
+        SyslogMakeWriter::new(output.clone(), "app")
+            .with_facility(Facility::Local0)
+            .with_hostname("host")
+    }
+
+    /// The frames of two records, an error and an info.
+    fn frames(make_writer: SyslogMakeWriter<CapturingMakeWriter>) {
+        let layer = JsonFormattingLayer::new("app".into(), make_writer);
+        let subscriber = tracing_subscriber::registry()
+            .with(JsonStorageLayer::new())
+            .with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::error!("Disk full");
+            tracing::info!("Retrying");
+        });
+    }
+
+    #[test]
+    fn pri_combines_facility_and_severity() {
+        let header = |facility| {
+            SyslogMakeWriter::new(CapturingMakeWriter::default(), "app")
+                .with_facility(facility)
+                .header
+        };
+
+        assert_eq!(
+            header(Facility::Kern).pri(syslog_severity(&Level::ERROR)),
+            3
+        );
+        assert_eq!(
+            header(Facility::User).pri(syslog_severity(&Level::WARN)),
+            12
+        );
+        assert_eq!(
+            header(Facility::Local0).pri(syslog_severity(&Level::INFO)),
+            134
+        );
+        assert_eq!(
+            header(Facility::Local7).pri(syslog_severity(&Level::TRACE)),
+            191
+        );
+    }
+
+    #[test]
+    fn records_are_framed_with_octet_counting() {
+        let output = CapturingMakeWriter::default();
+        frames(syslog(&output).with_octet_counting(true));
+
+        let contents = output.contents();
+        let mut rest = contents.as_str();
+        let mut messages = Vec::new();
+        while !rest.is_empty() {
+            let (len, frame) = rest.split_once(' ').expect("a MSG-LEN");
+            let len: usize = len.parse().unwrap();
+            messages.push(&frame[..len]);
+            rest = &frame[len..];
+        }
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].starts_with("<131>1 "));
+        assert!(messages[1].starts_with("<134>1 "));
+        for message in messages {
+            assert!(message.contains(" host app "));
+            assert!(message.ends_with('}'), "{:?} ends with the record", message);
+        }
+    }
+
+    #[test]
+    fn records_are_framed_with_a_trailing_newline_without_octet_counting() {
+        let output = CapturingMakeWriter::default();
+        frames(syslog(&output));
+
+        let contents = output.contents();
+        let frames: Vec<_> = contents.lines().collect();
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].starts_with("<131>1 ") && frames[0].ends_with('}'));
+        assert!(frames[1].starts_with("<134>1 ") && frames[1].ends_with('}'));
+        assert!(contents.ends_with("}\n"));
+    }
+
+    #[test]
+    fn a_record_written_in_pieces_makes_a_single_frame() {
+        let output = CapturingMakeWriter::default();
+        let make_writer = syslog(&output).with_octet_counting(true);
+        {
+            let mut writer = make_writer.make_writer();
+            writer.write_all(b"{\"msg\":").unwrap();
+            writer.write_all(b"\"hi\"}\n").unwrap();
+        }
+
+        let contents = output.contents();
+        let (len, frame) = contents.split_once(' ').unwrap();
+        assert_eq!(len.parse::<usize>().unwrap(), frame.len());
+        assert!(frame.starts_with("<134>1 ") && frame.ends_with(" - {\"msg\":\"hi\"}"));
+    }
+}
@@ -0,0 +1,188 @@
+use std::io::{self, Write};
+use tracing::level_filters::LevelFilter;
+use tracing::Metadata;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A [`MakeWriter`] duplicating every record to several destinations.
+///
+/// Destinations are isolated from one another: a failing destination doesn't prevent
+/// the record from reaching the others, and an error is only reported when the record
+/// couldn't be written anywhere.
+///
+/// ```rust
+/// use tracing_json_formatter::{JsonFormattingLayer, TeeMakeWriter};
+///
+/// let make_writer = TeeMakeWriter::default()
+///     .with_writer(std::io::stdout)
+///     .with_writer(std::io::stderr);
+/// let layer = JsonFormattingLayer::new("my-app".into(), make_writer);
+/// ```
+#[derive(Default)]
+pub struct TeeMakeWriter {
+    writers: Vec<(BoxMakeWriter, LevelFilter)>,
+}
+
+impl TeeMakeWriter {
+    /// Add a destination.
+    pub fn with_writer<M>(self, make_writer: M) -> Self
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        self.with_writer_at_level(make_writer, LevelFilter::TRACE)
+    }
+
+    /// Add a destination receiving only the records at `level` or more severe, e.g. the
+    /// `DEBUG` records to a file but only the `INFO` ones to stdout:
+    ///
+    /// ```rust
+    /// use tracing::Level;
+    /// use tracing_json_formatter::TeeMakeWriter;
+    ///
+    /// # let file = std::io::sink;
+    /// let make_writer = TeeMakeWriter::default()
+    ///     .with_writer_at_level(std::io::stdout, Level::INFO)
+    ///     .with_writer_at_level(file, Level::DEBUG);
+    /// ```
+    ///
+    /// Records are only known by their level when the layer asks for a writer with
+    /// `MakeWriter::make_writer_for`, as [`JsonFormattingLayer`](crate::JsonFormattingLayer)
+    /// does.
+    pub fn with_writer_at_level<M>(mut self, make_writer: M, level: impl Into<LevelFilter>) -> Self
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        self.writers
+            .push((BoxMakeWriter::new(make_writer), level.into()));
+        self
+    }
+}
+
+impl<'a> MakeWriter<'a> for TeeMakeWriter {
+    type Writer = TeeWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        TeeWriter {
+            writers: self.writers.iter().map(|(w, _)| w.make_writer()).collect(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        TeeWriter {
+            writers: self
+                .writers
+                .iter()
+                .filter(|(_, level)| meta.level() <= level)
+                .map(|(w, _)| w.make_writer_for(meta))
+                .collect(),
+        }
+    }
+}
+
+/// The writer returned by [`TeeMakeWriter`].
+pub struct TeeWriter<'a> {
+    writers: Vec<Box<dyn Write + 'a>>,
+}
+
+impl TeeWriter<'_> {
+    /// Run `f` against every destination, failing only if all destinations failed.
+    fn for_each<F>(&mut self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&mut dyn Write) -> io::Result<()>,
+    {
+        let mut error = None;
+        let mut succeeded = self.writers.is_empty();
+        for writer in self.writers.iter_mut() {
+            match f(writer.as_mut()) {
+                Ok(()) => succeeded = true,
+                Err(e) => error = Some(e),
+            }
+        }
+        match error {
+            Some(e) if !succeeded => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Write for TeeWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Each destination gets the whole buffer, partial writes would
+        // otherwise desynchronize them.
+        self.for_each(|writer| writer.write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.for_each(|writer| writer.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::CapturingMakeWriter;
+    use crate::{JsonFormattingLayer, JsonStorageLayer};
+    use tracing::Level;
+    use tracing_subscriber::prelude::*;
+
+    fn messages(make_writer: &CapturingMakeWriter) -> Vec<String> {
+        make_writer
+            .records()
+            .iter()
+            .map(|record| record["message"].as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn destinations_only_get_the_records_at_their_level() {
+        let stdout = CapturingMakeWriter::default();
+        let file = CapturingMakeWriter::default();
+        let all = CapturingMakeWriter::default();
+        let make_writer = TeeMakeWriter::default()
+            .with_writer_at_level(stdout.clone(), Level::INFO)
+            .with_writer_at_level(file.clone(), Level::DEBUG)
+            .with_writer(all.clone());
+        let subscriber = tracing_subscriber::registry()
+            .with(JsonStorageLayer::new())
+            .with(JsonFormattingLayer::new("test".into(), make_writer));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::trace!("Polling");
+            tracing::debug!("Cache miss");
+            tracing::info!("Request handled");
+            tracing::error!("Disk full");
+        });
+
+        assert_eq!(messages(&stdout), ["Request handled", "Disk full"]);
+        assert_eq!(
+            messages(&file),
+            ["Cache miss", "Request handled", "Disk full"]
+        );
+        assert_eq!(messages(&all).len(), 4);
+    }
+
+    #[test]
+    fn records_reach_the_other_destinations_when_one_fails() {
+        struct Failing;
+
+        impl Write for Failing {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let output = CapturingMakeWriter::default();
+        let make_writer = TeeMakeWriter::default()
+            .with_writer(|| Failing)
+            .with_writer(output.clone());
+        make_writer.make_writer().write_all(b"{}\n").unwrap();
+        assert_eq!(output.contents(), "{}\n");
+
+        let failing = TeeMakeWriter::default().with_writer(|| Failing);
+        assert!(failing.make_writer().write_all(b"{}\n").is_err());
+    }
+}
@@ -0,0 +1,179 @@
+//! Helpers to test the output of the layer.
+//!
+//! ```rust
+//! use tracing_json_formatter::test_support::{assert_field, CapturingMakeWriter};
+//! use tracing_json_formatter::{JsonFormattingLayer, JsonStorageLayer};
+//! use tracing_subscriber::prelude::*;
+//!
+//! let make_writer = CapturingMakeWriter::default();
+//! let subscriber = tracing_subscriber::registry()
+//!     .with(JsonStorageLayer::new())
+//!     .with(JsonFormattingLayer::new("test".into(), make_writer.clone()));
+//! tracing::subscriber::with_default(subscriber, || {
+//!     tracing::info!(user_id = 42, "Signed in");
+//! });
+//!
+//! let records = make_writer.records();
+//! assert_eq!(records.len(), 1);
+//! assert_field(&records[0], "message", "Signed in");
+//! assert_field(&records[0], "user_id", 42);
+//! ```
+use crate::timestamp::{self, Timestamp};
+use serde_json::Value;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// The values fixed by
+/// [`JsonFormattingLayer::with_snapshot`](crate::JsonFormattingLayer::with_snapshot), for
+/// output that can be compared with golden files.
+///
+/// Records are stamped at the Unix epoch, from process 1 on host `localhost`, by default.
+///
+/// ```rust
+/// use tracing_json_formatter::test_support::{CapturingMakeWriter, Snapshot};
+/// use tracing_json_formatter::JsonFormattingLayer;
+///
+/// let make_writer = CapturingMakeWriter::default();
+/// // {"time":"1970-01-01T00:00:00.000Z","name":"test","host":"ci","message":...,"pid":1,...}
+/// let layer = JsonFormattingLayer::new("test".into(), make_writer.clone())
+///     .with_snapshot(Snapshot::default().with_hostname("ci"));
+/// ```
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub(crate) time: Timestamp,
+    pub(crate) pid: u32,
+    pub(crate) hostname: String,
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Self {
+            time: timestamp::from_system_time(SystemTime::UNIX_EPOCH),
+            pid: 1,
+            hostname: "localhost".to_owned(),
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn with_time(mut self, time: Timestamp) -> Self {
+        self.time = time;
+        self
+    }
+
+    pub fn with_pid(mut self, pid: u32) -> Self {
+        self.pid = pid;
+        self
+    }
+
+    pub fn with_hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = hostname.into();
+        self
+    }
+}
+
+/// A cloneable [`MakeWriter`] keeping everything written to it in memory.
+///
+/// Clones share the same buffer: hand one to the layer and keep another to read the
+/// captured output.
+#[derive(Clone, Debug, Default)]
+pub struct CapturingMakeWriter {
+    buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+impl CapturingMakeWriter {
+    /// Everything written so far.
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.lock()).into_owned()
+    }
+
+    /// Parse every line written so far as a JSON record, see [`parse_records`].
+    pub fn records(&self) -> Vec<Value> {
+        parse_records(&self.contents())
+    }
+
+    /// Discard everything written so far.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<u8>> {
+        self.buffer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<'a> MakeWriter<'a> for CapturingMakeWriter {
+    type Writer = CapturingWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        CapturingWriter(self.lock())
+    }
+}
+
+/// The writer returned by [`CapturingMakeWriter`].
+pub struct CapturingWriter<'a>(MutexGuard<'a, Vec<u8>>);
+
+impl Write for CapturingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Parse newline-delimited JSON records, skipping blank lines.
+///
+/// # Panics
+///
+/// Panics if a line isn't valid JSON.
+pub fn parse_records(output: &str) -> Vec<Value> {
+    output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            serde_json::from_str(line)
+                .unwrap_or_else(|e| panic!("invalid JSON record {:?}: {}", line, e))
+        })
+        .collect()
+}
+
+/// Assert that `record` has the field `key` with the value `expected`.
+///
+/// # Panics
+///
+/// Panics if the field is missing or has another value.
+#[track_caller]
+pub fn assert_field(record: &Value, key: &str, expected: impl Into<Value>) {
+    let expected = expected.into();
+    match record.get(key) {
+        Some(value) => assert_eq!(
+            value, &expected,
+            "unexpected value for field {:?} in {}",
+            key, record
+        ),
+        None => panic!("missing field {:?} in {}", key, record),
+    }
+}
+
+/// Assert that `record` doesn't have the field `key`.
+///
+/// # Panics
+///
+/// Panics if the field is present.
+#[track_caller]
+pub fn assert_no_field(record: &Value, key: &str) {
+    if let Some(value) = record.get(key) {
+        panic!("unexpected field {:?} = {} in {}", key, value, record);
+    }
+}
+
+/// The first record with the given `message`, if any.
+pub fn find_record<'a>(records: &'a [Value], message: &str) -> Option<&'a Value> {
+    records
+        .iter()
+        .find(|record| record.get("message").and_then(Value::as_str) == Some(message))
+}
@@ -0,0 +1,546 @@
+//! Timestamps, with the local offset read with `chrono` (the default feature) or with the
+//! `time` crate.
+use std::time::{Duration, SystemTime};
+
+/// A point in time with the offset of the local time zone, the `time` of records.
+///
+/// It converts from and to `chrono::DateTime` with the `chrono` feature (the default), and
+/// from and to `time::OffsetDateTime` with the `time` feature.
+///
+/// The local offset is read with `chrono` when the feature is enabled, with the `time`
+/// crate otherwise. The `time` crate refuses to read it on some Unix systems once the
+/// process has several threads, and without either feature there's nothing to read it
+/// with: timestamps are then in UTC, see [`Timestamp::offset_seconds`].
+///
+/// ```rust
+/// use std::time::{Duration, SystemTime};
+/// use tracing_json_formatter::Timestamp;
+///
+/// let time = Timestamp::from(SystemTime::UNIX_EPOCH + Duration::from_secs(1_681_313_412));
+/// assert_eq!(time.unix_nanos(), 1_681_313_412_000_000_000);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timestamp {
+    unix_nanos: i128,
+    offset_seconds: i32,
+}
+
+impl Timestamp {
+    /// The current time, in the local offset.
+    pub fn now() -> Self {
+        Self::from_system_time(SystemTime::now())
+    }
+
+    /// `time`, in the local offset.
+    pub fn from_system_time(time: SystemTime) -> Self {
+        let unix_nanos = match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_nanos() as i128,
+            Err(e) => -(e.duration().as_nanos() as i128),
+        };
+        let offset_seconds = local_offset(unix_nanos.div_euclid(NANOS_PER_SECOND) as i64);
+        Self {
+            unix_nanos,
+            offset_seconds: offset_seconds.unwrap_or(0),
+        }
+    }
+
+    /// The time `unix_nanos` nanoseconds after the Unix epoch, in the offset of
+    /// `offset_seconds` seconds east of UTC.
+    pub fn from_unix_nanos(unix_nanos: i128, offset_seconds: i32) -> Self {
+        Self {
+            unix_nanos,
+            offset_seconds,
+        }
+    }
+
+    /// Nanoseconds since the Unix epoch.
+    pub fn unix_nanos(&self) -> i128 {
+        self.unix_nanos
+    }
+
+    /// The offset of the timestamp, in seconds east of UTC: 0 when the local offset
+    /// couldn't be read.
+    pub fn offset_seconds(&self) -> i32 {
+        self.offset_seconds
+    }
+
+    pub fn to_system_time(&self) -> SystemTime {
+        let elapsed = Duration::from_nanos(self.unix_nanos.unsigned_abs() as u64);
+        if self.unix_nanos < 0 {
+            SystemTime::UNIX_EPOCH - elapsed
+        } else {
+            SystemTime::UNIX_EPOCH + elapsed
+        }
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        Self::from_system_time(time)
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(time: Timestamp) -> Self {
+        time.to_system_time()
+    }
+}
+
+const NANOS_PER_SECOND: i128 = 1_000_000_000;
+
+/// The offset of the local time zone at `unix_seconds`, in seconds east of UTC.
+#[cfg(feature = "chrono")]
+fn local_offset(unix_seconds: i64) -> Option<i32> {
+    use chrono::{Offset, TimeZone};
+
+    chrono::Local
+        .timestamp_opt(unix_seconds, 0)
+        .earliest()
+        .map(|time| time.offset().fix().local_minus_utc())
+}
+
+/// The offset of the local time zone at `unix_seconds`, in seconds east of UTC.
+#[cfg(all(feature = "time", not(feature = "chrono")))]
+fn local_offset(unix_seconds: i64) -> Option<i32> {
+    let time = time::OffsetDateTime::from_unix_timestamp(unix_seconds).ok()?;
+    time::UtcOffset::local_offset_at(time)
+        .ok()
+        .map(|offset| offset.whole_seconds())
+}
+
+#[cfg(not(any(feature = "chrono", feature = "time")))]
+fn local_offset(_unix_seconds: i64) -> Option<i32> {
+    None
+}
+
+#[cfg(feature = "chrono")]
+mod chrono_conversions {
+    use super::{Timestamp, NANOS_PER_SECOND};
+    use chrono::{DateTime, FixedOffset, Local, Offset, TimeZone, Utc};
+
+    impl<Tz: TimeZone> From<DateTime<Tz>> for Timestamp {
+        fn from(time: DateTime<Tz>) -> Self {
+            // chrono represents leap seconds with an extra second of nanoseconds.
+            let nanos = i128::from(time.timestamp_subsec_nanos() % 1_000_000_000);
+            Self {
+                unix_nanos: i128::from(time.timestamp()) * NANOS_PER_SECOND + nanos,
+                offset_seconds: time.offset().fix().local_minus_utc(),
+            }
+        }
+    }
+
+    impl From<Timestamp> for DateTime<Utc> {
+        fn from(time: Timestamp) -> Self {
+            let seconds = time.unix_nanos.div_euclid(NANOS_PER_SECOND) as i64;
+            let nanos = time.unix_nanos.rem_euclid(NANOS_PER_SECOND) as u32;
+            Utc.timestamp_opt(seconds, nanos)
+                .single()
+                .unwrap_or(DateTime::<Utc>::MAX_UTC)
+        }
+    }
+
+    impl From<Timestamp> for DateTime<FixedOffset> {
+        fn from(time: Timestamp) -> Self {
+            let offset = FixedOffset::east_opt(time.offset_seconds).unwrap_or(Utc.fix());
+            DateTime::<Utc>::from(time).with_timezone(&offset)
+        }
+    }
+
+    impl From<Timestamp> for DateTime<Local> {
+        fn from(time: Timestamp) -> Self {
+            DateTime::<Utc>::from(time).with_timezone(&Local)
+        }
+    }
+}
+
+#[cfg(feature = "time")]
+mod time_conversions {
+    use super::Timestamp;
+    use time::{OffsetDateTime, UtcOffset};
+
+    impl From<OffsetDateTime> for Timestamp {
+        fn from(time: OffsetDateTime) -> Self {
+            Self {
+                unix_nanos: time.unix_timestamp_nanos(),
+                offset_seconds: time.offset().whole_seconds(),
+            }
+        }
+    }
+
+    impl From<Timestamp> for OffsetDateTime {
+        fn from(time: Timestamp) -> Self {
+            let offset =
+                UtcOffset::from_whole_seconds(time.offset_seconds).unwrap_or(UtcOffset::UTC);
+            OffsetDateTime::from_unix_timestamp_nanos(time.unix_nanos)
+                .map_or(OffsetDateTime::UNIX_EPOCH, |time| time.to_offset(offset))
+        }
+    }
+}
+
+/// The calendar fields of a timestamp in a given offset.
+pub(crate) struct Parts {
+    year: i32,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    nanosecond: u32,
+    offset_seconds: i32,
+}
+
+/// The current local time.
+pub(crate) fn now() -> Timestamp {
+    Timestamp::now()
+}
+
+pub(crate) fn from_system_time(time: SystemTime) -> Timestamp {
+    Timestamp::from_system_time(time)
+}
+
+/// The fields of `time` in its own (local) offset.
+pub(crate) fn local(time: &Timestamp) -> Parts {
+    Parts::new(time.unix_nanos, time.offset_seconds)
+}
+
+/// The fields of `time` in UTC.
+pub(crate) fn utc(time: &Timestamp) -> Parts {
+    Parts::new(time.unix_nanos, 0)
+}
+
+pub(crate) fn unix_millis(time: &Timestamp) -> i64 {
+    time.unix_nanos.div_euclid(1_000_000) as i64
+}
+
+/// Nanoseconds since the Unix epoch, `None` past the range of `i64` (around 2262).
+pub(crate) fn unix_nanos(time: &Timestamp) -> Option<i64> {
+    i64::try_from(time.unix_nanos).ok()
+}
+
+impl Parts {
+    fn new(unix_nanos: i128, offset_seconds: i32) -> Self {
+        let local_nanos = unix_nanos + i128::from(offset_seconds) * NANOS_PER_SECOND;
+        let seconds = local_nanos.div_euclid(NANOS_PER_SECOND) as i64;
+        let (year, month, day) = civil_from_days(seconds.div_euclid(86_400));
+        let second_of_day = seconds.rem_euclid(86_400);
+        Self {
+            year,
+            month,
+            day,
+            hour: (second_of_day / 3600) as u8,
+            minute: (second_of_day / 60 % 60) as u8,
+            second: (second_of_day % 60) as u8,
+            nanosecond: local_nanos.rem_euclid(NANOS_PER_SECOND) as u32,
+            offset_seconds,
+        }
+    }
+
+    /// `2023-04-12`
+    pub(crate) fn date(&self) -> String {
+        format!("{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+
+    /// `2023-04-12-15`
+    #[cfg(feature = "rolling")]
+    pub(crate) fn date_hour(&self) -> String {
+        format!("{}-{:02}", self.date(), self.hour)
+    }
+
+    /// `2023-04-12 15:30:12`
+    pub(crate) fn date_time(&self) -> String {
+        format!(
+            "{} {:02}:{:02}:{:02}",
+            self.date(),
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+
+    /// `15:30:12.345`
+    pub(crate) fn time_millis(&self) -> String {
+        format!(
+            "{:02}:{:02}:{:02}.{:03}",
+            self.hour,
+            self.minute,
+            self.second,
+            self.nanosecond / 1_000_000
+        )
+    }
+
+    /// `20230412T153012.123`
+    #[cfg(feature = "rolling")]
+    pub(crate) fn compact_millis(&self) -> String {
+        format!(
+            "{:04}{:02}{:02}T{:02}{:02}{:02}.{:03}",
+            self.year,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second,
+            self.nanosecond / 1_000_000
+        )
+    }
+
+    /// RFC 3339 with `digits` (up to 9) fractional digits, `Z` standing for UTC, e.g.
+    /// `2023-04-12T13:30:12.345Z` or `2023-04-12T15:30:12.345678+02:00`.
+    pub(crate) fn rfc3339(&self, digits: usize) -> String {
+        let fraction = format!("{:09}", self.nanosecond);
+        let offset = match self.offset_seconds {
+            0 => "Z".to_owned(),
+            seconds => format!(
+                "{}{:02}:{:02}",
+                if seconds < 0 { '-' } else { '+' },
+                seconds.abs() / 3600,
+                seconds.abs() / 60 % 60
+            ),
+        };
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{}{}",
+            self.year,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second,
+            &fraction[..digits.min(9)],
+            offset
+        )
+    }
+}
+
+/// The `time` of a record written by the layer, in any [`TimeFormat`](crate::TimeFormat):
+/// an RFC 3339 string, a local time string without offset, or a number of milliseconds or
+/// nanoseconds since the Unix epoch, told apart by their magnitude.
+#[cfg(any(
+    feature = "fluentd",
+    feature = "loki",
+    feature = "otlp",
+    feature = "splunk"
+))]
+pub(crate) fn parse(time: &serde_json::Value) -> Option<Timestamp> {
+    use serde_json::Value;
+
+    let unix_nanos = match time {
+        // 10^15 milliseconds is the year 33658, 10^15 nanoseconds the 12th of January 1970.
+        Value::Number(number) => match number.as_i64()? {
+            millis if millis.abs() < 1_000_000_000_000_000 => i128::from(millis) * 1_000_000,
+            nanos => i128::from(nanos),
+        },
+        Value::String(time) => parse_date_time(time)?,
+        _ => return None,
+    };
+    Some(Timestamp::from_unix_nanos(unix_nanos, 0))
+}
+
+/// Nanoseconds since the Unix epoch of `2023-04-12T13:30:12.345Z`, with an optional
+/// fraction and offset, the local offset applying without one.
+#[cfg(any(
+    feature = "fluentd",
+    feature = "loki",
+    feature = "otlp",
+    feature = "splunk"
+))]
+fn parse_date_time(time: &str) -> Option<i128> {
+    let bytes = time.as_bytes();
+    let number = |start: usize, len: usize| -> Option<i64> {
+        let digits = bytes.get(start..start + len)?;
+        digits.iter().try_fold(0, |n, &digit| {
+            digit
+                .is_ascii_digit()
+                .then(|| n * 10 + i64::from(digit - b'0'))
+        })
+    };
+    let separators = [(4, b'-'), (7, b'-'), (13, b':'), (16, b':')];
+    if separators
+        .iter()
+        .any(|&(i, separator)| bytes.get(i) != Some(&separator))
+        || !matches!(bytes.get(10), Some(b'T' | b't' | b' '))
+    {
+        return None;
+    }
+    let (year, month, day) = (number(0, 4)?, number(5, 2)?, number(8, 2)?);
+    let (hour, minute, second) = (number(11, 2)?, number(14, 2)?, number(17, 2)?);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    let mut rest = &bytes[19..];
+    let mut nanos = 0;
+    if let Some(fraction) = rest.strip_prefix(b".") {
+        let digits = fraction.iter().take_while(|b| b.is_ascii_digit()).count();
+        if digits == 0 {
+            return None;
+        }
+        for (i, &digit) in fraction[..digits].iter().take(9).enumerate() {
+            nanos += i128::from(digit - b'0') * 10_i128.pow(8 - i as u32);
+        }
+        rest = &fraction[digits..];
+    }
+    let seconds =
+        days_from_civil(year, month as u8, day as u8) * 86_400 + hour * 3600 + minute * 60 + second;
+    let offset = match rest {
+        b"" => i64::from(local_offset(seconds).unwrap_or(0)),
+        b"Z" | b"z" => 0,
+        [sign @ (b'+' | b'-'), offset @ ..] if offset.len() == 5 && offset[2] == b':' => {
+            let start = time.len() - 5;
+            let offset = number(start, 2)? * 3600 + number(start + 3, 2)? * 60;
+            if *sign == b'-' {
+                -offset
+            } else {
+                offset
+            }
+        }
+        _ => return None,
+    };
+    Some(i128::from(seconds - offset) * NANOS_PER_SECOND + nanos)
+}
+
+/// The days from the Unix epoch to a proleptic Gregorian date, the inverse of
+/// [`civil_from_days`].
+#[cfg(any(
+    feature = "fluentd",
+    feature = "loki",
+    feature = "otlp",
+    feature = "splunk"
+))]
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    // Years starting from March, so that the leap day is the last day of the year.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_index = i64::from((month + 9) % 12);
+    let day_of_year = (153 * month_index + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The proleptic Gregorian date `days` days after the Unix epoch, see
+/// <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i32, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months starting from March, so that the leap day is the last day of the year.
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year as i32, month as u8, day as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn days_map_to_their_gregorian_dates() {
+        let dates = [
+            (0, (1970, 1, 1)),
+            (-1, (1969, 12, 31)),
+            (59, (1970, 3, 1)),
+            (10_956, (1999, 12, 31)),
+            (10_957, (2000, 1, 1)),
+            (11_016, (2000, 2, 29)),
+            (11_017, (2000, 3, 1)),
+            (19_459, (2023, 4, 12)),
+            (19_782, (2024, 2, 29)),
+            (47_540, (2100, 2, 28)),
+            (47_541, (2100, 3, 1)),
+            (-719_468, (0, 3, 1)),
+            (-719_469, (0, 2, 29)),
+            (2_932_896, (9999, 12, 31)),
+        ];
+        for (days, date) in dates {
+            assert_eq!(civil_from_days(days), date, "{} days", days);
+        }
+    }
+
+    #[test]
+    fn parts_split_timestamps_in_their_offset() {
+        let time = 1_681_306_212_345_678_901;
+        assert_eq!(
+            utc(&Timestamp::from_unix_nanos(time, 0)).rfc3339(9),
+            "2023-04-12T13:30:12.345678901Z"
+        );
+        let local = local(&Timestamp::from_unix_nanos(time, -9000));
+        assert_eq!(local.rfc3339(3), "2023-04-12T11:00:12.345-02:30");
+        assert_eq!(local.date_time(), "2023-04-12 11:00:12");
+        let before_the_epoch = utc(&Timestamp::from_unix_nanos(-1, 0));
+        assert_eq!(
+            before_the_epoch.rfc3339(9),
+            "1969-12-31T23:59:59.999999999Z"
+        );
+    }
+
+    #[test]
+    #[cfg(any(
+        feature = "fluentd",
+        feature = "loki",
+        feature = "otlp",
+        feature = "splunk"
+    ))]
+    fn record_times_are_parsed_in_every_format() {
+        use serde_json::json;
+
+        let unix_nanos = |time| parse(&time).map(|time| time.unix_nanos());
+        let time = 1_681_306_212_345_000_000;
+
+        assert_eq!(unix_nanos(json!("2023-04-12T13:30:12.345Z")), Some(time));
+        assert_eq!(
+            unix_nanos(json!("2023-04-12T15:30:12.345+02:00")),
+            Some(time)
+        );
+        assert_eq!(
+            unix_nanos(json!("2023-04-12T10:00:12.345-03:30")),
+            Some(time)
+        );
+        assert_eq!(
+            unix_nanos(json!("2023-04-12T13:30:12.345678901Z")),
+            Some(time + 678_901)
+        );
+        assert_eq!(
+            unix_nanos(json!("2023-04-12T13:30:12Z")),
+            Some(time - 345_000_000)
+        );
+        assert_eq!(unix_nanos(json!(1_681_306_212_345_i64)), Some(time));
+        assert_eq!(unix_nanos(json!(time as i64)), Some(time));
+        assert_eq!(unix_nanos(json!("1970-01-01T00:00:00Z")), Some(0));
+        assert_eq!(
+            unix_nanos(json!("2024-02-29T00:00:00Z")),
+            Some(1_709_164_800 * NANOS_PER_SECOND)
+        );
+
+        let local = unix_nanos(json!("2023-04-12 13:30:12")).unwrap();
+        let offset = local_offset(1_681_306_212).unwrap_or(0);
+        assert_eq!(
+            local,
+            time - 345_000_000 - i128::from(offset) * NANOS_PER_SECOND
+        );
+
+        for invalid in [
+            json!("2023-04-12"),
+            json!("2023-13-12T13:30:12Z"),
+            json!("2023-04-12T13:30:12."),
+            json!("2023-04-12T13:30:12+0200"),
+            json!("2023-04-12T13:30:12 UTC"),
+            json!("+023-04-12T13:30:12Z"),
+            json!(null),
+        ] {
+            assert_eq!(unix_nanos(invalid.clone()), None, "{}", invalid);
+        }
+    }
+}
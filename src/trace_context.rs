@@ -0,0 +1,72 @@
+use crate::storage_layer::with_span_storage;
+use serde_json::Value;
+use tracing::Span;
+
+/// A W3C trace context, as carried by the `traceparent` HTTP header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    /// The 32 hex digits ID of the whole trace.
+    pub trace_id: String,
+    /// The 16 hex digits ID of the caller's span, the parent of the incoming request.
+    pub parent_id: String,
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// Parse a `traceparent` header value, e.g.
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+    ///
+    /// Returns `None` for malformed values and for all-zero IDs, which are invalid.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = parts.next().filter(|v| is_hex(v, 2) && *v != "ff")?;
+        let trace_id = parts.next().filter(|id| is_hex(id, 32))?;
+        let parent_id = parts.next().filter(|id| is_hex(id, 16))?;
+        let flags = parts.next().filter(|flags| is_hex(flags, 2))?;
+        // Later versions may append fields, version 00 can't.
+        if version == "00" && parts.next().is_some() {
+            return None;
+        }
+        if trace_id.bytes().all(|b| b == b'0') || parent_id.bytes().all(|b| b == b'0') {
+            return None;
+        }
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        Some(Self {
+            trace_id: trace_id.to_owned(),
+            parent_id: parent_id.to_owned(),
+            sampled: flags & 1 == 1,
+        })
+    }
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Attach the incoming W3C `traceparent` of a request to `span`: every record of the span
+/// and of the spans created inside it afterwards carries `trace_id` and `parent_id` fields.
+///
+/// Returns `false`, leaving the span untouched, when `traceparent` is invalid or when the
+/// span isn't recorded by a `Registry` with a [`JsonStorageLayer`](crate::JsonStorageLayer).
+///
+/// ```rust
+/// use tracing_json_formatter::set_trace_context;
+///
+/// fn handle(traceparent: Option<&str>) {
+///     let span = tracing::info_span!("handle");
+///     if let Some(traceparent) = traceparent {
+///         set_trace_context(&span, traceparent);
+///     }
+///     let _guard = span.enter();
+///     tracing::info!("Handling request");
+/// }
+/// ```
+pub fn set_trace_context(span: &Span, traceparent: &str) -> bool {
+    let Some(context) = TraceContext::parse(traceparent) else {
+        return false;
+    };
+    with_span_storage(span, |storage| {
+        storage.set("trace_id", Value::from(context.trace_id));
+        storage.set("parent_id", Value::from(context.parent_id));
+    })
+}
@@ -0,0 +1,47 @@
+use serde::de::{Deserialize, Deserializer, IgnoredAny, MapAccess, Visitor};
+use std::fmt;
+
+/// The keys of a JSON object, in order and with their duplicates, which parsing the line
+/// into a `serde_json::Value` would collapse.
+struct Keys(Vec<String>);
+
+impl<'de> Deserialize<'de> for Keys {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct KeysVisitor;
+
+        impl<'de> Visitor<'de> for KeysVisitor {
+            type Value = Keys;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a JSON object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Keys, A::Error> {
+                let mut keys = Vec::new();
+                while let Some(key) = map.next_key::<String>()? {
+                    map.next_value::<IgnoredAny>()?;
+                    keys.push(key);
+                }
+                Ok(Keys(keys))
+            }
+        }
+
+        deserializer.deserialize_map(KeysVisitor)
+    }
+}
+
+/// Assert that no key appears twice in any of the records of `output`.
+#[track_caller]
+pub fn assert_unique_keys(output: &str) {
+    for line in output.lines().filter(|line| !line.trim().is_empty()) {
+        let Keys(keys) = serde_json::from_str(line).expect("invalid JSON record");
+        for (i, key) in keys.iter().enumerate() {
+            assert!(
+                !keys[..i].contains(key),
+                "duplicate key {:?} in {}",
+                key,
+                line
+            );
+        }
+    }
+}
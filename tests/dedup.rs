@@ -0,0 +1,109 @@
+mod common;
+
+use common::assert_unique_keys;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing_json_formatter::test_support::{assert_field, CapturingMakeWriter};
+use tracing_json_formatter::{JsonFormattingLayer, JsonStorageLayer, Metrics};
+use tracing_subscriber::prelude::*;
+
+fn layer(make_writer: &CapturingMakeWriter) -> JsonFormattingLayer<CapturingMakeWriter> {
+    JsonFormattingLayer::new("test".into(), make_writer.clone())
+        .with_build_info("1.2.3", None)
+        .with_uptime(true)
+        .with_deduplication(Duration::from_secs(60))
+}
+
+fn retry_loop() {
+    for _ in 0..4 {
+        tracing::warn!(attempt = 1, "Connection refused");
+    }
+}
+
+#[test]
+fn repeats_are_counted_despite_uptime() {
+    let make_writer = CapturingMakeWriter::default();
+    let subscriber = tracing_subscriber::registry()
+        .with(JsonStorageLayer::new())
+        .with(layer(&make_writer));
+    tracing::subscriber::with_default(subscriber, || {
+        retry_loop();
+        tracing::info!("Connected");
+    });
+
+    let records = make_writer.records();
+    let messages: Vec<_> = records.iter().map(|record| &record["message"]).collect();
+    assert_eq!(
+        messages,
+        ["Connection refused", "Connection refused", "Connected"]
+    );
+    assert_field(&records[1], "repeat_count", 3);
+    assert_field(&records[1], "attempt", 1);
+    assert_field(&records[1], "service.version", "1.2.3");
+    assert_unique_keys(&make_writer.contents());
+}
+
+#[test]
+fn pending_repeats_are_emitted_when_the_layer_is_dropped() {
+    let make_writer = CapturingMakeWriter::default();
+    let subscriber = tracing_subscriber::registry()
+        .with(JsonStorageLayer::new())
+        .with(layer(&make_writer));
+    tracing::subscriber::with_default(subscriber, retry_loop);
+
+    let records = make_writer.records();
+    assert_eq!(records.len(), 2);
+    assert_field(&records[1], "repeat_count", 3);
+}
+
+#[test]
+fn pending_repeats_are_emitted_when_the_guard_is_flushed() {
+    let make_writer = CapturingMakeWriter::default();
+    let layer = layer(&make_writer);
+    let guard = layer.guard();
+    let subscriber = tracing_subscriber::registry()
+        .with(JsonStorageLayer::new())
+        .with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        retry_loop();
+        assert_eq!(make_writer.records().len(), 1);
+        guard.flush().unwrap();
+
+        let records = make_writer.records();
+        assert_eq!(records.len(), 2);
+        assert_field(&records[1], "repeat_count", 3);
+    });
+}
+
+#[test]
+fn repeats_are_counted_as_dropped() {
+    let make_writer = CapturingMakeWriter::default();
+    let metrics = Arc::new(Metrics::default());
+    let subscriber = tracing_subscriber::registry()
+        .with(JsonStorageLayer::new())
+        .with(layer(&make_writer).with_metrics(metrics.clone()));
+    tracing::subscriber::with_default(subscriber, retry_loop);
+
+    assert_eq!(metrics.dropped(), 3);
+    assert_eq!(metrics.emitted(), 2);
+}
+
+#[test]
+fn repeats_are_bundled_in_their_transaction() {
+    let make_writer = CapturingMakeWriter::default();
+    let subscriber = tracing_subscriber::registry()
+        .with(JsonStorageLayer::new())
+        .with(layer(&make_writer).with_transactions(true));
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info_span!("request").in_scope(retry_loop);
+        tracing::info_span!("request").in_scope(retry_loop);
+    });
+
+    let records = make_writer.records();
+    assert_eq!(records.len(), 2);
+    for record in &records {
+        let events = record["events"].as_array().expect("an events array");
+        assert_eq!(events.len(), 2);
+        assert_field(&events[1], "repeat_count", 3);
+    }
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use common::assert_unique_keys;
+use tracing::level_filters::LevelFilter;
+use tracing::Level;
+use tracing_json_formatter::test_support::{assert_field, find_record, CapturingMakeWriter};
+use tracing_json_formatter::{JsonFormattingLayer, JsonStorageLayer};
+use tracing_subscriber::prelude::*;
+
+fn capture(f: impl FnOnce()) -> CapturingMakeWriter {
+    let make_writer = CapturingMakeWriter::default();
+    let layer = JsonFormattingLayer::new("test".into(), make_writer.clone())
+        .with_build_info("1.2.3", Some("abc123"))
+        .with_uptime(true)
+        .with_tail_sampling(Level::DEBUG);
+    layer.reload_handle().set_max_level(LevelFilter::INFO);
+    let subscriber = tracing_subscriber::registry()
+        .with(JsonStorageLayer::new())
+        .with(layer);
+    tracing::subscriber::with_default(subscriber, f);
+    make_writer
+}
+
+#[test]
+fn held_events_are_replayed_when_the_root_span_fails() {
+    let make_writer = capture(|| {
+        tracing::info_span!("request", method = "GET").in_scope(|| {
+            tracing::debug!(rows = 0, "Queried the database");
+            tracing::error!("User not found");
+        });
+    });
+
+    let records = make_writer.records();
+    let held = find_record(&records, "[REQUEST - EVENT] Queried the database")
+        .expect("the held event is replayed");
+    assert_field(held, "rows", 0);
+    assert_field(held, "method", "GET");
+    assert_field(held, "service.version", "1.2.3");
+    assert_unique_keys(&make_writer.contents());
+}
+
+#[test]
+fn held_events_are_discarded_when_the_root_span_succeeds() {
+    let make_writer = capture(|| {
+        tracing::info_span!("request").in_scope(|| {
+            tracing::debug!(rows = 0, "Queried the database");
+            tracing::info!("User found");
+        });
+    });
+
+    let records = make_writer.records();
+    assert!(find_record(&records, "[REQUEST - EVENT] Queried the database").is_none());
+    assert!(find_record(&records, "[REQUEST - EVENT] User found").is_some());
+}
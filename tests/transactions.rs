@@ -0,0 +1,114 @@
+use serde_json::Value;
+use tracing::level_filters::LevelFilter;
+use tracing_json_formatter::test_support::{assert_field, find_record, CapturingMakeWriter};
+use tracing_json_formatter::{JsonFormattingLayer, JsonStorageLayer};
+use tracing_subscriber::prelude::*;
+
+fn layer(make_writer: &CapturingMakeWriter) -> JsonFormattingLayer<CapturingMakeWriter> {
+    JsonFormattingLayer::new("test".into(), make_writer.clone()).with_transactions(true)
+}
+
+fn capture(layer: JsonFormattingLayer<CapturingMakeWriter>, f: impl FnOnce()) {
+    let subscriber = tracing_subscriber::registry()
+        .with(JsonStorageLayer::new())
+        .with(layer);
+    tracing::subscriber::with_default(subscriber, f);
+}
+
+/// The messages of the records bundled in the END record of the `request` span.
+fn bundled_messages(records: &[Value]) -> Vec<String> {
+    let end = find_record(records, "[REQUEST - END]").expect("an END record");
+    end["events"]
+        .as_array()
+        .expect("an events array")
+        .iter()
+        .map(|event| event["message"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+#[test]
+fn events_are_bundled_in_the_end_record_of_the_root_span() {
+    let make_writer = CapturingMakeWriter::default();
+    capture(layer(&make_writer), || {
+        tracing::info_span!("request", method = "GET").in_scope(|| tracing::info!("Routing"));
+    });
+
+    let records = make_writer.records();
+    assert_eq!(records.len(), 1);
+    assert_field(&records[0], "method", "GET");
+    assert_eq!(bundled_messages(&records), ["[REQUEST - EVENT] Routing"]);
+}
+
+#[test]
+fn quiet_root_spans_emit_their_bundle() {
+    let make_writer = CapturingMakeWriter::default();
+    capture(layer(&make_writer), || {
+        tracing::info_span!("request", log_span = false).in_scope(|| tracing::info!("Routing"));
+        tracing::info_span!("request", log_span = false).in_scope(|| {});
+    });
+
+    let records = make_writer.records();
+    assert_eq!(records.len(), 1);
+    assert_eq!(bundled_messages(&records), ["[REQUEST - EVENT] Routing"]);
+}
+
+#[test]
+fn root_spans_filtered_out_after_opening_emit_their_bundle() {
+    let make_writer = CapturingMakeWriter::default();
+    let layer = layer(&make_writer);
+    let reload_handle = layer.reload_handle();
+    capture(layer, || {
+        tracing::info_span!("request").in_scope(|| {
+            tracing::info!("Routing");
+            reload_handle.set_max_level(LevelFilter::WARN);
+        });
+    });
+
+    let records = make_writer.records();
+    assert_eq!(records.len(), 1);
+    assert_eq!(bundled_messages(&records), ["[REQUEST - EVENT] Routing"]);
+}
+
+#[test]
+fn root_spans_filtered_out_when_opening_are_not_transactions() {
+    let make_writer = CapturingMakeWriter::default();
+    let layer = layer(&make_writer);
+    layer.reload_handle().set_max_level(LevelFilter::WARN);
+    capture(layer, || {
+        tracing::debug_span!("request").in_scope(|| tracing::warn!("Slow query"));
+    });
+
+    let records = make_writer.records();
+    assert_eq!(records.len(), 1);
+    assert_field(&records[0], "message", "[REQUEST - EVENT] Slow query");
+}
+
+#[test]
+fn log_context_spans_dont_hold_transactions() {
+    let make_writer = CapturingMakeWriter::default();
+    capture(layer(&make_writer), || {
+        let context = tracing::info_span!("request", request_id = "r-1")
+            .in_scope(tracing_json_formatter::LogContext::current);
+        context.in_scope(|| tracing::info!("Sending the confirmation email"));
+    });
+
+    let records = make_writer.records();
+    assert_eq!(records.len(), 2);
+    assert_field(&records[1], "message", "Sending the confirmation email");
+    assert_field(&records[1], "request_id", "r-1");
+}
+
+#[test]
+fn transactions_start_under_child_spans() {
+    let make_writer = CapturingMakeWriter::default();
+    capture(layer(&make_writer), || {
+        let tenant = tracing_json_formatter::child_span([("tenant_id", "acme".into())]);
+        tenant.in_scope(|| {
+            tracing::info_span!("request").in_scope(|| tracing::info!("Routing"));
+        });
+    });
+
+    let records = make_writer.records();
+    assert_eq!(records.len(), 1);
+    assert_eq!(bundled_messages(&records), ["[REQUEST - EVENT] Routing"]);
+}